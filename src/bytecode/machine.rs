@@ -0,0 +1,304 @@
+//! Runs lowered code with a single dispatch loop
+
+use bytecode::{Code, Instr};
+use dispatcher::operation::*;
+use std::io::{Read, Write};
+use std::mem;
+use std::sync::Arc;
+
+// A call to return from: the code and instruction to continue with afterwards
+struct Frame {
+    code: Arc<Code>,
+    pc: usize,
+    // The array of the caller, for calls on a separate array
+    caller: Option<ArrayHandler>,
+    // Whether the guard of the caller reserved the cells of its guarded instructions
+    reserved: bool,
+}
+
+// Where a run stopped when it ran out of fuel
+struct Suspended {
+    code: Arc<Code>,
+    pc: usize,
+    // The number of operations lowered into the code
+    end: usize,
+}
+
+// Both versions of a function, lowered the first time it is called
+struct Lowered {
+    fun: Arc<Function>,
+    ops: Arc<Code>,
+    separate: Arc<Code>,
+}
+
+/// Runs operations lowered to instructions on a `Context`
+///
+/// Function calls push a frame instead of recursing, so deep recursion in the scripts
+/// does not use up the stack of the interpreter. The functions are lowered once and kept.
+///
+/// # Examples
+/// ```
+/// use Brainfuck::bytecode::Machine;
+/// use Brainfuck::dispatcher::Context;
+/// use Brainfuck::dispatcher::Operation::*;
+///
+/// let mut ctx = Context::new("".as_bytes(), Vec::new());
+/// let mut machine = Machine::new();
+/// machine.run(&mut ctx, &[Set(0, 3), While(vec![Add(1, 21), Add(0, -1)]), Write(1)]).unwrap();
+/// assert_eq!(ctx.io.output(), b"?");
+/// ```
+#[derive(Default)]
+pub struct Machine {
+    // The calls being executed, the innermost last
+    frames: Vec<Frame>,
+    suspended: Option<Suspended>,
+    // Whether the last guard reserved the cells of the guarded instructions after it
+    reserved: bool,
+    funs: Vec<Option<Lowered>>,
+}
+
+impl Machine {
+    /// Creates a machine with no functions lowered yet
+    pub fn new() -> Machine {
+        Machine::default()
+    }
+    /// Checks if the last run ran out of fuel and is waiting to be continued
+    pub fn is_suspended(&self) -> bool {
+        self.suspended.is_some()
+    }
+    /// Lowers and executes `ops`, using up one unit of the fuel of `ctx` for each instruction
+    ///
+    /// If the last run was suspended, continues it from where it stopped instead, so `ops`
+    /// must be the same operations, with possibly more added at the end.
+    ///
+    /// # Errors
+    /// Stops at the first instruction that fails, returning its error, with the array
+    /// of the outermost call back in `ctx`. On `OutOfFuel` the run is suspended and can be
+    /// continued after adding more fuel.
+    pub fn run<R: Read, W: Write>(&mut self, ctx: &mut Context<R, W>, ops: &[Operation]) -> Result<(), RuntimeError> {
+        let (mut code, mut pc, mut end) = match self.suspended.take() {
+            Some(run) => {
+                // Put back the arrays of the calls on separate arrays, outermost first; the
+                // array given back could have been replaced, so the guarded instructions
+                // check the cells until the next guard
+                self.reserved = false;
+                for frame in &mut self.frames {
+                    frame.reserved = false;
+                    if let Some(ref mut caller) = frame.caller {
+                        mem::swap(&mut ctx.ah, caller);
+                    }
+                }
+                (run.code, run.pc, run.end)
+            }
+            None => (Arc::new(Code::lower(ops)), 0, ops.len()),
+        };
+        loop {
+            if let Err(e) = self.execute(ctx, &mut code, &mut pc) {
+                if e == RuntimeError::OutOfFuel {
+                    // Keep the arrays of the calls in their frames until the run continues
+                    for frame in self.frames.iter_mut().rev() {
+                        if let Some(ref mut caller) = frame.caller {
+                            mem::swap(&mut ctx.ah, caller);
+                        }
+                    }
+                    self.suspended = Some(Suspended { code, pc, end });
+                } else {
+                    if let Some(off) = code.instrs().get(pc).and_then(Instr::offset) {
+                        ctx.ah.settle(off);
+                    }
+                    while let Some(frame) = self.frames.pop() {
+                        if let Some(caller) = frame.caller {
+                            ctx.ah = caller;
+                        }
+                    }
+                }
+                return Err(e);
+            }
+            // Operations added while the run was suspended
+            if end >= ops.len() {
+                return Ok(());
+            }
+            code = Arc::new(Code::lower(&ops[end..]));
+            pc = 0;
+            end = ops.len();
+        }
+    }
+    // Executes instructions from `pc` until the end of `code`, leaving `code` and `pc`
+    // on the instruction that failed
+    fn execute<R: Read, W: Write>(&mut self, ctx: &mut Context<R, W>, code: &mut Arc<Code>, pc: &mut usize) -> Result<(), RuntimeError> {
+        loop {
+            let instr = match code.instrs().get(*pc) {
+                Some(&instr) => instr,
+                None => match self.frames.pop() {
+                    Some(frame) => {
+                        if let Some(caller) = frame.caller {
+                            ctx.ah = caller;
+                        }
+                        self.reserved = frame.reserved;
+                        *code = frame.code;
+                        *pc = frame.pc;
+                        continue;
+                    }
+                    None => return Ok(()),
+                },
+            };
+            // Guards use no fuel, so the operations take as much as before
+            if let Instr::Guard(min, max) = instr {
+                self.reserved = ctx.ah.reserve(min, max);
+                *pc += 1;
+                continue;
+            }
+            ctx.burn()?;
+            match instr {
+                Instr::Add(off, i) => ctx.ah.add_at(off, i)?,
+                // SAFETY: the guarded instructions are only reached through their guard, which
+                // reserved every cell they reach from where it left the pointer, and only other
+                // guarded instructions move it. The array it reserved them in only grows until
+                // the run is suspended, after which the cells are checked again.
+                Instr::GuardedAdd(off, i) if self.reserved => unsafe { ctx.ah.add_reserved(off, i)? },
+                Instr::GuardedMove(i) if self.reserved => ctx.ah.move_reserved(i),
+                Instr::GuardedSet(off, i) if self.reserved => unsafe { ctx.ah.set_reserved(off, i)? },
+                Instr::GuardedAdd(off, i) => ctx.ah.add_at(off, i)?,
+                Instr::GuardedMove(i) => ctx.ah.move_r(i)?,
+                Instr::GuardedSet(off, i) => ctx.ah.set_at(off, i)?,
+                Instr::Move(i) => ctx.ah.move_r(i)?,
+                Instr::Set(off, i) => ctx.ah.set_at(off, i)?,
+                Instr::JumpZero(to) => if ctx.ah.get() == 0 {
+                    *pc = to;
+                    continue;
+                },
+                Instr::JumpNotZero(to) => if ctx.ah.get() != 0 {
+                    *pc = to;
+                    continue;
+                },
+                Instr::MoveTo(n) => code.linear_loop(n).execute(ctx)?,
+                Instr::SkipMove(i) => ctx.ah.skip_move(i)?,
+                Instr::Read(off) => ctx.ah.read(off, &mut ctx.io)?,
+                Instr::Write(off) => ctx.ah.write(off, &mut ctx.io)?,
+                Instr::InsFuns => ctx.ah.set(ctx.fh.no_functions() as i64)?,
+                Instr::CallFun => {
+                    let fun = self.function(&ctx.fh, ctx.ah.get())?.ops.clone();
+                    self.call(code, pc, fun, None);
+                    continue;
+                }
+                Instr::CallFSep(args) => {
+                    let fun = self.function(&ctx.fh, ctx.ah.get())?.separate.clone();
+                    let ah = ctx.separate_array(args)?;
+                    self.call(code, pc, fun, Some(mem::replace(&mut ctx.ah, ah)));
+                    continue;
+                }
+                Instr::Call(n) => {
+                    let fun = self.function(&ctx.fh, n as i64)?.ops.clone();
+                    self.call(code, pc, fun, None);
+                    continue;
+                }
+                Instr::CallSep(n, args) => {
+                    let fun = self.function(&ctx.fh, n as i64)?.separate.clone();
+                    let ah = ctx.separate_array(args)?;
+                    self.call(code, pc, fun, Some(mem::replace(&mut ctx.ah, ah)));
+                    continue;
+                }
+                Instr::Separate(args, n) => {
+                    let body = code.body(n).clone();
+                    let ah = ctx.separate_array(args)?;
+                    self.call(code, pc, body, Some(mem::replace(&mut ctx.ah, ah)));
+                    continue;
+                }
+                Instr::Debug => ctx.debug(),
+                Instr::PeekStack(off) => ctx.peek_at(off)?,
+                Instr::PopStack(off, i) => ctx.pop_at(off, i)?,
+                Instr::PushStack(off) => ctx.stack.push(ctx.ah.get_at(off)?),
+                Instr::StackLen(off) => ctx.ah.set_at(off, ctx.stack.len() as i64)?,
+                Instr::Restore(n) => code.snapshot(n).restore(ctx)?,
+                Instr::Guard(..) => {}
+            }
+            *pc += 1;
+        }
+    }
+    // Pushes a frame returning after the current instruction and continues at the start of `callee`
+    fn call(&mut self, code: &mut Arc<Code>, pc: &mut usize, callee: Arc<Code>, caller: Option<ArrayHandler>) {
+        let code = mem::replace(code, callee);
+        self.frames.push(Frame { code, pc: *pc + 1, caller, reserved: self.reserved });
+        *pc = 0;
+    }
+    // Returns the function number `n` of `fh`, lowering it if it was not called before
+    fn function(&mut self, fh: &FunctionHolder, n: i64) -> Result<&Lowered, RuntimeError> {
+        let fun = fh.get(n)?;
+        let n = n as usize;
+        if self.funs.len() <= n {
+            self.funs.resize_with(n + 1, || None);
+        }
+        let fresh = match self.funs[n] {
+            Some(ref lowered) => !Arc::ptr_eq(&lowered.fun, &fun),
+            None => true,
+        };
+        if fresh {
+            let ops = Arc::new(Code::lower(fun.ops()));
+            let separate = if fun.separate_ops() == fun.ops() {
+                ops.clone()
+            } else {
+                Arc::new(Code::lower(fun.separate_ops()))
+            };
+            self.funs[n] = Some(Lowered { fun, ops, separate });
+        }
+        Ok(self.funs[n].as_ref().expect("function was just lowered"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Machine;
+    use dispatcher::{Context, RuntimeError};
+    use dispatcher::Operation::*;
+    #[test]
+    fn deep_recursion_runs_without_recursing() {
+        let mut ctx = Context::new("".as_bytes(), Vec::new());
+        ctx.fh.add_temp(vec![Add(0, -1), While(vec![Call(0)])]);
+        ctx.fh.push_funs();
+        Machine::new().run(&mut ctx, &[Set(0, 200_000), Call(0), Add(0, 1)]).unwrap();
+        assert_eq!(ctx.ah.values(), vec![(0, 1)]);
+    }
+    #[test]
+    fn separate_arrays_are_put_back() {
+        let mut ctx = Context::new("".as_bytes(), Vec::new());
+        ctx.fh.add_temp(vec![Set(0, 5), Write(0), PopStack(0, 1)]);
+        ctx.fh.push_funs();
+        let mut machine = Machine::new();
+        let ops = [Set(0, 7), CallSep(0, 0)];
+        ctx.set_fuel(Some(3));
+        assert_eq!(machine.run(&mut ctx, &ops), Err(RuntimeError::OutOfFuel));
+        assert!(machine.is_suspended());
+        assert_eq!(ctx.ah.values(), vec![(0, 7)]);
+        ctx.set_fuel(None);
+        assert_eq!(machine.run(&mut ctx, &ops), Err(RuntimeError::StackUnderflow));
+        assert!(!machine.is_suspended());
+        assert_eq!(ctx.ah.values(), vec![(0, 7)]);
+        assert_eq!(ctx.io.output(), &[5]);
+    }
+    #[test]
+    fn loops_too_big_for_the_tape_are_checked() {
+        // The loop could reach cell 5, but never does
+        let ops = [Set(0, 2), While(vec![Add(0, -1), Move(1), While(vec![Add(4, 1)]), Write(0), Move(-1)])];
+        let mut ctx = Context::new("".as_bytes(), Vec::new());
+        ctx.set_tape(Some(3));
+        Machine::new().run(&mut ctx, &ops).unwrap();
+        assert_eq!(ctx.io.output(), &[0, 0]);
+        ctx.ah.set(1).unwrap();
+        ctx.ah.set_at(1, 1).unwrap();
+        assert_eq!(Machine::new().run(&mut ctx, &ops[1..]), Err(RuntimeError::OutOfTape(3)));
+        assert_eq!(ctx.ah.pointer(), 2);
+    }
+    #[test]
+    fn replaced_arrays_are_checked_after_suspending() {
+        let ops = [Set(0, 3), While(vec![Move(1), Add(0, 1), Move(-1), Add(0, -1)])];
+        let mut ctx = Context::new("".as_bytes(), Vec::new());
+        let mut machine = Machine::new();
+        ctx.set_fuel(Some(3));
+        assert_eq!(machine.run(&mut ctx, &ops), Err(RuntimeError::OutOfFuel));
+        // The cells the guard reserved are gone when the run continues
+        ctx.set_tape(Some(1));
+        ctx.set_fuel(None);
+        assert_eq!(machine.run(&mut ctx, &ops), Err(RuntimeError::OutOfTape(-1)));
+        assert_eq!(ctx.ah.values(), vec![(0, 1)]);
+    }
+}
@@ -0,0 +1,300 @@
+//! Lowers the operations into a flat array of instructions and runs them.
+//!
+//! Loops become a pair of jumps to precomputed places instead of nested sequences,
+//! and functions are called with an explicit stack of frames instead of recursion,
+//! see `Machine`.
+//!
+//! When the cells a loop or a function can reach are known when compiling, the array is
+//! made big enough for all of them once, before it runs, and its instructions then reach
+//! the cells without checking the size of the array each time. If they do not fit in a
+//! bounded array, or the run was suspended in between, the same instructions check it.
+
+mod machine;
+pub use self::machine::Machine;
+
+use dispatcher::operation::*;
+use std::convert::TryFrom;
+use std::slice;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// How the compiled operations are executed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// Walks the tree of operations, running the bodies of loops and functions recursively
+    /// and checking the size of the array on every access
+    Tree,
+    /// Lowers the operations to instructions and runs them on a `Machine`
+    Bytecode,
+    /// Compiles the operations to machine code and runs it, see `jit::Jit`
+    ///
+    /// Runs limited by fuel, and every run where machine code is not supported, use
+    /// `Bytecode` instead.
+    Jit,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+    /// Parses `tree`, `bytecode` or `jit`
+    fn from_str(s: &str) -> Result<Backend, String> {
+        match s {
+            "tree" => Ok(Backend::Tree),
+            "bytecode" => Ok(Backend::Bytecode),
+            "jit" => Ok(Backend::Jit),
+            _ => Err(format!("Unknown backend '{}', expected tree, bytecode or jit", s)),
+        }
+    }
+}
+
+/// A single instruction
+///
+/// The instructions on cells work like the operations with the same name. The rest
+/// refer to the places of the instructions in the `Code` and to its tables.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instr {
+    /// Add y to the position at offset x
+    Add(i32, i64),
+    /// Move the pointer by x spaces
+    Move(i32),
+    /// Set the value at offset x to y
+    Set(i32, i64),
+    /// Jump to instruction x if the value at the pointer is 0, the start of a loop
+    JumpZero(usize),
+    /// Jump to instruction x if the value at the pointer is not 0, the end of a loop
+    JumpNotZero(usize),
+    /// Runs the linear loop x of the code
+    MoveTo(usize),
+    /// Equivalent to Loop(Move x)
+    SkipMove(i32),
+    /// Read next character to the position at offset x
+    Read(i32),
+    /// Write the character at offset x
+    Write(i32),
+    /// Insert the total number of functions at the current position
+    InsFuns,
+    /// Calls the function with the number at the current position on the current array
+    CallFun,
+    /// Calls the function with the number at the current position on a separate array
+    /// with x arguments copied
+    CallFSep(usize),
+    /// Calls function x on the current array
+    Call(usize),
+    /// Calls function x on a separate array with y arguments copied
+    CallSep(usize, usize),
+    /// Runs the body y of the code on a separate array with x arguments copied
+    Separate(usize, usize),
+    /// Prints debug information
+    Debug,
+    /// Inserts the number at the top of the stack at offset x
+    PeekStack(i32),
+    /// Pops y elements from the stack, inserting the last one at offset x
+    PopStack(i32, usize),
+    /// Push the number at offset x to the stack
+    PushStack(i32),
+    /// Inserts the length of the stack at offset x
+    StackLen(i32),
+    /// Restores the snapshot x of the code
+    Restore(usize),
+    /// Reserves the cells from offset x to y (see `ArrayHandler::reserve`) for the guarded
+    /// instructions after it, which check the size of the array if they do not fit in it
+    Guard(i32, i32),
+    /// Add y to the reserved position at offset x
+    GuardedAdd(i32, i64),
+    /// Move the pointer by x spaces, to a reserved position
+    GuardedMove(i32),
+    /// Set the value at the reserved offset x to y
+    GuardedSet(i32, i64),
+}
+
+impl Instr {
+    /// Returns the offset of the cell the instruction works on, for the instructions on a single cell
+    pub fn offset(&self) -> Option<i32> {
+        match *self {
+            Instr::Add(off, _) | Instr::Set(off, _) | Instr::Read(off) | Instr::Write(off) |
+            Instr::PeekStack(off) | Instr::PopStack(off, _) | Instr::PushStack(off) | Instr::StackLen(off) |
+            Instr::GuardedAdd(off, _) | Instr::GuardedSet(off, _) => Some(off),
+            _ => None,
+        }
+    }
+}
+
+// How the instructions of a sequence of operations reach the cells
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Cells {
+    // Checked, guarding the loops whose reach is known
+    Guard,
+    // Through the guarded instructions, inside a guard which reserves them
+    Reserved,
+}
+
+/// A sequence of operations lowered to instructions, together with the data
+/// which does not fit in them
+///
+/// # Examples
+/// ```
+/// use Brainfuck::bytecode::{Code, Instr};
+/// use Brainfuck::dispatcher::Operation::*;
+///
+/// let code = Code::lower(&[Add(0, 1), While(vec![Write(0), SkipMove(1)]), Move(1)]);
+/// assert_eq!(code.instrs(), &[Instr::Add(0, 1), Instr::JumpZero(5), Instr::Write(0),
+///     Instr::SkipMove(1), Instr::JumpNotZero(2), Instr::Move(1)]);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Code {
+    instrs: Vec<Instr>,
+    loops: Vec<LinearLoop>,
+    snapshots: Vec<Snapshot>,
+    bodies: Vec<Arc<Code>>,
+}
+
+impl Code {
+    /// Lowers `ops` to instructions, guarding them as a whole if the cells they reach are known,
+    /// or else each loop whose cells are
+    pub fn lower(ops: &[Operation]) -> Code {
+        let mut code = Code::default();
+        match reach(ops) {
+            Some((min, max, _)) => code.push_guarded(ops, min, max),
+            None => code.push(ops, Cells::Guard),
+        }
+        code
+    }
+    /// Returns the instructions, in order
+    pub fn instrs(&self) -> &[Instr] {
+        &self.instrs
+    }
+    /// Returns the linear loop number `n`
+    pub fn linear_loop(&self, n: usize) -> &LinearLoop {
+        &self.loops[n]
+    }
+    /// Returns the snapshot number `n`
+    pub fn snapshot(&self, n: usize) -> &Snapshot {
+        &self.snapshots[n]
+    }
+    /// Returns the body number `n`, run on a separate array
+    pub fn body(&self, n: usize) -> &Arc<Code> {
+        &self.bodies[n]
+    }
+    // Appends a guard reserving the cells from `min` to `max`, then the instructions of `ops`
+    // reaching them as reserved
+    //
+    // The guarded instructions check the cells themselves when the guard could not reserve
+    // them, so there is a single copy of the instructions, as long as the operations.
+    fn push_guarded(&mut self, ops: &[Operation], min: i32, max: i32) {
+        self.instrs.push(Instr::Guard(min, max));
+        self.push(ops, Cells::Reserved);
+    }
+    // Appends the instructions of `ops`
+    fn push(&mut self, ops: &[Operation], cells: Cells) {
+        let reserved = cells == Cells::Reserved;
+        for op in ops {
+            let instr = match *op {
+                Add(off, i) if reserved => Instr::GuardedAdd(off, i),
+                Add(off, i) => Instr::Add(off, i),
+                Move(i) if reserved => Instr::GuardedMove(i),
+                Move(i) => Instr::Move(i),
+                Set(off, i) if reserved => Instr::GuardedSet(off, i),
+                Set(off, i) => Instr::Set(off, i),
+                While(ref body) => {
+                    if cells == Cells::Guard {
+                        if let Some((min, max, _)) = reach(slice::from_ref(op)) {
+                            self.push_guarded(slice::from_ref(op), min, max);
+                            continue;
+                        }
+                    }
+                    // The jump past the loop is filled in once the body is known
+                    let start = self.instrs.len();
+                    self.instrs.push(Instr::JumpZero(0));
+                    self.push(body, cells);
+                    self.instrs.push(Instr::JumpNotZero(start + 1));
+                    self.instrs[start] = Instr::JumpZero(self.instrs.len());
+                    continue;
+                }
+                MoveTo(ref lin) => {
+                    self.loops.push(lin.clone());
+                    Instr::MoveTo(self.loops.len() - 1)
+                }
+                SkipMove(i) => Instr::SkipMove(i),
+                Read(off) => Instr::Read(off),
+                Write(off) => Instr::Write(off),
+                InsFuns => Instr::InsFuns,
+                CallFun => Instr::CallFun,
+                CallFSep(args) => Instr::CallFSep(args),
+                Call(n) => Instr::Call(n),
+                CallSep(n, args) => Instr::CallSep(n, args),
+                Separate(args, ref body) => {
+                    self.bodies.push(Arc::new(Code::lower(body)));
+                    Instr::Separate(args, self.bodies.len() - 1)
+                }
+                Debug => Instr::Debug,
+                PeekStack(off) => Instr::PeekStack(off),
+                PopStack(off, i) => Instr::PopStack(off, i),
+                PushStack(off) => Instr::PushStack(off),
+                StackLen(off) => Instr::StackLen(off),
+                Restore(ref snap) => {
+                    self.snapshots.push(snap.clone());
+                    Instr::Restore(self.snapshots.len() - 1)
+                }
+                EmptyOp => continue,
+            };
+            self.instrs.push(instr);
+        }
+    }
+}
+
+// Returns the lowest and highest offsets of the cells `ops` reach from where they start,
+// and where they leave the pointer, if those are known when compiling
+//
+// Loops are only known if each iteration ends where it started, so that every iteration
+// reaches the same cells.
+fn reach(ops: &[Operation]) -> Option<(i32, i32, i32)> {
+    let (mut min, mut max, mut pos) = (0i64, 0i64, 0i64);
+    for op in ops {
+        let (low, high) = match *op {
+            Add(off, _) | Set(off, _) | Read(off) | Write(off) | PeekStack(off) | PopStack(off, _) |
+            PushStack(off) | StackLen(off) => (off, off),
+            Move(i) => {
+                pos += i64::from(i);
+                (0, 0)
+            }
+            MoveTo(ref lin) => lin.sets.iter().chain(&lin.adds)
+                .fold((0, 0), |(low, high), &(off, _)| (low.min(off), high.max(off))),
+            While(ref body) => match reach(body) {
+                Some((low, high, 0)) => (low, high),
+                _ => return None,
+            },
+            // The arguments copied to the separate array
+            CallFSep(args) | CallSep(_, args) | Separate(args, _) => (0, i32::try_from(args).ok()?),
+            InsFuns | Debug | EmptyOp => (0, 0),
+            SkipMove(_) | CallFun | Call(_) | Restore(_) => return None,
+        };
+        min = min.min(pos + i64::from(low));
+        max = max.max(pos + i64::from(high));
+    }
+    Some((i32::try_from(min).ok()?, i32::try_from(max).ok()?, i32::try_from(pos).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{reach, Code, Instr};
+    use dispatcher::Operation::*;
+    #[test]
+    fn balanced_loops_are_guarded() {
+        // [>[-]<-], after a move unknown when compiling
+        let code = Code::lower(&[SkipMove(1), While(vec![Move(1), While(vec![Add(0, -1)]), Move(-1), Add(0, -1)])]);
+        assert_eq!(code.instrs(), &[Instr::SkipMove(1), Instr::Guard(0, 1),
+            Instr::JumpZero(10), Instr::GuardedMove(1), Instr::JumpZero(7), Instr::GuardedAdd(0, -1), Instr::JumpNotZero(5),
+            Instr::GuardedMove(-1), Instr::GuardedAdd(0, -1), Instr::JumpNotZero(3)]);
+    }
+    #[test]
+    fn reach_of_operations() {
+        assert_eq!(reach(&[Add(-2, 1), Move(3), Write(1), Separate(2, vec![SkipMove(1)])]), Some((-2, 5, 3)));
+        assert_eq!(reach(&[While(vec![Move(1), Add(0, 1)])]), None);
+        assert_eq!(reach(&[Move(1), Call(0)]), None);
+    }
+    #[test]
+    fn bodies_are_lowered_separately() {
+        let code = Code::lower(&[EmptyOp, Separate(1, vec![While(vec![Write(0), SkipMove(1)])]), Call(0)]);
+        assert_eq!(code.instrs(), &[Instr::Separate(1, 0), Instr::Call(0)]);
+        assert_eq!(code.body(0).instrs(), &[Instr::JumpZero(4), Instr::Write(0), Instr::SkipMove(1), Instr::JumpNotZero(1)]);
+    }
+}
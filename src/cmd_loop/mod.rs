@@ -32,7 +32,13 @@ pub fn run(cmp: &mut Compiler) {
     loop {
         // Read the next command
         let mut cmd = String::new();
-        stdin().read_line(&mut cmd).expect("Failed to read from stdin.");
+        // The end of the input works as `:q`
+        if stdin().read_line(&mut cmd).expect("Failed to read from stdin.") == 0 {
+            if let Some(mut file) = save_file {
+                file.flush().expect("Error writing to file.");
+            }
+            return;
+        }
         let mut chs = CmdChars::new(cmd.clone());
         match chs.peek() {
             //Checks if there is a special command
@@ -40,30 +46,33 @@ pub fn run(cmp: &mut Compiler) {
                 match special_command(&mut chs) {
                     SpecialCmd::Time => timed = !timed,
                     SpecialCmd::File(f) => {
-                        if save_file.is_some() {
-                            save_file.unwrap().flush().expect("Error writing to file.");
+                        if let Some(mut file) = save_file {
+                            file.flush().expect("Error writing to file.");
                         }
                         save_file = Some(File::create(f).expect("Could not create file"));
                     }
                     SpecialCmd::SaveFile => {
-                        if save_file.is_none() {
-                            println!("No file open for saving");
-                        } else {
-                            save_file.unwrap().flush().expect("Error writing to file.");
-                            save_file = None;
+                        match save_file {
+                            Some(mut file) => {
+                                file.flush().expect("Error writing to file.");
+                                save_file = None;
+                            }
+                            None => println!("No file open for saving"),
                         }
                     }
                     SpecialCmd::Load(files) => {
                         for file in files.iter() {
                             cmp.compile_file(file.clone());
-                            cmp.execute(timed);
+                            if let Err(e) = cmp.execute(timed) {
+                                println!("Runtime error: {}", e);
+                            }
                         }
                     }
                     SpecialCmd::Error => println!("{}", err_str),
                     SpecialCmd::Help => println!("{}", help_str),
                     SpecialCmd::Quit => {
-                        if save_file.is_some() {
-                            save_file.unwrap().flush().expect("Error writing to file.");
+                        if let Some(mut file) = save_file {
+                            file.flush().expect("Error writing to file.");
                         }
                         return;
                     }
@@ -74,12 +83,14 @@ pub fn run(cmp: &mut Compiler) {
                     Ok(s) => {
                         if save_file.is_some() {
                             save_file = save_file.map(|mut x| {
-                                x.write(s.as_bytes()).expect("Could not write to file");
+                                x.write_all(s.as_bytes()).expect("Could not write to file");
                                 x
                             });
                         }
                         cmp.compile_string(s);
-                        cmp.execute(timed);
+                        if let Err(e) = cmp.execute_interpreted(timed) {
+                            println!("Runtime error: {}", e);
+                        }
                     }
                     Err(s) => println!("Error: {}, nothing saved", s),
                 }
@@ -107,9 +118,9 @@ fn special_command(chs: &mut CmdChars) -> SpecialCmd {
                 }
                 files.push(file);
             }
-            return SpecialCmd::Load(files);
+            SpecialCmd::Load(files)
         }
-        Some('t') => return SpecialCmd::Time,
+        Some('t') => SpecialCmd::Time,
         Some('s') => {
             chs.next();chs.next();
             if chs.peek().is_none() {
@@ -121,11 +132,11 @@ fn special_command(chs: &mut CmdChars) -> SpecialCmd {
                 file.push(chs.peek().unwrap());
                 chs.next();
             }
-            return SpecialCmd::File(file);
+            SpecialCmd::File(file)
         }
-        Some('q') => return SpecialCmd::Quit,
-        Some('h') => return SpecialCmd::Help,
-        _ => return SpecialCmd::Error,
+        Some('q') => SpecialCmd::Quit,
+        Some('h') => SpecialCmd::Help,
+        _ => SpecialCmd::Error,
     }
 }
 //Reads a sequence of characters until al loops and functions are read
@@ -6,25 +6,39 @@
 //! Loading multiple files in the interpreter at the same time results in
 //! each being compiled and instantly run after.
 
+mod report;
+pub use self::report::{CompileReport, CompileError};
+
 use dispatcher::*;
 use std::collections::VecDeque;
 use reader::Reader;
-use std::time::SystemTime;
-use optimiser::optimise_code;
+use std::time::{Instant, SystemTime};
+use optimiser::Optimiser;
+use bytecode::{Backend, Machine};
+use jit::Jit;
+use emit::{emit, Language, Target};
+use precompiled::{LoadError, Program};
+use std::fs;
+use std::io::{stdin, stdout, ErrorKind, Read, Stdin, Stdout, Write};
 
-enum CompileError {
-    Ok,
-    Error(String)
-}
 /// Main structure of the module, stores all the necessary data to run scripts
 ///
-/// Each Compiler has its own set of functions, so creating multiple Compilers is not recommended
+/// Each Compiler has its own array, stack and set of functions, so multiple Compilers
+/// can run side by side without affecting each other, even on different threads.
 ///
-pub struct Compiler {
+/// Scripts read from `R` and write to `W`, which are the standard input and output by default.
+///
+pub struct Compiler<R = Stdin, W = Stdout> {
     ops: VecDeque<Operation>,
-    temp: VecDeque<Operation>,
-    ah: ArrayHandler,
-    fh: FunctionHolder,
+    ctx: Context<R, W>,
+    optimiser: Optimiser,
+    backend: Backend,
+    machine: Machine,
+    jit: Jit,
+    // The number of functions at the last execute, and whether the operations queued since
+    // then were optimised to run first on an empty array and stack
+    base: usize,
+    from_start: bool,
 }
 
 impl Compiler {
@@ -41,13 +55,193 @@ impl Compiler {
     /// //do stuff with the compiler
     /// ```
     pub fn new() -> Compiler {
+        Compiler::with_io(stdin(), stdout())
+    }
+}
+
+impl<R: Read, W: Write> Compiler<R, W> {
+    /// Creates a new Compiler whose scripts read from `input` and write to `output`.
+    ///
+    /// # Examples
+    /// ```
+    /// use Brainfuck::Compiler;
+    ///
+    /// let mut compiler = Compiler::with_io("ab".as_bytes(), Vec::new());
+    /// compiler.compile_string(String::from(",.+.,."));
+    /// compiler.execute(false).unwrap();
+    /// assert_eq!(compiler.output(), b"abb");
+    /// ```
+    pub fn with_io(input: R, output: W) -> Compiler<R, W> {
         Compiler {
             ops: VecDeque::new(),
-            temp: VecDeque::new(),
-            ah: ArrayHandler::new(1024, true),
-            fh: FunctionHolder::new(),
+            ctx: Context::new(input, output),
+            optimiser: Optimiser::new(),
+            backend: Backend::Tree,
+            machine: Machine::new(),
+            jit: Jit::new(),
+            base: 0,
+            from_start: false,
+        }
+    }
+    /// Sets what reading does once the input is exhausted. The default is `EofMode::Unchanged`.
+    ///
+    /// # Examples
+    /// ```
+    /// use Brainfuck::Compiler;
+    /// use Brainfuck::dispatcher::EofMode;
+    ///
+    /// let mut compiler = Compiler::with_io("a".as_bytes(), Vec::new());
+    /// compiler.set_eof(EofMode::MinusOne);
+    /// compiler.compile_string(String::from(",[.,+]"));
+    /// compiler.execute(false).unwrap();
+    /// assert_eq!(compiler.output(), b"a");
+    /// ```
+    pub fn set_eof(&mut self, eof: EofMode) {
+        self.ctx.io.eof = eof;
+    }
+    /// Sets the type of the cells of the array and the stack. The default is `CellType::Trap`.
+    ///
+    /// Scripts are optimised for the cell type at the time they are compiled, so it should be
+    /// set before compiling anything. The values already in the array and the stack are
+    /// wrapped into the new range.
+    ///
+    /// # Examples
+    /// ```
+    /// use Brainfuck::Compiler;
+    /// use Brainfuck::dispatcher::CellType;
+    ///
+    /// let mut compiler = Compiler::with_io("".as_bytes(), Vec::new());
+    /// compiler.set_cell_type(CellType::U8);
+    /// compiler.compile_string(String::from("-[->+<]>."));
+    /// compiler.execute(false).unwrap();
+    /// assert_eq!(compiler.output(), &[255]);
+    /// ```
+    pub fn set_cell_type(&mut self, cells: CellType) {
+        self.ctx.set_cells(cells);
+    }
+    /// Sets the size of the array. The default, `None`, is an array growing in both directions.
+    ///
+    /// With a fixed size the pointer starts on the first cell and moving it off either end
+    /// stops the script with `RuntimeError::OutOfTape`, as in strict interpreters.
+    /// Setting it empties the array.
+    ///
+    /// Scripts are optimised for the tape at the time they are compiled, so it should be set
+    /// before compiling anything, like the cell type.
+    ///
+    /// # Examples
+    /// ```
+    /// use Brainfuck::Compiler;
+    /// use Brainfuck::dispatcher::RuntimeError;
+    ///
+    /// let mut compiler = Compiler::with_io("".as_bytes(), Vec::new());
+    /// compiler.set_tape(Some(30000));
+    /// compiler.compile_string(String::from("+>+<<"));
+    /// assert_eq!(compiler.execute(false), Err(RuntimeError::OutOfTape(-1)));
+    /// ```
+    pub fn set_tape(&mut self, size: Option<usize>) {
+        self.ctx.set_tape(size);
+    }
+    /// Sets the optimisation passes run on the scripts compiled from now on.
+    /// The default runs all of them, like `-O3`.
+    ///
+    /// # Examples
+    /// ```
+    /// use Brainfuck::Compiler;
+    /// use Brainfuck::optimiser::Optimiser;
+    ///
+    /// let mut compiler = Compiler::with_io("".as_bytes(), Vec::new());
+    /// compiler.set_optimiser(Optimiser::with_level(0).unwrap());
+    /// compiler.compile_string(String::from("++++++++[>++++++++<-]>+."));
+    /// compiler.execute(false).unwrap();
+    /// assert_eq!(compiler.output(), b"A");
+    /// ```
+    pub fn set_optimiser(&mut self, optimiser: Optimiser) {
+        self.optimiser = optimiser;
+    }
+    /// Returns the optimisation passes run on the scripts, allowing them to be changed
+    pub fn optimiser_mut(&mut self) -> &mut Optimiser {
+        &mut self.optimiser
+    }
+    /// Sets how the operations are executed. The default is `Backend::Tree`.
+    ///
+    /// A run suspended by `execute_with_fuel` is still continued by the backend it started on.
+    ///
+    /// # Examples
+    /// ```
+    /// use Brainfuck::Compiler;
+    /// use Brainfuck::bytecode::Backend;
+    ///
+    /// let mut compiler = Compiler::with_io("".as_bytes(), Vec::new());
+    /// compiler.set_backend(Backend::Bytecode);
+    /// compiler.compile_string(String::from("++++++++[>++++++++<-]>+."));
+    /// compiler.execute(false).unwrap();
+    /// assert_eq!(compiler.output(), b"A");
+    /// ```
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+    }
+    /// Returns a standalone program in `lang` running the operations compiled since the last
+    /// execute, with the functions and the settings of the compiler
+    ///
+    /// The program starts on an empty array and stack, see `emit::emit`.
+    ///
+    /// # Examples
+    /// ```
+    /// use Brainfuck::Compiler;
+    /// use Brainfuck::emit::Language;
+    ///
+    /// let mut compiler = Compiler::with_io("".as_bytes(), Vec::new());
+    /// compiler.compile_string(String::from("~,.~ >!"));
+    /// assert!(compiler.emit(Language::C).contains("int main(void)"));
+    /// ```
+    pub fn emit(&self, lang: Language) -> String {
+        let ops = self.ops.iter().cloned().collect::<Vec<_>>();
+        emit(lang, &ops, &self.ctx.fh, Target::of(&self.ctx))
+    }
+    /// Returns the operations compiled since the last execute and the functions declared
+    /// since then as a program which can be stored and loaded again, see `try_load_program`
+    ///
+    /// # Examples
+    /// ```
+    /// use Brainfuck::Compiler;
+    ///
+    /// let mut compiler = Compiler::with_io("".as_bytes(), Vec::new());
+    /// compiler.compile_string(String::from("~++++++++[>++++++++<-]>+.<~ !-|"));
+    /// let bytes = compiler.precompiled().to_bytes();
+    ///
+    /// let mut other = Compiler::with_io("".as_bytes(), Vec::new());
+    /// other.try_load_program(&bytes).unwrap();
+    /// other.execute(false).unwrap();
+    /// assert_eq!(other.output(), b"A");
+    /// ```
+    pub fn precompiled(&self) -> Program {
+        Program {
+            cells: self.ctx.cells(),
+            tape: self.ctx.ah.bound(),
+            starts_empty: self.from_start,
+            base: self.base,
+            functions: (self.base..self.ctx.fh.no_functions())
+                .map(|n| (*self.ctx.fh.get(n as i64).expect("stable function")).clone())
+                .collect(),
+            ops: self.ops.iter().cloned().collect(),
         }
     }
+    /// Returns the context the scripts are executed in
+    pub fn context(&self) -> &Context<R, W> {
+        &self.ctx
+    }
+    /// Returns the context the scripts are executed in, allowing it to be changed
+    pub fn context_mut(&mut self) -> &mut Context<R, W> {
+        &mut self.ctx
+    }
+    /// Returns the stream the scripts write to
+    pub fn output(&self) -> &W {
+        self.ctx.io.output()
+    }
+    /// Returns the stream the scripts write to, allowing it to be changed (e.g. cleared)
+    pub fn output_mut(&mut self) -> &mut W {
+        self.ctx.io.output_mut()
+    }
     /// Compiles a script file, optimising the operations and timing the process.
     ///
     /// # Examples
@@ -63,53 +257,20 @@ impl Compiler {
     ///
     /// If the file cannot be open/read, prints the error and does nothing.
     ///
-    /// If the timer returns an error, prints it and finishes compiling.
-    ///
     /// If the file contains any syntax errors, stops at the first error detected
-    /// and prints it, without compiling anything.
+    /// and prints it together with the line it was found on, without compiling anything.
     ///
     pub fn compile_file(&mut self, fname: String) {
         println!("Compiling file '{}'", fname);
-        let now = SystemTime::now();
-        let mut reader = match Reader::from_file(fname.clone()) {
-            Ok(rdr) => rdr,
-            Err(s) => {
-                println!("{}",s);
-                return;
-            }
-        };
-        let res = loop {
-            match dispatch(&mut reader, &mut self.fh) {
-                Op(op) => self.temp.push_back(op),
-                Fun => {}
-                Empty => break CompileError::Ok,
-                Error(s) => break CompileError::Error(s),
-            }
-        };
-        match res {
-            CompileError::Ok => {
-                self.fh.push_funs();
-                //println!("Before optimisation: {:?}", self.temp);
-                self.temp = optimise_code(self.temp.clone());
-                //println!("Optimised operations: {:?}", self.temp);
-                while !self.temp.is_empty() {
-                    self.ops.push_back(self.temp.pop_front().unwrap());
-                }
-                self.temp.shrink_to_fit();
-                match now.elapsed() {
-                    Ok(elapsed) => {
-                        let in_ms = (elapsed.as_secs() * 1000) as f64 +
-                            elapsed.subsec_nanos() as f64 / 1_000_000f64;
-                        println!("File '{}' compiled, elapsed time: {}ms", fname, in_ms);
-                    }
-                    Err(e) => println!("Timer error: {:?}\nFile '{}' compiled successfully", e, fname),
-                }
-            }
-            CompileError::Error(s) => {
-                self.fh.discard_funs();
-                self.temp.truncate(0);
-                println!("{}\nFile {} not compiled", s, fname);
+        match self.try_compile_file(&fname) {
+            Ok(report) => {
+                let elapsed = report.parse_time + report.optimise_time;
+                let in_ms = (elapsed.as_secs() * 1000) as f64 +
+                    f64::from(elapsed.subsec_nanos()) / 1_000_000f64;
+                println!("File '{}' compiled, elapsed time: {}ms", fname, in_ms);
             }
+            Err(CompileError::File(s)) => println!("{}", s),
+            Err(e) => println!("{}\nFile {} not compiled", e, fname),
         }
     }
     /// Takes a String and compiles the scripts contained by it
@@ -121,40 +282,188 @@ impl Compiler {
     /// let mut compiler = Compiler::new();
     /// //Pushes Set(0) as the last operation to be executed;
     /// compiler.compile_string(String::from("[-]"));
-    /// compiler.execute(false);
+    /// compiler.execute(false).unwrap();
     /// ```
     /// # Errors
     ///
-    /// If the string contains any syntax errors, prints the first error and
-    /// doesn't compile anything.
+    /// If the string contains any syntax errors, prints the first error and the
+    /// line it was found on and doesn't compile anything.
     ///
     pub fn compile_string(&mut self, s: String) {
-        let mut reader = Reader::from_string(s);
-        let res = loop {
-            match dispatch(&mut reader, &mut self.fh) {
-                Op(op) => self.temp.push_back(op),
-                Fun => {}   
-                Empty => break CompileError::Ok,
-                Error(s) => break CompileError::Error(s),
+        if let Err(e) = self.try_compile_string(s) {
+            println!("{}", e);
+        }
+    }
+    /// Compiles a script file without printing anything, reporting what was compiled.
+    ///
+    /// A file starting with `precompiled::MAGIC` is loaded as a precompiled program instead,
+    /// see `try_load_program`.
+    ///
+    /// # Examples
+    /// ```
+    /// use Brainfuck::Compiler;
+    ///
+    /// let mut compiler = Compiler::new();
+    /// let report = compiler.try_compile_file("loadtest").unwrap();
+    /// assert_eq!(report.functions_added, 1);
+    /// assert!(compiler.try_compile_file("no such file").is_err());
+    /// ```
+    /// # Errors
+    ///
+    /// Returns `CompileError::File` if the file cannot be open/read,
+    /// `CompileError::Syntax` for the first syntax error in the file and
+    /// `CompileError::Load` if it holds a precompiled program which is damaged, of another
+    /// version of the format or cannot be loaded.
+    /// In all cases nothing is compiled.
+    ///
+    pub fn try_compile_file(&mut self, fname: &str) -> Result<CompileReport, CompileError> {
+        let start = Instant::now();
+        let unreadable = || CompileError::File(format!("Could not read from file '{}'", fname));
+        let bytes = fs::read(fname).map_err(|e| match e.kind() {
+            ErrorKind::NotFound => CompileError::File(format!("No such file: {}", fname)),
+            _ => unreadable(),
+        })?;
+        match Program::from_bytes(&bytes) {
+            Ok(program) => self.load(program, start),
+            Err(LoadError::NotPrecompiled) => {
+                let s = String::from_utf8(bytes).map_err(|_| unreadable())?;
+                self.compile_reader(Reader::from_string(s), start)
             }
+            Err(e) => Err(CompileError::Load(e)),
+        }
+    }
+    /// Compiles a String without printing anything, reporting what was compiled.
+    ///
+    /// # Examples
+    /// ```
+    /// use Brainfuck::Compiler;
+    ///
+    /// let mut compiler = Compiler::new();
+    /// let report = compiler.try_compile_string(String::from(",+++--[->+<]")).unwrap();
+    /// assert_eq!((report.ops_before, report.ops_after), (8, 3));
+    /// assert!(compiler.try_compile_string(String::from("[")).is_err());
+    /// ```
+    /// # Errors
+    ///
+    /// Returns `CompileError::Syntax` for the first syntax error, without compiling anything.
+    ///
+    pub fn try_compile_string(&mut self, s: String) -> Result<CompileReport, CompileError> {
+        let start = Instant::now();
+        self.compile_reader(Reader::from_string(s), start)
+    }
+    /// Loads a program stored with `precompiled`, queueing its operations for execution
+    /// without parsing or optimising them.
+    ///
+    /// The report counts the operations of the program both before and after optimising,
+    /// and puts the time taken to read it in `parse_time`.
+    ///
+    /// # Examples
+    /// ```
+    /// use Brainfuck::Compiler;
+    /// use Brainfuck::precompiled::LoadError;
+    /// use Brainfuck::CompileError;
+    ///
+    /// let mut compiler = Compiler::with_io("".as_bytes(), Vec::new());
+    /// compiler.compile_string(String::from("~,.~"));
+    /// let library = compiler.precompiled().to_bytes();
+    ///
+    /// let mut other = Compiler::with_io("".as_bytes(), Vec::new());
+    /// assert_eq!(other.try_load_program(&library).unwrap().functions_added, 1);
+    /// // Its function is now number 0, so the same program cannot be loaded after it
+    /// match other.try_load_program(&library) {
+    ///     Err(CompileError::Load(e)) => assert_eq!(e, LoadError::Base(0, 1)),
+    ///     _ => unreachable!(),
+    /// }
+    /// ```
+    /// # Errors
+    ///
+    /// Returns `CompileError::Load` if `bytes` are not a program in the current version of
+    /// the format, or if the program was compiled for another type of cells or after
+    /// another number of functions, or for an unbounded tape while the current one is bounded.
+    /// A program whose start was evaluated when compiling also has to be loaded on an empty
+    /// array and stack, with the same tape.
+    /// Nothing is loaded then.
+    ///
+    pub fn try_load_program(&mut self, bytes: &[u8]) -> Result<CompileReport, CompileError> {
+        let program = Program::from_bytes(bytes).map_err(CompileError::Load)?;
+        self.load(program, Instant::now())
+    }
+    // Checks that `program` fits the compiler, then queues it like a compiled one
+    fn load(&mut self, program: Program, start: Instant) -> Result<CompileReport, CompileError> {
+        let funs = self.ctx.fh.no_functions();
+        let error = if program.cells != self.ctx.cells() {
+            Some(LoadError::Cells(program.cells))
+        } else if program.base != funs {
+            Some(LoadError::Base(program.base, funs))
+        } else if program.starts_empty && !self.starts_empty() {
+            Some(LoadError::NotEmpty)
+        } else if program.tape != self.ctx.ah.bound() && (program.starts_empty || program.tape.is_none()) {
+            Some(LoadError::Tape(program.tape))
+        } else {
+            None
         };
-        match res {
-            CompileError::Ok => {
-                self.fh.push_funs();
-                //println!("Before optimisation: {:?}", self.temp);
-                self.temp = optimise_code(self.temp.clone());
-                //println!("Optimised operations: {:?}", self.temp);
-                while !self.temp.is_empty() {
-                    self.ops.push_back(self.temp.pop_front().unwrap());
-                }
-                self.temp.shrink_to_fit();
-            }
-            CompileError::Error(s) => {
-                self.fh.discard_funs();
-                self.temp.truncate(0);
-                println!("{}", s);
+        if let Some(e) = error {
+            return Err(CompileError::Load(e));
+        }
+        let functions_added = program.functions.len();
+        for fun in program.functions {
+            self.ctx.fh.push(fun);
+        }
+        let ops = size(&program.ops);
+        if self.ops.is_empty() {
+            self.from_start = program.starts_empty;
+        }
+        self.ops.extend(program.ops);
+        Ok(CompileReport {
+            ops_before: ops,
+            ops_after: ops,
+            functions_added,
+            parse_time: start.elapsed(),
+            optimise_time: Default::default(),
+        })
+    }
+    // Checks if the next operations compiled run first on an empty array and stack
+    fn starts_empty(&self) -> bool {
+        self.ops.is_empty() && self.ctx.ah.pointer() == 0 && self.ctx.stack.is_empty() && self.ctx.ah.values().is_empty()
+    }
+    // Parses and optimises everything in `reader`, queueing the operations for execution.
+    // On error nothing is queued and the functions declared by the script are discarded.
+    fn compile_reader(&mut self, mut reader: Reader, start: Instant) -> Result<CompileReport, CompileError> {
+        let funs = self.ctx.fh.no_functions();
+        let ops = match parse(&mut reader, &mut self.ctx.fh) {
+            Ok(ops) => ops,
+            Err(e) => {
+                self.ctx.fh.discard_funs();
+                let shown = e.show(&reader);
+                return Err(CompileError::Syntax(e, shown));
             }
+        };
+        let parse_time = start.elapsed();
+        let ops_before = size(&ops);
+        let start = Instant::now();
+        let (cells, bound) = (self.ctx.cells(), self.ctx.ah.bound());
+        let optimiser = &self.optimiser;
+        self.ctx.fh.map_temp(|fun, fh| optimiser.optimise_function(fun, cells, fh, bound));
+        self.ctx.fh.push_funs();
+        let from_start = self.starts_empty();
+        let ops = if from_start {
+            self.optimiser.optimise_start(ops, cells, &self.ctx.fh, bound)
+        } else {
+            self.optimiser.optimise(ops, cells, &self.ctx.fh, bound)
+        };
+        let optimise_time = start.elapsed();
+        let ops_after = size(&ops);
+        if self.ops.is_empty() {
+            self.from_start = from_start;
         }
+        self.ops.extend(ops);
+        Ok(CompileReport {
+            ops_before,
+            ops_after,
+            functions_added: self.ctx.fh.no_functions() - funs,
+            parse_time,
+            optimise_time,
+        })
     }
     /// Runs the operations compiled since the last execute
     ///
@@ -169,30 +478,356 @@ impl Compiler {
     /// //Pushes Set(0) as the last operation to be executed;
     /// compiler.compile_string(String::from("[-]"));
     /// //Executes the Set(0) operation timed.
-    /// compiler.execute(true);
+    /// compiler.execute(true).unwrap();
     /// //Doesn't execute anything
-    /// compiler.execute(false);
+    /// compiler.execute(false).unwrap();
     /// ```
     ///
     /// # Errors
     ///
     /// If the timer has any errors, prints the error, but doesn't affect the operations in any way.
     ///
-    pub fn execute(&mut self, timed: bool) {
+    /// If an operation fails, the rest of the operations are discarded and the error is returned.
+    /// The array keeps the values it had when the error occurred.
+    ///
+    pub fn execute(&mut self, timed: bool) -> Result<(), RuntimeError> {
+        self.run(timed, None, true)
+    }
+    /// Runs the operations compiled since the last execute like `execute`, but with
+    /// `Backend::Bytecode` instead of `Backend::Jit`
+    ///
+    /// Compiling a few operations to machine code takes longer than interpreting them, so
+    /// the lines typed in the interactive interpreter are run this way.
+    ///
+    /// # Examples
+    /// ```
+    /// use Brainfuck::Compiler;
+    /// use Brainfuck::bytecode::Backend;
+    ///
+    /// let mut compiler = Compiler::with_io("".as_bytes(), Vec::new());
+    /// compiler.set_backend(Backend::Jit);
+    /// compiler.compile_string(String::from("++++++++[>++++++++<-]>+."));
+    /// compiler.execute_interpreted(false).unwrap();
+    /// assert_eq!(compiler.output(), b"A");
+    /// ```
+    pub fn execute_interpreted(&mut self, timed: bool) -> Result<(), RuntimeError> {
+        self.run(timed, None, false)
+    }
+    /// Runs the operations compiled since the last execute, stopping after `fuel` operations.
+    /// Each iteration of a loop counts as one more operation.
+    ///
+    /// # Examples
+    /// ```
+    /// use Brainfuck::Compiler;
+    /// use Brainfuck::dispatcher::RuntimeError;
+    ///
+    /// let mut compiler = Compiler::with_io("".as_bytes(), Vec::new());
+    /// compiler.compile_string(String::from("+[.>+<]"));
+    /// assert_eq!(compiler.execute_with_fuel(false, 1000), Err(RuntimeError::OutOfFuel));
+    /// let printed = compiler.output().len();
+    /// // Continues the same loop
+    /// assert_eq!(compiler.execute_with_fuel(false, 1000), Err(RuntimeError::OutOfFuel));
+    /// assert!(compiler.output().len() > printed);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns `OutOfFuel` if the fuel ran out first. The operations are kept and the next
+    /// execute continues the run from where it stopped, with the fuel it is given.
+    ///
+    /// Otherwise fails like `execute`.
+    pub fn execute_with_fuel(&mut self, timed: bool, fuel: u64) -> Result<(), RuntimeError> {
+        self.run(timed, Some(fuel), true)
+    }
+    // Runs or continues the operations with the given fuel, compiling them to machine code
+    // if `native` and the backend says so
+    fn run(&mut self, timed: bool, fuel: Option<u64>, native: bool) -> Result<(), RuntimeError> {
+        self.ctx.set_fuel(fuel);
         let now = SystemTime::now();
-        for i in self.ops.iter() {
-            i.execute(&mut self.ah, &mut self.fh);
-        }
+        // A suspended run is continued by the backend it started on
+        let backend = if self.ctx.is_suspended() {
+            Backend::Tree
+        } else if self.machine.is_suspended() {
+            Backend::Bytecode
+        } else {
+            self.backend
+        };
+        let res = match backend {
+            Backend::Tree => self.ctx.run(self.ops.make_contiguous()),
+            Backend::Jit if native && fuel.is_none() && Jit::is_supported() => {
+                self.jit.run(&mut self.ctx, self.ops.make_contiguous())
+            }
+            Backend::Bytecode | Backend::Jit => self.machine.run(&mut self.ctx, self.ops.make_contiguous()),
+        };
+        self.ctx.set_fuel(None);
+        let flushed = self.ctx.io.flush();
         if timed {
             match now.elapsed() {
                 Ok(elapsed) => {
                     let in_ms = (elapsed.as_secs() * 1000) as f64 +
-                        elapsed.subsec_nanos() as f64 / (1_000_000 as f64);
+                        f64::from(elapsed.subsec_nanos()) / 1_000_000f64;
                     println!("Time elapsed: {}ms", in_ms);
                 }
                 Err(e) => println!("Unexpected timer error: {:?}", e),
             }
         }
-        self.ops = VecDeque::new();
+        if res != Err(RuntimeError::OutOfFuel) {
+            self.ops = VecDeque::new();
+            self.base = self.ctx.fh.no_functions();
+        }
+        res.and(flushed)
+    }
+}
+
+impl Default for Compiler {
+    fn default() -> Compiler {
+        Compiler::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Compiler;
+    use dispatcher::{CellType, EofMode, RuntimeError};
+    use dispatcher::Operation::*;
+    use optimiser::{Optimiser, Pass};
+    use bytecode::Backend;
+    use precompiled::{LoadError, MAGIC};
+    use compiler::CompileError;
+    use std::{env, fs, process, thread};
+    #[test]
+    fn captures_output() {
+        let mut cmp = Compiler::with_io("".as_bytes(), Vec::new());
+        cmp.compile_file(String::from("hello_world"));
+        cmp.execute(false).unwrap();
+        assert!(cmp.output().starts_with(b"Hello World!\n"));
+        assert!(cmp.output().ends_with(b"Hello World!\nHello World!\n"));
+    }
+    #[test]
+    fn reads_given_input() {
+        let mut cmp = Compiler::with_io("hi\nyou\n".as_bytes(), Vec::new());
+        cmp.compile_string(String::from(",.,.,,.,.,."));
+        cmp.execute(false).unwrap();
+        assert_eq!(cmp.output().as_slice(), &b"hiyou"[..]);
+    }
+    #[test]
+    fn end_of_input() {
+        for &(eof, cell) in &[(EofMode::Unchanged, 7), (EofMode::Zero, 0), (EofMode::MinusOne, -1)] {
+            let mut cmp = Compiler::with_io("x".as_bytes(), Vec::new());
+            cmp.set_eof(eof);
+            cmp.compile_string(String::from(",>+++++++,,"));
+            cmp.execute(false).unwrap();
+            assert_eq!(cmp.context().ah.get(), cell);
+        }
+    }
+    #[test]
+    fn fuel_continues_the_run() {
+        let script = "~>[>+<-]>&~~>>++++[<++++++++>-]<.[-]~+++[>+/-<-]>>+++>++<<|^.<+/\\.";
+        let mut expected = Compiler::with_io("".as_bytes(), Vec::new());
+        expected.compile_string(String::from(script));
+        expected.execute(false).unwrap();
+        for &backend in [Backend::Tree, Backend::Bytecode, Backend::Jit].iter() {
+            for fuel in 1..20 {
+                let mut cmp = Compiler::with_io("".as_bytes(), Vec::new());
+                cmp.set_backend(backend);
+                // Run the script instead of its snapshot
+                cmp.optimiser_mut().set_pass(Pass::Prefix, false);
+                cmp.compile_string(String::from(script));
+                while let Err(e) = cmp.execute_with_fuel(false, fuel) {
+                    assert_eq!(e, RuntimeError::OutOfFuel);
+                }
+                assert!(!cmp.context().is_suspended() && !cmp.machine.is_suspended());
+                assert_eq!(cmp.output(), expected.output());
+                assert_eq!(cmp.context().ah.values(), expected.context().ah.values());
+                assert_eq!(cmp.context().stack.as_slice(), expected.context().stack.as_slice());
+            }
+        }
+    }
+    #[test]
+    fn failures_stop_where_the_script_does() {
+        for &backend in [Backend::Tree, Backend::Bytecode, Backend::Jit].iter() {
+            let mut cmp = Compiler::with_io("".as_bytes(), Vec::new());
+            cmp.set_backend(backend);
+            cmp.optimiser_mut().set_pass(Pass::Prefix, false);
+            // Popping the empty stack fails before the first cell is cleared
+            cmp.compile_string(String::from("+>>^<<[-]"));
+            assert_eq!(cmp.execute(false), Err(RuntimeError::StackUnderflow));
+            assert_eq!(cmp.context().ah.pointer(), 2, "{:?}", backend);
+            assert_eq!(cmp.context().ah.values(), vec![(0, 1)], "{:?}", backend);
+        }
+    }
+    #[test]
+    fn moves_off_a_bounded_tape_fail_at_every_level() {
+        let scripts = ["<>", ">>+<<<>>>", "+[>+<-]>>>>>>>>>>-<<<<<<<<<<", ">>>>>>>>+[->>+<<]", "~<+>~!!-|", "+++[>>>>>>>>>+<<<<<<<<-]"];
+        let run = |script: &str, level, backend| {
+            let mut cmp = Compiler::with_io("".as_bytes(), Vec::new());
+            cmp.set_tape(Some(10));
+            cmp.set_backend(backend);
+            cmp.set_optimiser(Optimiser::with_level(level).unwrap());
+            cmp.compile_string(script.to_string());
+            let res = cmp.execute(false);
+            (res, cmp.context().ah.pointer(), cmp.context().ah.values())
+        };
+        for script in scripts.iter() {
+            let expected = run(script, 0, Backend::Tree);
+            assert!(expected.0.is_err(), "{}", script);
+            for &backend in [Backend::Tree, Backend::Bytecode, Backend::Jit].iter() {
+                assert_eq!(run(script, 3, backend), expected, "{} on {:?}", script, backend);
+            }
+        }
+    }
+    #[test]
+    fn multiplying_loops() {
+        let run = |cells, script: &str| {
+            let mut cmp = Compiler::with_io("".as_bytes(), Vec::new());
+            cmp.set_cell_type(cells);
+            cmp.compile_string(String::from(script));
+            let res = cmp.execute_with_fuel(false, 1000);
+            (res, cmp.output().clone())
+        };
+        assert_eq!(run(CellType::U8, "++++++[-->+<]>."), (Ok(()), vec![3]));
+        assert_eq!(run(CellType::U8, "+++++[-->+<]>."), (Err(RuntimeError::OutOfFuel), vec![]));
+        assert_eq!(run(CellType::U8, "-[>+<---]>."), (Ok(()), vec![85]));
+        // Overflowing takes as many iterations as the loop would run before leaving the range
+        assert_eq!(run(CellType::Trap, "+++++[-->+<]").0, Err(RuntimeError::OutOfFuel));
+        let (min, max) = (i64::from(i32::MIN), i64::from(i32::MAX));
+        for &backend in [Backend::Tree, Backend::Bytecode, Backend::Jit].iter() {
+            for &(script, ref values) in [("+++++[-->+<]", vec![(0, min + 1), (1, 1073741826)]), ("-[->+<]", vec![(0, min), (1, max)])].iter() {
+                let mut cmp = Compiler::with_io("".as_bytes(), Vec::new());
+                cmp.set_backend(backend);
+                cmp.compile_string(String::from(script));
+                assert_eq!(cmp.execute(false), Err(RuntimeError::Overflow));
+                assert_eq!(&cmp.context().ah.values(), values);
+            }
+        }
+        assert_eq!(run(CellType::Trap, "+++[>[-]++>+<<-]>.>."), (Ok(()), vec![2, 3]));
+    }
+    #[test]
+    fn function_bodies_are_optimised() {
+        let mut cmp = Compiler::with_io("".as_bytes(), Vec::new());
+        cmp.set_cell_type(CellType::U8);
+        cmp.compile_string(String::from("~+++--->><<[-]++++.>+<~ |[-]/"));
+        cmp.execute(false).unwrap();
+        let fun = cmp.context().fh.get(0).unwrap();
+        assert_eq!(fun.ops(), &[Set(0, 4), Write(0), Add(1, 1)]);
+        assert_eq!(fun.separate_ops(), &[Set(0, 4), Write(0)]);
+        assert_eq!(cmp.output(), &[4, 4]);
+        assert_eq!(cmp.context().ah.values(), vec![(1, 1)]);
+    }
+    #[test]
+    fn known_calls_are_inlined() {
+        let mut cmp = Compiler::with_io("".as_bytes(), Vec::new());
+        cmp.optimiser_mut().set_pass(Pass::Prefix, false);
+        cmp.compile_string(String::from("~++.>+<~ ~!!->+<|~ !!-| !!--/"));
+        assert!(cmp.ops.iter().all(|op| !matches!(*op, CallFun | CallFSep(_) | Call(_) | CallSep(_, _))));
+        assert!(cmp.ops.iter().any(|op| matches!(*op, Separate(0, _))));
+        cmp.execute(false).unwrap();
+        assert_eq!(cmp.output(), &[2, 2]);
+        assert_eq!(cmp.context().ah.values(), vec![(1, 2)]);
+    }
+    #[test]
+    fn start_is_evaluated_when_compiling() {
+        let mut cmp = Compiler::with_io("a".as_bytes(), Vec::new());
+        cmp.compile_string(String::from("~>++.<~ ++++++++[>++++++++<-]>+.&!!-|>,."));
+        assert!(matches!(cmp.ops[0], Restore(_)));
+        assert_eq!(cmp.ops.iter().skip(1).cloned().collect::<Vec<_>>(), vec![Read(1), Write(1), Move(1)]);
+        // Queued operations run first, so the next script is not evaluated
+        cmp.compile_string(String::from("+."));
+        assert_eq!(cmp.ops.iter().skip(4).cloned().collect::<Vec<_>>(), vec![Add(0, 1), Write(0)]);
+        cmp.execute(false).unwrap();
+        assert_eq!(cmp.output(), b"A\x02ab");
+        assert_eq!(cmp.context().stack.as_slice(), &[65]);
+        // Neither is a script running on a changed array
+        cmp.compile_string(String::from("."));
+        assert_eq!(cmp.ops, vec![Write(0)]);
+    }
+    #[test]
+    fn levels_give_the_same_output() {
+        let scripts = ["~++.>+<~ ~!!->+<|~ !!-| !!--/", "++++[>+++<-]>[>++<<+>-]<.>>.", "~[-]++>+++<~++&>!!-/?^.<."];
+        for script in scripts.iter() {
+            let outputs: Vec<_> = (0..4).map(|level| {
+                let mut cmp = Compiler::with_io("".as_bytes(), Vec::new());
+                cmp.set_optimiser(Optimiser::with_level(level).unwrap());
+                cmp.compile_string(script.to_string());
+                cmp.execute(false).unwrap();
+                cmp.output().clone()
+            }).collect();
+            assert!(outputs.iter().all(|out| *out == outputs[3]), "{}", script);
+        }
+    }
+    #[test]
+    fn compilers_do_not_share_state() {
+        let handles: Vec<_> = (1..5).map(|i| thread::spawn(move || {
+            let mut cmp = Compiler::with_io("".as_bytes(), Vec::new());
+            let script = "+".repeat(i) + "&>?.<";
+            for _ in 0..100 {
+                cmp.compile_string(script.clone());
+                cmp.execute(false).unwrap();
+            }
+            cmp.compile_string("[-]".repeat(100) + &"^".repeat(100) + ".");
+            cmp.execute(false).unwrap();
+            cmp.output().clone()
+        })).collect();
+        for (i, h) in handles.into_iter().enumerate() {
+            let mut expected: Vec<u8> = (1..101).collect();
+            // Sum of i+1, 2(i+1), ..., 100(i+1), truncated to a byte
+            expected.push((5050 * (i + 1)) as u8);
+            assert_eq!(h.join().unwrap(), expected);
+        }
+    }
+    #[test]
+    fn precompiled_files_run_like_the_scripts() {
+        let mut cmp = Compiler::with_io("".as_bytes(), Vec::new());
+        let dir = env::temp_dir().join(format!("brainfuck-precompiled-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (mut paths, mut results) = (Vec::new(), Vec::new());
+        for file in &["loadtest", "funtest", "hello_world"] {
+            cmp.try_compile_file(file).unwrap();
+            paths.push(dir.join(file));
+            fs::write(&paths[paths.len() - 1], cmp.precompiled().to_bytes()).unwrap();
+            results.push(cmp.execute(false));
+        }
+        let mut loaded = Compiler::with_io("".as_bytes(), Vec::new());
+        match loaded.try_compile_file(paths[1].to_str().unwrap()) {
+            Err(CompileError::Load(e)) => assert_eq!(e, LoadError::Base(1, 0)),
+            _ => panic!("funtest loaded without the function of loadtest"),
+        }
+        for (path, result) in paths.iter().zip(results) {
+            let report = loaded.try_compile_file(path.to_str().unwrap()).unwrap();
+            assert_eq!(report.optimise_time, Default::default());
+            assert_eq!(loaded.execute(false), result);
+        }
+        assert!(!loaded.output().is_empty());
+        assert_eq!(loaded.output(), cmp.output());
+        // Files of another version of the format are rejected instead of read as scripts
+        let newer = dir.join("newer");
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&99u32.to_le_bytes());
+        bytes.extend_from_slice(b"+++.");
+        fs::write(&newer, bytes).unwrap();
+        match loaded.try_compile_file(newer.to_str().unwrap()) {
+            Err(CompileError::Load(e)) => assert_eq!(e, LoadError::Version(99)),
+            _ => panic!("a program of another version was not rejected"),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn evaluated_starts_only_load_on_empty_compilers() {
+        let mut cmp = Compiler::with_io("".as_bytes(), Vec::new());
+        cmp.compile_string(String::from("++++++++[>++++++++<-]>+."));
+        let program = cmp.precompiled();
+        assert!(program.starts_empty);
+        let mut loaded = Compiler::with_io("".as_bytes(), Vec::new());
+        loaded.compile_string(String::from(">+"));
+        loaded.execute(false).unwrap();
+        match loaded.try_load_program(&program.to_bytes()) {
+            Err(CompileError::Load(e)) => assert_eq!(e, LoadError::NotEmpty),
+            _ => panic!("an evaluated start loaded on a used array"),
+        }
+        loaded.set_cell_type(CellType::U8);
+        match loaded.try_load_program(&program.to_bytes()) {
+            Err(CompileError::Load(e)) => assert_eq!(e, LoadError::Cells(CellType::Trap)),
+            _ => panic!("a program loaded for other cells"),
+        }
     }
-}
\ No newline at end of file
+}
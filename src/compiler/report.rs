@@ -0,0 +1,42 @@
+//! The results of compiling a script
+
+use dispatcher::SyntaxError;
+use precompiled::LoadError;
+use std::fmt;
+use std::time::Duration;
+
+/// Statistics about a successful compile
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompileReport {
+    /// Number of operations parsed, including the ones inside loops
+    pub ops_before: usize,
+    /// Number of operations left after optimising, including the ones inside loops
+    pub ops_after: usize,
+    /// Number of functions declared by the script
+    pub functions_added: usize,
+    /// Time spent reading and parsing the script
+    pub parse_time: Duration,
+    /// Time spent optimising the operations
+    pub optimise_time: Duration,
+}
+
+/// The reasons a script can fail to compile
+#[derive(Clone, Debug)]
+pub enum CompileError {
+    /// The file could not be opened or read
+    File(String),
+    /// The script has a syntax error. The string shows the error together with the line it is on.
+    Syntax(SyntaxError, String),
+    /// The precompiled program cannot be loaded
+    Load(LoadError),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CompileError::File(ref s) => write!(f, "{}", s),
+            CompileError::Syntax(_, ref s) => write!(f, "{}", s),
+            CompileError::Load(ref e) => write!(f, "{}", e),
+        }
+    }
+}
@@ -2,10 +2,9 @@
 //! and returning the unoptimised operations.
 
 pub mod operation;
-use reader::Reader;
+use reader::{Reader, Span};
 pub use self::operation::*;
 pub use self::Dispatcher::*;
-use optimiser::loop_optimiser;
 
 ///Last type of operation found
 pub enum Dispatcher{
@@ -16,7 +15,58 @@ pub enum Dispatcher{
     ///Finished the script
     Empty,
     ///Found an error in the script
-    Error(String),
+    Error(SyntaxError),
+}
+
+/// An error found while parsing a script
+#[derive(Clone, Debug)]
+pub struct SyntaxError {
+    /// What went wrong
+    pub msg: String,
+    /// Where in the source the error was caused
+    pub span: Span,
+}
+
+impl SyntaxError {
+    fn new(msg: &str, span: Span) -> SyntaxError {
+        SyntaxError { msg: String::from(msg), span }
+    }
+    /// Formats the error, showing the line that caused it from the script read by `rd`
+    pub fn show(&self, rd: &Reader) -> String {
+        rd.show(self.span, &self.msg)
+    }
+}
+
+/// Parses everything left in `rd`, returning the top level operations in order.
+///
+/// Functions are added to `fh` as temp functions, to be pushed with `FunctionHolder::push_funs`
+/// once the operations are accepted.
+///
+/// # Examples
+/// ```
+/// use Brainfuck::reader::Reader;
+/// use Brainfuck::dispatcher::{parse, FunctionHolder, Operation};
+///
+/// let mut fh = FunctionHolder::new();
+/// let mut rd = Reader::from_string(String::from("~+~ ++>[-]"));
+/// let ops = parse(&mut rd, &mut fh).unwrap();
+/// assert_eq!(ops, vec![Operation::Add(0, 2), Operation::Move(1), Operation::While(vec![Operation::Add(0, -1)])]);
+/// assert_eq!(fh.curr_funs(), 1);
+/// ```
+///
+/// # Errors
+/// Returns the first syntax error in the script. The temp functions declared before it
+/// are kept in `fh`, so they should be discarded.
+pub fn parse(rd: &mut Reader, fh: &mut FunctionHolder) -> Result<Vec<Operation>, SyntaxError> {
+    let mut ops = Vec::new();
+    loop {
+        match dispatch(rd, fh) {
+            Op(op) => ops.push(op),
+            Fun => {}
+            Empty => return Ok(ops),
+            Error(e) => return Err(e),
+        }
+    }
 }
 
 ///Parses the next character (and more if necessary) and returns the corresponding operation
@@ -24,19 +74,20 @@ pub fn dispatch(rd: &mut Reader, fh: &mut FunctionHolder) -> Dispatcher {
     if !rd.has_next() {
         return Empty;
     }
+    let span = rd.span();
     match rd.peek() {
-        '>' => return Op(Move(amalgamate(rd))),
-        '<' => return Op(Move(-amalgamate(rd))),
-        '+' => return Op(Add(amalgamate(rd))),
-        '-' => return Op(Add(-amalgamate(rd))),
-        '.' => {rd.next(); return Op(Write);}
-        ',' => {rd.next(); return Op(Read);}
+        '>' => Op(Move(amalgamate(rd))),
+        '<' => Op(Move(-amalgamate(rd))),
+        '+' => Op(Add(0, i64::from(amalgamate(rd)))),
+        '-' => Op(Add(0, -i64::from(amalgamate(rd)))),
+        '.' => {rd.next(); Op(Write(0))}
+        ',' => {rd.next(); Op(Read(0))}
         '!' => {
             let n = amalgamate(rd);
             if n > 1 {
-                return Op(Set(fh.curr_funs()));
+                Op(Set(0, i64::from(fh.curr_funs())))
             } else {
-                return Op(InsFuns);
+                Op(InsFuns)
             }
         }
         '/' => {
@@ -45,21 +96,21 @@ pub fn dispatch(rd: &mut Reader, fh: &mut FunctionHolder) -> Dispatcher {
                 return Op(CallFSep(0));
             }
             match rd.peek() {
-                '\\' => return Op(CallFSep(amalgamate(rd) as usize)),
-                _ => return Op(CallFSep(0)),
+                '\\' => Op(CallFSep(amalgamate(rd) as usize)),
+                _ => Op(CallFSep(0)),
             }
         }
-        '|' => {rd.next(); return Op(CallFun);}
-        ']' => {rd.next(); return Error(String::from("Found ']' without matching '['"))}
-        '\\' => {rd.next(); return Error(String::from("Found '\\' without '/' function call"))}
-        '[' => {rd.next(); return create_loop(rd, fh)}
-        '~' => {rd.next(); return create_fun(rd, fh)}
-        '#' => {amalgamate(rd); return Op(Debug)}
-        '@' => {amalgamate(rd); return Op(PeekStack)}
-        '&' => {rd.next(); return Op(PushStack)}
-        '^' => {rd.next(); return Op(PopStack(1))}
-        '?' => {amalgamate(rd); return Op(StackLen)}
-        _ => return Error(String::from("Unidentified character passed filtering")),
+        '|' => {rd.next(); Op(CallFun)}
+        ']' => {rd.next(); Error(SyntaxError::new("found ']' without matching '['", span))}
+        '\\' => {rd.next(); Error(SyntaxError::new("found '\\' without '/' function call", span))}
+        '[' => {rd.next(); create_loop(rd, fh, span)}
+        '~' => {rd.next(); create_fun(rd, fh, span)}
+        '#' => {amalgamate(rd); Op(Debug)}
+        '@' => {amalgamate(rd); Op(PeekStack(0))}
+        '&' => {rd.next(); Op(PushStack(0))}
+        '^' => {rd.next(); Op(PopStack(0, 1))}
+        '?' => {amalgamate(rd); Op(StackLen(0))}
+        _ => Error(SyntaxError::new("unidentified character passed filtering", span)),
     }
 }
 /// Collapses multiple operations of the same kind
@@ -74,47 +125,86 @@ fn amalgamate(rd: &mut Reader) -> i32 {
 }
 /// Creates a loop, reads until it finds a corresponding ']'
 ///
+/// `start` is the position of the opening '['.
+///
 /// # Errors
 /// Returns an error if the loop has no matching ']' or a function is declared inside.
-fn create_loop(rd: &mut Reader, fh: &mut FunctionHolder) -> Dispatcher {
+fn create_loop(rd: &mut Reader, fh: &mut FunctionHolder, start: Span) -> Dispatcher {
     let mut ops: Vec<Operation> = vec![];
     while rd.has_next() && rd.peek() != ']' {
         match rd.peek() {
-            '~' => return Error(String::from("Error: functions cannot be declared in loops")),
+            '~' => return Error(SyntaxError::new("functions cannot be declared in loops", start)),
             _ => {
                 match dispatch(rd, fh) {
                     Op(op) => ops.push(op),
-                    Fun => return Error(String::from("Error: functions cannot be declared in loops")),
+                    Fun => return Error(SyntaxError::new("functions cannot be declared in loops", start)),
                     Error(s) => return Error(s),
-                    Empty => return Error(String::from("Error: Dispatch returned empty from non-empty reader")),
+                    Empty => return Error(SyntaxError::new("dispatch returned empty from non-empty reader", start)),
                 }
             }
         }
     }
     if !rd.has_next() {
-        return Error(String::from("Error: loop has no end point"));
+        return Error(SyntaxError::new("loop has no end point", start));
     }
     rd.next();
-    Op(loop_optimiser(ops))
+    Op(While(ops))
 }
 ///Creates a function. Reads until it finds a matching '~'
 ///
+/// `start` is the position of the opening '~'.
+///
 /// # Errors
 /// Returns an error if the function has no end point
-fn create_fun(rd: &mut Reader, fh: &mut FunctionHolder) -> Dispatcher {
+fn create_fun(rd: &mut Reader, fh: &mut FunctionHolder, start: Span) -> Dispatcher {
     let mut ops: Vec<Operation> = vec![];
     while rd.has_next() && rd.peek() != '~' {
         match dispatch(rd, fh) {
             Op(op) => ops.push(op),
-            Fun => return Error(String::from("Error: Function inside a function: HOW????")),
+            Fun => return Error(SyntaxError::new("function inside a function", start)),
             Error(s) => return Error(s),
-            Empty => return Error(String::from("Error: Dispatch returned empty from non-empty reader")),
+            Empty => return Error(SyntaxError::new("dispatch returned empty from non-empty reader", start)),
         }
     }
     if !rd.has_next() {
-        return Error(String::from("Error: function has no end point"));
+        return Error(SyntaxError::new("function has no end point", start));
     }
     rd.next();
     fh.add_temp(ops);
     Fun
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn first_error(s: &str) -> SyntaxError {
+        let mut rd = Reader::from_string(String::from(s));
+        let mut fh = FunctionHolder::new();
+        loop {
+            match dispatch(&mut rd, &mut fh) {
+                Error(e) => return e,
+                Empty => panic!("No error found in {:?}", s),
+                _ => {}
+            }
+        }
+    }
+    #[test]
+    fn unclosed_loop_points_at_start() {
+        let e = first_error("+[[-]\n>>");
+        assert_eq!(e.span, Span { line: 1, col: 2 });
+    }
+    #[test]
+    fn unclosed_function_points_at_start() {
+        let e = first_error("~+\n  ~ a ~\n+");
+        assert_eq!(e.span, Span { line: 2, col: 7 });
+    }
+    #[test]
+    fn functions_in_loops_point_at_the_loop() {
+        let e = first_error("+\n [-\n ~+~]");
+        assert_eq!(e.span, Span { line: 2, col: 2 });
+    }
+    #[test]
+    fn unmatched_end_of_loop() {
+        let e = first_error("++\n -]");
+        assert_eq!(e.span, Span { line: 2, col: 3 });
+    }
+}
@@ -1,71 +1,186 @@
 //! The array on which the operations are executed
 
-mod input_reader;
-use self::input_reader::INPUT_READER;
-use dispatcher::operation::stack_handler::STACK_HOLDER;
+use dispatcher::operation::{CellType, IoHandler, RuntimeError};
+use std::io::{Read, Write};
 
-/// Resizable array with 'negative' entries
+/// Resizable array with 'negative' entries, or a fixed size array starting at 0
 pub struct ArrayHandler {
-    arr: Vec<i32>,
+    arr: Vec<i64>,
     pos: usize,
     displacement: usize,
+    cells: CellType,
+    // The size of the tape if it does not grow
+    bound: Option<usize>,
 }
 
 impl ArrayHandler {
-    /// Creates a new array with the specified size
+    /// Creates a new array with the specified size and the default cell type
     ///
     /// If `mid_array` is true, the start point is in the middle of the array,
     /// allowing going to the left at 0 cost
     pub fn new(capacity: usize, mid_array: bool) -> ArrayHandler {
+        ArrayHandler::with_cells(capacity, mid_array, CellType::default())
+    }
+    /// Creates a new array with the specified size, holding values of type `cells`
+    pub fn with_cells(capacity: usize, mid_array: bool, cells: CellType) -> ArrayHandler {
         assert!(capacity > 0);
-        if mid_array {
-            ArrayHandler{
-                arr: vec![0; capacity],
-                pos: capacity/2,
-                displacement: capacity/2,
-            }
-        } else {
-            ArrayHandler{
-                arr: vec![0; capacity],
-                pos: 0,
-                displacement: 0,
-            }
+        let pos = if mid_array { capacity/2 } else { 0 };
+        ArrayHandler{
+            arr: vec![0; capacity],
+            pos,
+            displacement: pos,
+            cells,
+            bound: None,
+        }
+    }
+    /// Creates an array of exactly `size` cells holding values of type `cells`,
+    /// with the pointer on the first one
+    ///
+    /// Moving outside the array is an error instead of making it grow.
+    pub fn bounded(size: usize, cells: CellType) -> ArrayHandler {
+        assert!(size > 0);
+        ArrayHandler{
+            arr: vec![0; size],
+            pos: 0,
+            displacement: 0,
+            cells,
+            bound: Some(size),
+        }
+    }
+    /// Returns the size of the array if it is bounded
+    pub fn bound(&self) -> Option<usize> {
+        self.bound
+    }
+    /// Returns the type of the cells
+    pub fn cells(&self) -> CellType {
+        self.cells
+    }
+    /// Changes the type of the cells, wrapping the current values into the new range
+    pub fn set_cells(&mut self, cells: CellType) {
+        self.cells = cells;
+        for val in self.arr.iter_mut() {
+            *val = cells.wrap(*val).unwrap_or(i64::from(*val as i32));
         }
     }
     /// Adds to the current position
-    pub fn add(&mut self, i: i32) {
-        self.arr[self.pos] += i;
+    pub fn add(&mut self, i: i64) -> Result<(), RuntimeError> {
+        self.arr[self.pos] = self.cells.add(self.arr[self.pos], i)?;
+        Ok(())
     }
     /// Adds at a position with displacement `pos`
-    pub fn add_at(&mut self, pos: i32, val: i32) {
-        self.holds(pos);
-        let pos = if pos < 0 {
-            self.pos - (-pos) as usize
-        } else {
-            self.pos + pos as usize
-        };
-        self.arr[pos] += val;
+    pub fn add_at(&mut self, pos: i32, val: i64) -> Result<(), RuntimeError> {
+        let pos = self.index(pos)?;
+        self.arr[pos] = self.cells.add(self.arr[pos], val)?;
+        Ok(())
+    }
+    /// Sets the element at a position with displacement `pos` to `val`
+    pub fn set_at(&mut self, pos: i32, val: i64) -> Result<(), RuntimeError> {
+        let pos = self.index(pos)?;
+        self.arr[pos] = self.cells.wrap(val)?;
+        Ok(())
+    }
+    /// Gets the value at a position with displacement `pos`
+    pub fn get_at(&mut self, pos: i32) -> Result<i64, RuntimeError> {
+        let pos = self.index(pos)?;
+        Ok(self.arr[pos])
+    }
+    // Returns the index in `arr` of the position with displacement `pos`
+    fn index(&mut self, pos: i32) -> Result<usize, RuntimeError> {
+        let index = self.pos as i64 + i64::from(pos);
+        if index >= 0 && index < self.arr.len() as i64 {
+            return Ok(index as usize);
+        }
+        self.holds(pos)?;
+        Ok((self.pos as i64 + i64::from(pos)) as usize)
+    }
+    /// Makes sure the cells from displacement `min` to `max` exist, so the `_reserved`
+    /// methods can reach them without checking the size of the array
+    ///
+    /// The array only grows afterwards, so the cells stay in it until it is replaced.
+    /// Returns false, reserving nothing, if some of them are outside a bounded array.
+    pub fn reserve(&mut self, min: i32, max: i32) -> bool {
+        self.holds(min).is_ok() && self.holds(max).is_ok()
+    }
+    // Returns the index in `arr` of a reserved position with displacement `pos`
+    fn reserved(&self, pos: i32) -> usize {
+        (self.pos as isize + pos as isize) as usize
+    }
+    /// Adds at a reserved position with displacement `pos`, see `reserve`
+    ///
+    /// # Safety
+    /// The cell at displacement `pos` has to be one of the cells reserved in this array.
+    pub unsafe fn add_reserved(&mut self, pos: i32, val: i64) -> Result<(), RuntimeError> {
+        let cells = self.cells;
+        let pos = self.reserved(pos);
+        let cell = self.arr.get_unchecked_mut(pos);
+        *cell = cells.add(*cell, val)?;
+        Ok(())
+    }
+    /// Sets the element at a reserved position with displacement `pos` to `val`, see `reserve`
+    ///
+    /// # Safety
+    /// The cell at displacement `pos` has to be one of the cells reserved in this array.
+    pub unsafe fn set_reserved(&mut self, pos: i32, val: i64) -> Result<(), RuntimeError> {
+        let pos = self.reserved(pos);
+        *self.arr.get_unchecked_mut(pos) = self.cells.wrap(val)?;
+        Ok(())
+    }
+    /// Moves the pointer to a reserved position `i` spaces to the right, see `reserve`
+    ///
+    /// The pointer is left outside the array if the position was not reserved, so the
+    /// accesses after it panic.
+    pub fn move_reserved(&mut self, i: i32) {
+        self.pos = self.reserved(i);
+        debug_assert!(self.pos < self.arr.len(), "position {} was not reserved", self.pos);
+    }
+    /// Returns a pointer to the cells, their number and the index of the pointer in them,
+    /// for machine code reaching the cells directly
+    ///
+    /// The cells stay where they are until the array is changed through its other methods.
+    pub fn raw_parts(&mut self) -> (*mut i64, usize, usize) {
+        (self.arr.as_mut_ptr(), self.arr.len(), self.pos)
+    }
+    /// Moves the pointer to the cell `index` of the ones returned by `raw_parts`
+    pub fn set_index(&mut self, index: usize) {
+        assert!(index < self.arr.len(), "index {} outside the array", index);
+        self.pos = index;
     }
     /// Moves i to the right (if i is negative it moves to the left)
-    pub fn move_r(&mut self, i: i32) {
-        self.holds(i);
-        if i < 0 {
-            self.pos -= (-i) as usize;
-        } else {
-            self.pos += i as usize;
+    ///
+    /// # Errors
+    /// Returns `OutOfTape` if the array is bounded and the pointer would leave it,
+    /// leaving the pointer on the cell at that end, as moving one cell at a time does.
+    pub fn move_r(&mut self, i: i32) -> Result<(), RuntimeError> {
+        match self.index(i) {
+            Ok(pos) => {
+                self.pos = pos;
+                Ok(())
+            }
+            Err(e) => {
+                self.pos = if i < 0 { 0 } else { self.arr.len() - 1 };
+                Err(e)
+            }
         }
     }
+    /// Moves the pointer to the cell at displacement `off`, or as close to it as the array allows
+    ///
+    /// Used when an operation on that cell fails, so the pointer is left where the script stopped.
+    pub fn settle(&mut self, off: i32) {
+        let _ = self.move_r(off);
+    }
     /// Set the element at the current position to `i`
-    pub fn set(&mut self, i: i32) {
-        self.arr[self.pos] = i;
+    pub fn set(&mut self, i: i64) -> Result<(), RuntimeError> {
+        self.arr[self.pos] = self.cells.wrap(i)?;
+        Ok(())
     }
     /// Used for copying from the current array to `ah` `args` numbers
-    pub fn set_more(&mut self, ah: &mut ArrayHandler, args: usize) {
-        self.holds(args as i32);
-        ah.holds(args as i32);
+    pub fn set_more(&mut self, ah: &mut ArrayHandler, args: usize) -> Result<(), RuntimeError> {
+        self.holds(args as i32)?;
+        ah.holds(args as i32)?;
         for i in 0..(args + 1) {
             self.arr[self.pos + i] = ah.arr[ah.pos + i];
         }
+        Ok(())
     }
     // Makes sure there is enough size to the right for a move
     fn resize_right(&mut self) {
@@ -84,7 +199,20 @@ impl ArrayHandler {
         self.arr = aux;
     }
     // Makes sure there is enough space to move by `offset`
-    fn holds(&mut self, offset: i32) {
+    //
+    // A bounded array reports the first cell past the end it would cross, so moving several
+    // cells at once fails like moving them one at a time.
+    fn holds(&mut self, offset: i32) -> Result<(), RuntimeError> {
+        if let Some(size) = self.bound {
+            let target = self.pointer() + i64::from(offset);
+            if target < 0 {
+                return Err(RuntimeError::OutOfTape(-1));
+            }
+            if target >= size as i64 {
+                return Err(RuntimeError::OutOfTape(size as i64));
+            }
+            return Ok(());
+        }
         while offset + (self.pos as i32) >= (self.arr.capacity() as i32) {
             self.resize_right();
         }
@@ -93,79 +221,95 @@ impl ArrayHandler {
             self.resize_left();
             poss = self.pos as i32;
         }
+        Ok(())
     }
     /// Get the value at the current position
-    pub fn get(&self) -> i32 {
+    pub fn get(&self) -> i64 {
         self.arr[self.pos]
     }
-    /// Read from the standard input to the current position
-    pub fn read(&mut self) {
-        self.set(INPUT_READER.lock().unwrap().next());
+    /// Read the next character of the input to the position with displacement `pos`
+    pub fn read<R: Read, W: Write>(&mut self, pos: i32, io: &mut IoHandler<R, W>) -> Result<(), RuntimeError> {
+        let pos = self.index(pos)?;
+        if let Some(ch) = io.read()? {
+            self.arr[pos] = self.cells.wrap(i64::from(ch))?;
+        }
+        Ok(())
+    }
+    /// Write the char at the position with displacement `pos` to the output
+    pub fn write<R: Read, W: Write>(&mut self, pos: i32, io: &mut IoHandler<R, W>) -> Result<(), RuntimeError> {
+        let pos = self.index(pos)?;
+        io.write(self.arr[pos] as u8)
     }
-    /// Write the char at the current position to the standard output
-    pub fn write(&self) {
-        print!("{}", (self.arr[self.pos] as u8) as char);
+    /// Returns the position of the pointer, relative to where it started
+    pub fn pointer(&self) -> i64 {
+        (self.pos as i64) - (self.displacement as i64)
+    }
+    /// Returns the positions and values of all the non-zero elements, in order
+    pub fn values(&self) -> Vec<(i64, i64)> {
+        self.arr.iter().enumerate()
+            .filter(|&(_, &val)| val != 0)
+            .map(|(i, &val)| ((i as i64) - (self.displacement as i64), val))
+            .collect()
     }
     /// Print debug info
-    pub fn debug(&self, funs: usize) {
-        for i in 0..self.arr.len() {
-            if self.arr[i] != 0 {
-                let pos = (i as i64) - (self.displacement as i64);
-                println!("{}: {}", pos, self.arr[i]);
-            }
+    pub fn debug(&self) {
+        for (pos, val) in self.values() {
+            println!("{}: {}", pos, val);
         }
-        let pt = (self.pos as i64) - (self.displacement as i64);
-        println!("Pointer at {}", pt);
-        STACK_HOLDER.lock().unwrap().debug();
-        println!("Number of functions: {}", funs);
+        println!("Pointer at {}", self.pointer());
     }
     /// Executes `SkipMove(offset)`
-    pub fn skip_move(&mut self, offset: i32) {
+    pub fn skip_move(&mut self, offset: i32) -> Result<(), RuntimeError> {
         while self.arr[self.pos] != 0 {
-            self.holds(offset);
-            if offset < 0 {
-                self.pos -= (-offset) as usize;
-            } else {
-                self.pos += offset as usize;
-            }
+            self.move_r(offset)?;
         }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::ArrayHandler;
+    use dispatcher::operation::{CellType, RuntimeError};
     #[test]
     fn add_positive() {
         let mut ah = ArrayHandler::new(2, true);
-        ah.add(10);
+        ah.add(10).unwrap();
         assert_eq!(ah.arr, [0, 10]);
     }
     #[test]
     fn add_negative() {
         let mut ah = ArrayHandler::new(2, true);
-        ah.add(-10);
+        ah.add(-10).unwrap();
         assert_eq!(ah.arr, [0, -10]);
     }
     #[test]
+    fn add_wrapping() {
+        let mut ah = ArrayHandler::with_cells(2, true, CellType::U8);
+        ah.add(-10).unwrap();
+        assert_eq!(ah.arr, [0, 246]);
+        ah.add_at(-1, 300).unwrap();
+        assert_eq!(ah.arr, [44, 246]);
+    }
+    #[test]
     fn move_forward_no_resize() {
         let mut ah = ArrayHandler::new(10, true);
-        ah.move_r(2);
+        ah.move_r(2).unwrap();
         assert_eq!(ah.pos, 7);
         assert_eq!(ah.arr.capacity(), 10);
     }
     #[test]
     fn move_backward_no_resize() {
         let mut ah = ArrayHandler::new(10, true);
-        ah.move_r(-2);
+        ah.move_r(-2).unwrap();
         assert_eq!(ah.pos, 3);
         assert_eq!(ah.arr.capacity(), 10);
     }
     #[test]
     fn move_forward_resize() {
         let mut ah = ArrayHandler::new(2, true);
-        ah.add(10);
-        ah.move_r(3);
+        ah.add(10).unwrap();
+        ah.move_r(3).unwrap();
         assert_eq!(ah.pos, 4);
         assert_eq!(ah.arr.capacity(), 8);
         assert_eq!(ah.arr, [0, 10, 0, 0, 0, 0, 0, 0])
@@ -173,8 +317,8 @@ mod tests {
     #[test]
     fn move_backward_resize() {
         let mut ah = ArrayHandler::new(2, true);
-        ah.add(10);
-        ah.move_r(-4);
+        ah.add(10).unwrap();
+        ah.move_r(-4).unwrap();
         assert_eq!(ah.pos, 3);
         assert_eq!(ah.arr.capacity(), 8);
         assert_eq!(ah.arr, [0, 0, 0, 0, 0, 0, 0, 10])
@@ -182,11 +326,35 @@ mod tests {
     #[test]
     fn move_both_dir_resize() {
         let mut ah = ArrayHandler::new(2, true);
-        ah.add(10);
-        ah.move_r(1);
-        ah.move_r(-3);
+        ah.add(10).unwrap();
+        ah.move_r(1).unwrap();
+        ah.move_r(-3).unwrap();
         assert_eq!(ah.pos, 3);
         assert_eq!(ah.arr.capacity(), 8);
         assert_eq!(ah.arr, [0, 0, 0, 0, 0, 10, 0, 0])
     }
-}
\ No newline at end of file
+    #[test]
+    fn bounded_edges() {
+        let mut ah = ArrayHandler::bounded(3, CellType::U8);
+        assert_eq!(ah.move_r(-1), Err(RuntimeError::OutOfTape(-1)));
+        ah.move_r(2).unwrap();
+        assert_eq!(ah.add_at(1, 5), Err(RuntimeError::OutOfTape(3)));
+        assert_eq!(ah.move_r(1), Err(RuntimeError::OutOfTape(3)));
+        assert_eq!(ah.pointer(), 2);
+        assert_eq!(ah.arr.capacity(), 3);
+        ah.add(1).unwrap();
+        assert_eq!(ah.skip_move(1), Err(RuntimeError::OutOfTape(3)));
+    }
+    #[test]
+    fn reserving_cells() {
+        let mut ah = ArrayHandler::new(2, true);
+        assert!(ah.reserve(-3, 4));
+        ah.move_reserved(-3);
+        // SAFETY: 7 cells right of -3 is 4, the last cell reserved
+        unsafe { ah.add_reserved(7, 2).unwrap() };
+        assert_eq!(ah.values(), vec![(4, 2)]);
+        let mut ah = ArrayHandler::bounded(3, CellType::U8);
+        assert!(ah.reserve(0, 2));
+        assert!(!ah.reserve(0, 3) && !ah.reserve(-1, 0));
+    }
+}
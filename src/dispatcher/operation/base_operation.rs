@@ -1,26 +1,29 @@
 //! The operations that are executed
 
 use dispatcher::operation::*;
-use dispatcher::operation::stack_handler::STACK_HOLDER;
+use std::io::{Read, Write};
 
 /// All the types of operations
-#[derive(Clone, Debug)]
+///
+/// The operations on cells take the offset of the cell from the pointer as their first value,
+/// so the optimiser can leave the pointer in place instead of moving it between them.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Operation {
-    /// Add x to the current position
-    Add(i32),
+    /// Add y to the position at offset x
+    Add(i32, i64),
     /// Move the pointer by x spaces
     Move(i32),
-    /// Add the current value to multiple locations multiplied
-    /// by a specified value
-    MoveTo(Vec<(i32, i32)>),
+    /// Runs a loop which only adds to and sets other cells
+    /// in a single step, see `LinearLoop`
+    MoveTo(LinearLoop),
     /// Equivalent to Loop(Move x)
     SkipMove(i32),
-    /// Set the value at the current position to x
-    Set(i32),
-    /// Read next character
-    Read,
-    /// Write the character at the current position
-    Write,
+    /// Set the value at offset x to y
+    Set(i32, i64),
+    /// Read next character to the position at offset x
+    Read(i32),
+    /// Write the character at offset x
+    Write(i32),
     /// While operation
     While(Vec<Operation>),
     /// Insert the total number of functions at the current position
@@ -32,58 +35,209 @@ pub enum Operation {
     /// by the current position on a separate array
     /// with x arguments copied
     CallFSep(usize),
+    /// Calls function x on the current array,
+    /// the number being known when compiling
+    Call(usize),
+    /// Calls function x on a separate array
+    /// with y arguments copied, the number being known when compiling
+    CallSep(usize, usize),
+    /// Runs the operations of an inlined function on a separate array
+    /// with x arguments copied
+    Separate(usize, Vec<Operation>),
     /// Prints debug information
     Debug,
     /// Inserts the number at the top of the stack
-    /// at the current position
-    PeekStack,
-    /// Pops x elements from the stack, inserting the last one
-    /// at the current position
-    PopStack(usize),
-    /// Push the number at the current position to the stack
-    PushStack,
-    /// Inserts the length of the stack at the current position
-    StackLen,
+    /// at offset x
+    PeekStack(i32),
+    /// Pops y elements from the stack, inserting the last one
+    /// at offset x
+    PopStack(i32, usize),
+    /// Push the number at offset x to the stack
+    PushStack(i32),
+    /// Inserts the length of the stack at offset x
+    StackLen(i32),
+    /// Restores what the start of a script evaluated when compiling left,
+    /// see `Snapshot`
+    Restore(Snapshot),
     /// An operation that does nothing and is reduced during optimisation
     EmptyOp,
 }
 
+/// A loop which adds `step` to the current cell each time, and otherwise only
+/// adds constants to other cells or sets them
+///
+/// Once the number of iterations `n` is known, the loop sets the current cell to 0,
+/// the cells in `sets` to their values and adds `n` times each multiplier in `adds`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LinearLoop {
+    /// What is added to the current cell each iteration
+    pub step: i64,
+    /// The offsets and final values of the cells set by the loop
+    pub sets: Vec<(i32, i64)>,
+    /// The offsets of the cells added to and what is added each iteration
+    pub adds: Vec<(i32, i64)>,
+}
+
+/// The array, stack and output left by operations evaluated when compiling,
+/// which ran on an empty array and stack
+///
+/// Restoring it sets the cells, pushes the numbers to the stack, writes the output
+/// and then moves the pointer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    /// The offsets of the cells which are not 0 and their values
+    pub cells: Vec<(i32, i64)>,
+    /// The numbers on the stack, the top last
+    pub stack: Vec<i64>,
+    /// What the operations wrote
+    pub output: Vec<u8>,
+    /// How far the pointer moved
+    pub pointer: i32,
+}
+
+/// Returns the number of operations in `ops`, including the ones inside loops
+pub fn size(ops: &[Operation]) -> usize {
+    ops.iter().map(Operation::size).sum()
+}
+
 impl Operation {
+    /// Returns the number of operations this one consists of: 1 plus the ones inside it
+    pub fn size(&self) -> usize {
+        match *self {
+            While(ref ops) | Separate(_, ref ops) => 1 + size(ops),
+            _ => 1,
+        }
+    }
     /// Maps each Operation to its specified behaviour
-    pub fn execute(& self, ah: &mut ArrayHandler, fun_holder: &FunctionHolder) {
-        match self {
-            &Add(i) => ah.add(i),
-            &Move(i) => ah.move_r(i),
-            &Set(i) => ah.set(i),
-            &While(ref ops) => {
-                while ah.get() != 0 {
-                    for op in ops.iter() {
-                        op.execute(ah, fun_holder);
-                    }
-                }
+    ///
+    /// Every operation uses up one unit of the fuel of `ctx`, and loops one more for each
+    /// iteration. An operation left half done when the fuel ran out is continued instead.
+    ///
+    /// # Errors
+    /// Stops at the first `RuntimeError`, leaving the array as it was at that point.
+    /// An operation on a cell away from the pointer moves the pointer to that cell when it
+    /// fails, where the script stopped before the moves were folded into the offsets.
+    pub fn execute<R: Read, W: Write>(& self, ctx: &mut Context<R, W>) -> Result<(), RuntimeError> {
+        if !ctx.is_suspended() {
+            ctx.burn()?;
+        }
+        let res = self.apply(ctx);
+        if res.is_err() {
+            if let Some(off) = self.offset() {
+                ctx.ah.settle(off);
             }
-            &InsFuns => ah.set(fun_holder.no_functions() as i32 ),
-            &CallFun => fun_holder.execute(ah),
-            &CallFSep(args) => fun_holder.execute_separate(ah, args),
-            &Read => ah.read(),
-            &Write => ah.write(),
-            &Debug => ah.debug(fun_holder.no_functions()),
-            &MoveTo(ref places) => {
-                let val = ah.get();
-                if val == 0 {
-                    return;
-                }
-                ah.set(0);
-                for &(place, mult) in places.iter() {
-                    ah.add_at(place, val*mult);
-                }
+        }
+        res
+    }
+    /// Returns the offset of the cell the operation works on, for the operations on a single cell
+    pub fn offset(&self) -> Option<i32> {
+        match *self {
+            Add(off, _) | Set(off, _) | Read(off) | Write(off) | PeekStack(off) | PopStack(off, _) |
+            PushStack(off) | StackLen(off) => Some(off),
+            _ => None,
+        }
+    }
+    // Runs the operation, without using up any fuel for it
+    fn apply<R: Read, W: Write>(&self, ctx: &mut Context<R, W>) -> Result<(), RuntimeError> {
+        match *self {
+            Add(off, i) => ctx.ah.add_at(off, i)?,
+            Move(i) => ctx.ah.move_r(i)?,
+            Set(off, i) => ctx.ah.set_at(off, i)?,
+            While(ref ops) => ctx.run_loop(ops)?,
+            InsFuns => ctx.ah.set(ctx.fh.no_functions() as i64)?,
+            CallFun => ctx.call()?,
+            CallFSep(args) => ctx.call_separate(args)?,
+            Call(n) => ctx.call_fun(n as i64)?,
+            CallSep(n, args) => ctx.call_fun_separate(n as i64, args)?,
+            Separate(args, ref ops) => ctx.run_separate(args, ops)?,
+            Read(off) => ctx.ah.read(off, &mut ctx.io)?,
+            Write(off) => ctx.ah.write(off, &mut ctx.io)?,
+            Debug => ctx.debug(),
+            MoveTo(ref lin) => lin.execute(ctx)?,
+            EmptyOp => {}
+            SkipMove(i) => ctx.ah.skip_move(i)?,
+            PeekStack(off) => ctx.peek_at(off)?,
+            PopStack(off, i) => ctx.pop_at(off, i)?,
+            PushStack(off) => ctx.stack.push(ctx.ah.get_at(off)?),
+            StackLen(off) => ctx.ah.set_at(off, ctx.stack.len() as i64)?,
+            Restore(ref snap) => snap.restore(ctx)?,
+        }
+        Ok(())
+    }
+}
+
+impl LinearLoop {
+    /// Runs the loop on the current cell of `ctx`, all its iterations at once
+    ///
+    /// Each iteration uses up a unit of fuel, the first one the unit of the operation, so a
+    /// loop with less fuel left than it has iterations runs as many as it can and stops
+    /// there, like the unoptimised loop.
+    ///
+    /// # Errors
+    /// Fails like the loop would, returning `Overflow` for `Trap` cells after the iterations
+    /// which keep the current cell in range, and runs out of fuel if it never ends.
+    pub fn execute<R: Read, W: Write>(&self, ctx: &mut Context<R, W>) -> Result<(), RuntimeError> {
+        let val = ctx.ah.get();
+        if val == 0 {
+            return Ok(());
+        }
+        let cells = ctx.ah.cells();
+        // The number of iterations, none if the loop never ends, and the error after them
+        let (n, error) = match cells.iterations(val, self.step) {
+            Ok(n) => (n.map(|n| n as u64), None),
+            Err(RuntimeError::Overflow) => (Some(steps_in_range(val, self.step)), Some(RuntimeError::Overflow)),
+            Err(e) => return Err(e),
+        };
+        // The first iteration is paid for by the fuel of the operation itself
+        let (k, error) = match (n, ctx.fuel()) {
+            (Some(n), Some(fuel)) if n <= fuel.saturating_add(1) => (n, error),
+            (Some(n), None) => (n, error),
+            (_, Some(fuel)) => (fuel.saturating_add(1), Some(RuntimeError::OutOfFuel)),
+            // The loop never ends and there is no fuel to run out of
+            (None, None) => loop {
+                ctx.burn()?;
+            },
+        };
+        if let Some(fuel) = ctx.fuel() {
+            ctx.set_fuel(Some(fuel - k.saturating_sub(1)));
+        }
+        if k > 0 {
+            let k = k as i64;
+            // In range for `Trap` cells, which never run past the iterations keeping it there
+            ctx.ah.set(cells.wrap(val.wrapping_add(k.wrapping_mul(self.step)))?)?;
+            for &(place, val) in &self.sets {
+                ctx.ah.set_at(place, val)?;
             }
-            &EmptyOp => {}
-            &SkipMove(i) => ah.skip_move(i),
-            &PeekStack => ah.add(STACK_HOLDER.lock().unwrap().peek()),
-            &PopStack(i) => ah.add(STACK_HOLDER.lock().unwrap().pop(i)),
-            &PushStack => STACK_HOLDER.lock().unwrap().push(ah.get()),
-            &StackLen => ah.set(STACK_HOLDER.lock().unwrap().len() as i32),
+            for &(place, mult) in &self.adds {
+                ctx.ah.add_at(place, cells.mul(k, mult)?)?;
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
         }
     }
-}
\ No newline at end of file
+}
+
+// Returns how many times `step` can be added to `val` with the result staying in the range
+// of `Trap` cells
+fn steps_in_range(val: i64, step: i64) -> u64 {
+    let (min, max) = (i64::from(i32::MIN), i64::from(i32::MAX));
+    if step > 0 { ((max - val) / step) as u64 } else { ((min - val) / step) as u64 }
+}
+
+impl Snapshot {
+    /// Restores the snapshot on the array, stack and output of `ctx`
+    pub fn restore<R: Read, W: Write>(&self, ctx: &mut Context<R, W>) -> Result<(), RuntimeError> {
+        for &(place, val) in &self.cells {
+            ctx.ah.set_at(place, val)?;
+        }
+        for &val in &self.stack {
+            ctx.stack.push(val);
+        }
+        for &byte in &self.output {
+            ctx.io.write(byte)?;
+        }
+        ctx.ah.move_r(self.pointer)
+    }
+}
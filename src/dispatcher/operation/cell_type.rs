@@ -0,0 +1,174 @@
+//! The kinds of numbers the cells can hold
+
+use dispatcher::operation::RuntimeError;
+use std::fmt;
+use std::str::FromStr;
+
+/// The type of the cells in the arrays and the stack, deciding what happens on overflow.
+///
+/// All values are stored as `i64`, kept in the range of the chosen type.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CellType {
+    /// 8 bit cells going from 0 to 255, wrapping around on overflow
+    U8,
+    /// 16 bit cells going from 0 to 65535, wrapping around on overflow
+    U16,
+    /// 32 bit cells going from 0 to 2^32 - 1, wrapping around on overflow
+    U32,
+    /// Signed 64 bit cells, wrapping around on overflow
+    I64,
+    /// Signed 32 bit cells, stopping the script with an error on overflow
+    #[default]
+    Trap,
+}
+
+impl CellType {
+    /// Brings `val` in the range of the type
+    ///
+    /// # Errors
+    /// Returns `Overflow` for `Trap` cells if `val` does not fit in 32 bits.
+    pub fn wrap(self, val: i64) -> Result<i64, RuntimeError> {
+        match self {
+            CellType::U8 => Ok(val & 0xff),
+            CellType::U16 => Ok(val & 0xffff),
+            CellType::U32 => Ok(val & 0xffff_ffff),
+            CellType::I64 => Ok(val),
+            CellType::Trap => {
+                if val < i64::from(i32::MIN) || val > i64::from(i32::MAX) {
+                    Err(RuntimeError::Overflow)
+                } else {
+                    Ok(val)
+                }
+            }
+        }
+    }
+    /// Adds two values, bringing the result in the range of the type
+    pub fn add(self, a: i64, b: i64) -> Result<i64, RuntimeError> {
+        match self {
+            CellType::Trap => self.wrap(a.checked_add(b).ok_or(RuntimeError::Overflow)?),
+            _ => self.wrap(a.wrapping_add(b)),
+        }
+    }
+    /// Multiplies two values, bringing the result in the range of the type
+    pub fn mul(self, a: i64, b: i64) -> Result<i64, RuntimeError> {
+        match self {
+            CellType::Trap => self.wrap(a.checked_mul(b).ok_or(RuntimeError::Overflow)?),
+            _ => self.wrap(a.wrapping_mul(b)),
+        }
+    }
+    /// Checks if the values wrap around on overflow
+    pub fn wraps(self) -> bool {
+        self != CellType::Trap
+    }
+    /// Returns how many times `step` has to be added to `val` to reach 0,
+    /// or `None` if it never does
+    ///
+    /// For wrapping cells the count is only meaningful modulo the number of values,
+    /// so it can come out negative for `I64`; multiplying it with `mul` still gives
+    /// the right result.
+    ///
+    /// # Errors
+    /// Returns `Overflow` for `Trap` cells if `val` goes out of range before reaching 0.
+    pub fn iterations(self, val: i64, step: i64) -> Result<Option<i64>, RuntimeError> {
+        let bits = match self {
+            CellType::U8 => 8,
+            CellType::U16 => 16,
+            CellType::U32 => 32,
+            CellType::I64 => 64,
+            CellType::Trap => {
+                if step == 0 {
+                    return if val == 0 { Ok(Some(0)) } else { Ok(None) };
+                }
+                return if val % step == 0 && -val / step >= 0 {
+                    Ok(Some(-val / step))
+                } else {
+                    Err(RuntimeError::Overflow)
+                };
+            }
+        };
+        let mask = if bits == 64 { !0u64 } else { (1u64 << bits) - 1 };
+        let (val, step) = ((val as u64).wrapping_neg() & mask, step as u64 & mask);
+        if val == 0 {
+            return Ok(Some(0));
+        }
+        if step == 0 {
+            return Ok(None);
+        }
+        // Solve step * n = -val modulo 2^bits, dividing both by the powers of 2 of step
+        let twos = step.trailing_zeros();
+        if val.trailing_zeros() < twos {
+            return Ok(None);
+        }
+        let (val, step) = (val >> twos, step >> twos);
+        // The inverse of the odd step modulo 2^64, each round doubling the correct bits
+        let mut inverse = step;
+        for _ in 0..5 {
+            inverse = inverse.wrapping_mul(2u64.wrapping_sub(step.wrapping_mul(inverse)));
+        }
+        Ok(Some((val.wrapping_mul(inverse) & (mask >> twos)) as i64))
+    }
+}
+
+impl FromStr for CellType {
+    type Err = String;
+    /// Parses `u8`, `u16`, `u32`, `i64` or `trap`
+    fn from_str(s: &str) -> Result<CellType, String> {
+        match s {
+            "u8" => Ok(CellType::U8),
+            "u16" => Ok(CellType::U16),
+            "u32" => Ok(CellType::U32),
+            "i64" => Ok(CellType::I64),
+            "trap" => Ok(CellType::Trap),
+            _ => Err(format!("Unknown cell type '{}', expected u8, u16, u32, i64 or trap", s)),
+        }
+    }
+}
+
+impl fmt::Display for CellType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            CellType::U8 => "u8",
+            CellType::U16 => "u16",
+            CellType::U32 => "u32",
+            CellType::I64 => "i64",
+            CellType::Trap => "trap",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CellType;
+    use dispatcher::operation::RuntimeError;
+    #[test]
+    fn wrapping() {
+        assert_eq!(CellType::U8.add(255, 1), Ok(0));
+        assert_eq!(CellType::U8.add(0, -1), Ok(255));
+        assert_eq!(CellType::U16.mul(300, 300), Ok(90000 - 65536));
+        assert_eq!(CellType::U32.add(0, -1), Ok(0xffff_ffff));
+        assert_eq!(CellType::I64.add(i64::MAX, 1), Ok(i64::MIN));
+    }
+    #[test]
+    fn trapping() {
+        assert_eq!(CellType::Trap.add(2147483647, 1), Err(RuntimeError::Overflow));
+        assert_eq!(CellType::Trap.add(-2147483648, -1), Err(RuntimeError::Overflow));
+        assert_eq!(CellType::Trap.mul(65536, 32768), Err(RuntimeError::Overflow));
+        assert_eq!(CellType::Trap.add(-5, 3), Ok(-2));
+    }
+    #[test]
+    fn iterations() {
+        assert_eq!(CellType::Trap.iterations(6, -2), Ok(Some(3)));
+        assert_eq!(CellType::Trap.iterations(-6, 3), Ok(Some(2)));
+        assert_eq!(CellType::Trap.iterations(5, -2), Err(RuntimeError::Overflow));
+        assert_eq!(CellType::Trap.iterations(6, 2), Err(RuntimeError::Overflow));
+        assert_eq!(CellType::U8.iterations(5, -1), Ok(Some(5)));
+        assert_eq!(CellType::U8.iterations(5, 1), Ok(Some(251)));
+        assert_eq!(CellType::U8.iterations(1, -3), Ok(Some(171)));
+        assert_eq!(CellType::U8.iterations(6, 2), Ok(Some(125)));
+        assert_eq!(CellType::U8.iterations(5, 2), Ok(None));
+        assert_eq!(CellType::U16.iterations(4, -4), Ok(Some(1)));
+        assert_eq!(CellType::I64.iterations(-7, 1), Ok(Some(7)));
+        assert_eq!(CellType::I64.iterations(3, 3), Ok(Some(-1)));
+    }
+}
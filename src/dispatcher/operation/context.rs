@@ -0,0 +1,310 @@
+//! The state scripts are executed in
+
+use dispatcher::operation::*;
+use dispatcher::operation::stack_handler::StackHandler;
+use std::io::{Read, Write};
+use std::mem;
+use std::sync::Arc;
+
+// Where a run stopped when it ran out of fuel, one entry for each level it was in
+enum Frame {
+    // The index of the operation to continue from in a sequence of operations
+    At(usize),
+    // A function being executed
+    Call(Arc<Function>),
+    // The array of a function called on a separate array
+    Separate(ArrayHandler),
+}
+
+/// Everything the operations work on: the array, the stack, the functions and the streams.
+///
+/// Each Compiler owns a single Context, so separate Compilers never share any state.
+pub struct Context<R, W> {
+    /// The array the pointer is currently on
+    pub ah: ArrayHandler,
+    /// The stack shared by all the arrays
+    pub stack: StackHandler,
+    /// The functions defined so far
+    pub fh: FunctionHolder,
+    /// The input and output of the scripts
+    pub io: IoHandler<R, W>,
+    // The operations left to execute, or None for no limit
+    fuel: Option<u64>,
+    // The levels of the suspended run, the outermost last
+    suspended: Vec<Frame>,
+}
+
+impl<R: Read, W: Write> Context<R, W> {
+    /// Creates a new Context with an empty stack, no functions and the default array
+    pub fn new(input: R, output: W) -> Context<R, W> {
+        Context {
+            ah: ArrayHandler::new(1024, true),
+            stack: StackHandler::new(),
+            fh: FunctionHolder::new(),
+            io: IoHandler::new(input, output),
+            fuel: None,
+            suspended: Vec::new(),
+        }
+    }
+    /// Limits the number of operations that can still be executed, `None` meaning no limit
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+    /// Returns the fuel left, `None` meaning there is no limit
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+    /// Uses up one unit of fuel
+    ///
+    /// # Errors
+    /// Returns `OutOfFuel` if there is none left.
+    pub fn burn(&mut self) -> Result<(), RuntimeError> {
+        match self.fuel {
+            Some(0) => Err(RuntimeError::OutOfFuel),
+            Some(ref mut fuel) => {
+                *fuel -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+    /// Checks if the last run ran out of fuel and is waiting to be continued
+    pub fn is_suspended(&self) -> bool {
+        !self.suspended.is_empty()
+    }
+    /// Returns the type of the cells
+    pub fn cells(&self) -> CellType {
+        self.ah.cells()
+    }
+    /// Changes the type of the cells of the array and the stack, wrapping their values
+    /// into the new range
+    pub fn set_cells(&mut self, cells: CellType) {
+        self.ah.set_cells(cells);
+        self.stack.set_cells(cells);
+    }
+    /// Replaces the array with an empty one of exactly `size` cells, or with an empty
+    /// growing array if `size` is `None`
+    ///
+    /// Functions called on a separate array get an array of the same kind.
+    pub fn set_tape(&mut self, size: Option<usize>) {
+        let cells = self.ah.cells();
+        self.ah = match size {
+            Some(size) => ArrayHandler::bounded(size, cells),
+            None => ArrayHandler::with_cells(1024, true, cells),
+        };
+    }
+    /// Executes `ops` in order
+    ///
+    /// # Examples
+    /// ```
+    /// use Brainfuck::dispatcher::{Context, Operation};
+    ///
+    /// let mut ctx = Context::new("".as_bytes(), Vec::new());
+    /// ctx.run(&[Operation::Set(0, 72), Operation::Write(0), Operation::PushStack(0)]).unwrap();
+    /// assert_eq!(ctx.io.output(), b"H");
+    /// assert_eq!(ctx.stack.as_slice(), &[72]);
+    /// ```
+    ///
+    /// If the last run was suspended inside `ops`, continues it from where it stopped instead.
+    /// A suspended run which `ops` cannot continue is dropped, and `ops` run from the start.
+    ///
+    /// # Errors
+    /// Stops at the first operation that fails, returning its error.
+    /// On `OutOfFuel` the run is suspended and can be continued after adding more fuel.
+    pub fn run(&mut self, ops: &[Operation]) -> Result<(), RuntimeError> {
+        if !self.resumes(ops) {
+            self.suspended.clear();
+        }
+        self.resume(ops)
+    }
+    // Checks if the levels of the suspended run lead through `ops`, so running them continues it
+    fn resumes(&self, ops: &[Operation]) -> bool {
+        let mut frames = self.suspended.iter().rev().peekable();
+        let mut ops = ops;
+        while let Some(frame) = frames.next() {
+            let i = match *frame {
+                Frame::At(i) => i,
+                _ => return false,
+            };
+            if frames.peek().is_none() {
+                return i <= ops.len();
+            }
+            // The operation the run stopped inside, and the frames it left before the next level
+            ops = match ops.get(i) {
+                Some(While(body)) => body,
+                Some(Call(_)) | Some(CallFun) => match frames.next() {
+                    Some(Frame::Call(fun)) => fun.ops(),
+                    _ => return false,
+                },
+                Some(CallSep(..)) | Some(CallFSep(_)) => match (frames.next(), frames.next()) {
+                    (Some(Frame::Call(fun)), Some(Frame::Separate(_))) => fun.separate_ops(),
+                    _ => return false,
+                },
+                Some(Separate(_, body)) => match frames.next() {
+                    Some(Frame::Separate(_)) => body,
+                    _ => return false,
+                },
+                _ => return false,
+            };
+        }
+        true
+    }
+    // Runs `ops`, continuing the suspended run from the level it stopped in inside them
+    fn resume(&mut self, ops: &[Operation]) -> Result<(), RuntimeError> {
+        let start = match self.suspended.pop() {
+            Some(Frame::At(i)) => i,
+            // Not suspended, or suspended somewhere else: drop what is left and start afresh
+            _ => {
+                self.suspended.clear();
+                0
+            }
+        };
+        for (i, op) in ops.iter().enumerate().skip(start) {
+            if let Err(e) = op.execute(self) {
+                if e == RuntimeError::OutOfFuel {
+                    self.suspended.push(Frame::At(i));
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+    /// Executes `ops` while the value at the pointer is not 0
+    pub fn run_loop(&mut self, ops: &[Operation]) -> Result<(), RuntimeError> {
+        if self.is_suspended() {
+            self.resume(ops)?;
+        }
+        while self.ah.get() != 0 {
+            if let Err(e) = self.burn() {
+                // Continue with the check of the loop
+                self.suspended.push(Frame::At(ops.len()));
+                return Err(e);
+            }
+            self.resume(ops)?;
+        }
+        Ok(())
+    }
+    /// Executes the function with the number at the pointer on the current array
+    pub fn call(&mut self) -> Result<(), RuntimeError> {
+        let n = self.ah.get();
+        self.call_fun(n)
+    }
+    /// Executes the function with the number `n` on the current array
+    pub fn call_fun(&mut self, n: i64) -> Result<(), RuntimeError> {
+        let fun = match self.suspended.pop() {
+            Some(Frame::Call(fun)) => fun,
+            _ => {
+                self.suspended.clear();
+                self.fh.get(n)?
+            }
+        };
+        let res = self.resume(fun.ops());
+        if res == Err(RuntimeError::OutOfFuel) {
+            self.suspended.push(Frame::Call(fun));
+        }
+        res
+    }
+    /// Executes the function with the number at the pointer on a new array,
+    /// copying the number and the `args` numbers after it
+    ///
+    /// The current array is restored afterwards, even if the function fails.
+    pub fn call_separate(&mut self, args: usize) -> Result<(), RuntimeError> {
+        let n = self.ah.get();
+        self.call_fun_separate(n, args)
+    }
+    /// Executes the function with the number `n` on a new array, like `call_separate`
+    pub fn call_fun_separate(&mut self, n: i64, args: usize) -> Result<(), RuntimeError> {
+        let fun = match self.suspended.pop() {
+            Some(Frame::Call(fun)) => fun,
+            _ => {
+                self.suspended.clear();
+                self.fh.get(n)?
+            }
+        };
+        let res = self.run_separate(args, fun.separate_ops());
+        if res == Err(RuntimeError::OutOfFuel) {
+            self.suspended.push(Frame::Call(fun));
+        }
+        res
+    }
+    /// Executes `ops` on a new array, copying the current number and the `args` numbers
+    /// after it, as if they were a function called on a separate array
+    pub fn run_separate(&mut self, args: usize, ops: &[Operation]) -> Result<(), RuntimeError> {
+        let ah = match self.suspended.pop() {
+            Some(Frame::Separate(ah)) => ah,
+            _ => {
+                self.suspended.clear();
+                self.separate_array(args)?
+            }
+        };
+        let caller = mem::replace(&mut self.ah, ah);
+        let res = self.resume(ops);
+        let ah = mem::replace(&mut self.ah, caller);
+        if res == Err(RuntimeError::OutOfFuel) {
+            // Keep the separate array for when the run continues
+            self.suspended.push(Frame::Separate(ah));
+        }
+        res
+    }
+    /// Creates the array of a function called on a separate array, of the same kind as the
+    /// current one, with the current number and the `args` numbers after it copied
+    pub fn separate_array(&mut self, args: usize) -> Result<ArrayHandler, RuntimeError> {
+        let mut ah = match self.ah.bound() {
+            Some(size) => ArrayHandler::bounded(size, self.ah.cells()),
+            None => ArrayHandler::with_cells((args + 1) * 2, false, self.ah.cells()),
+        };
+        ah.set_more(&mut self.ah, args)?;
+        Ok(ah)
+    }
+    /// Adds the number at the top of the stack to the cell at displacement `off`
+    pub fn peek_at(&mut self, off: i32) -> Result<(), RuntimeError> {
+        // The cell is reached first, as the pointer moves before the stack is looked at
+        self.ah.get_at(off)?;
+        self.ah.add_at(off, self.stack.peek()?)
+    }
+    /// Pops `i` numbers from the stack, adding their sum to the cell at displacement `off`
+    ///
+    /// # Errors
+    /// Returns `StackUnderflow` if there are less than `i` numbers, after adding the ones
+    /// there are, like popping them one at a time.
+    pub fn pop_at(&mut self, off: i32, i: usize) -> Result<(), RuntimeError> {
+        self.ah.get_at(off)?;
+        let n = i.min(self.stack.len());
+        self.ah.add_at(off, self.stack.pop(n)?)?;
+        if n < i {
+            return Err(RuntimeError::StackUnderflow);
+        }
+        Ok(())
+    }
+    /// Print debug info
+    pub fn debug(&self) {
+        self.ah.debug();
+        self.stack.debug();
+        println!("Number of functions: {}", self.fh.no_functions());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Context;
+    use dispatcher::operation::RuntimeError;
+    use dispatcher::Operation::*;
+    #[test]
+    fn other_operations_drop_the_suspended_run() {
+        let mut ctx = Context::new("".as_bytes(), Vec::new());
+        ctx.set_fuel(Some(2));
+        assert_eq!(ctx.run(&[Add(0, 1), Add(0, 1), Add(0, 1), Write(0)]), Err(RuntimeError::OutOfFuel));
+        ctx.set_fuel(None);
+        ctx.run(&[Add(0, 5)]).unwrap();
+        assert!(!ctx.is_suspended());
+        assert_eq!(ctx.ah.get(), 7);
+        // Stopped inside a loop, where the other operations run on a separate array
+        ctx.set_fuel(Some(4));
+        assert_eq!(ctx.run(&[Set(0, 3), While(vec![Add(0, -1), Add(1, 1)])]), Err(RuntimeError::OutOfFuel));
+        ctx.set_fuel(None);
+        ctx.run(&[Write(0), Separate(0, vec![Add(0, 1), Write(0)])]).unwrap();
+        assert!(!ctx.is_suspended());
+        assert_eq!(ctx.io.output(), &[2, 3]);
+        assert_eq!(ctx.ah.values(), vec![(0, 2)]);
+    }
+}
@@ -1,33 +1,57 @@
 //! Contains the functions and a structure to hold them
 
 use dispatcher::operation::base_operation::Operation;
-use dispatcher::operation::array_handler::ArrayHandler;
+use dispatcher::operation::{Context, RuntimeError};
+use std::io::{Read, Write};
 use std::collections::VecDeque;
+use std::sync::Arc;
 
-struct Function {
-    ops: Vec<Operation>
+/// The operations of a single function
+///
+/// A function called on a separate array runs its own version of the operations,
+/// which can skip whatever only changes that array at the end.
+#[derive(Clone)]
+pub struct Function {
+    ops: Vec<Operation>,
+    separate: Vec<Operation>,
 }
 
 impl Function {
-    ///Execute the operations of a function
-    pub fn execute(&self, ah: &mut ArrayHandler, fh: &FunctionHolder) {
-        for op in (&self.ops).iter() {
-            op.execute(ah, fh);
+    /// Creates a function running `ops` on any array
+    pub fn new(ops: Vec<Operation>) -> Function {
+        Function {
+            separate: ops.clone(),
+            ops,
         }
     }
-    ///Executes the operations of a function on a separate array
-    pub fn execute_separate(&self, ah: &mut ArrayHandler, args: usize, fh: &FunctionHolder) {
-        let mut ah2 = ArrayHandler::new((args + 1) * 2, false);
-        ah2.set_more(ah, args);
-        for op in (&self.ops).iter() {
-            op.execute(&mut ah2, fh);
-        }
+    /// Creates a function running `ops` on the current array and `separate` on a separate one
+    pub fn with_separate(ops: Vec<Operation>, separate: Vec<Operation>) -> Function {
+        Function { ops, separate }
+    }
+    /// Returns the operations of the function
+    pub fn ops(&self) -> &[Operation] {
+        &self.ops
+    }
+    /// Returns the operations run when the function is called on a separate array
+    pub fn separate_ops(&self) -> &[Operation] {
+        &self.separate
+    }
+    /// Returns the operations of the function, dropping the function
+    pub fn into_ops(self) -> Vec<Operation> {
+        self.ops
+    }
+    ///Execute the operations of a function on the current array of `ctx`
+    pub fn execute<R: Read, W: Write>(&self, ctx: &mut Context<R, W>) -> Result<(), RuntimeError> {
+        ctx.run(&self.ops)
     }
 }
 
 /// The structure which holds the functions
+///
+/// The stable functions are shared, so a function can keep running while the holder is changed.
+#[derive(Clone)]
 pub struct FunctionHolder {
-    funs: Vec<Function>,
+    funs: Vec<Arc<Function>>,
     temp: VecDeque<Function>
 }
 
@@ -43,27 +67,51 @@ impl FunctionHolder {
     ///
     /// The functions are kept until they are pushed with the rest of them or discarded
     pub fn add_temp(&mut self, ops: Vec<Operation>) {
-        self.temp.push_back(Function{ops});
+        self.temp.push_back(Function::new(ops));
+    }
+    /// Replaces each temp function with `f(function, holder)`, in order
+    ///
+    /// While a function is replaced, the holder has the stable functions and the
+    /// temp ones before it.
+    pub fn map_temp<F: FnMut(Function, &FunctionHolder) -> Function>(&mut self, mut f: F) {
+        let temp = ::std::mem::take(&mut self.temp);
+        for fun in temp {
+            let fun = f(fun, self);
+            self.temp.push_back(fun);
+        }
     }
     /// Pushes the temp functions to the rest of them
     ///
     /// Usually called after a successful compile.
     pub fn push_funs(&mut self) {
-        while !self.temp.is_empty() {
-            self.funs.push(self.temp.pop_front().unwrap());
+        while let Some(fun) = self.temp.pop_front() {
+            self.funs.push(Arc::new(fun));
         }
     }
-    /// Execute the function with the given number
-    pub fn execute(&self, ah: &mut ArrayHandler) {
-        let n = ah.get() as usize;
-        assert!(n < self.funs.len());
-        self.funs[n].execute(ah, self);
+    /// Adds a stable function after the others, skipping the temp functions
+    pub fn push(&mut self, fun: Function) {
+        self.funs.push(Arc::new(fun));
     }
-    /// Execute the function with the given number on a separate array
-    pub fn execute_separate(&self, ah: &mut ArrayHandler, args: usize) {
-        let n = ah.get() as usize;
-        assert!(n < self.funs.len());
-        self.funs[n].execute_separate(ah, args, self);
+    /// Returns the stable function with the number `n`
+    ///
+    /// # Errors
+    /// Returns an error if `n` is not the number of a stable function.
+    pub fn get(&self, n: i64) -> Result<Arc<Function>, RuntimeError> {
+        if n < 0 {
+            return Err(RuntimeError::NegativeFunction(n));
+        }
+        match self.funs.get(n as usize) {
+            Some(fun) => Ok(fun.clone()),
+            None => Err(RuntimeError::UnknownFunction(n as usize)),
+        }
+    }
+    /// Returns the function with the number `n`, stable or temp, if there is one
+    pub fn compiled(&self, n: usize) -> Option<&Function> {
+        if n < self.funs.len() {
+            Some(&self.funs[n])
+        } else {
+            self.temp.get(n - self.funs.len())
+        }
     }
     /// Returns the number of stable function (without the temps)
     pub fn no_functions(&self) -> usize {
@@ -71,10 +119,16 @@ impl FunctionHolder {
     }
     /// Returns the current number of functions (stable + temp)
     pub fn curr_funs(&self) -> i32 {
-        return (self.funs.len() + self.temp.len()) as i32
+        (self.funs.len() + self.temp.len()) as i32
     }
     /// Discard the temp functions
     pub fn discard_funs(&mut self) {
         self.temp.truncate(0);
     }
 }
+
+impl Default for FunctionHolder {
+    fn default() -> FunctionHolder {
+        FunctionHolder::new()
+    }
+}
@@ -0,0 +1,58 @@
+//! Holds the input reader for the script operations
+
+use std::io::Read;
+use dispatcher::operation::RuntimeError;
+
+/// Structure which holds the last line and consumes characters one by one
+pub struct InputReader<R> {
+    input: R,
+    ch: Vec<char>,
+    pos: usize,
+}
+
+impl<R: Read> InputReader<R> {
+    /// Constructs a new InputReader reading from `input`
+    pub fn new(input: R) -> InputReader<R> {
+        InputReader {
+            input,
+            ch: vec!(),
+            pos: 0,
+        }
+    }
+    // Read when there are no characters left
+    //
+    // Reads byte by byte, so nothing after the end of the line is taken from `input`.
+    fn read(&mut self) -> Result<(), RuntimeError> {
+        let mut line = Vec::new();
+        let mut byte = [0u8];
+        loop {
+            match self.input.read(&mut byte) {
+                Ok(0) => break,
+                Ok(_) => {
+                    line.push(byte[0]);
+                    if byte[0] == b'\n' {
+                        break;
+                    }
+                }
+                Err(e) => return Err(RuntimeError::Io(e.to_string())),
+            }
+        }
+        self.ch = String::from_utf8_lossy(&line).chars().collect();
+        self.pos = 0;
+        Ok(())
+    }
+    /// Returns the next character from the input as i32, or `None` at the end of the input
+    ///
+    /// # Errors
+    /// Returns `Io` if the input cannot be read.
+    pub fn next(&mut self) -> Result<Option<i32>, RuntimeError> {
+        if self.pos == self.ch.len() {
+            self.read()?;
+            if self.ch.is_empty() {
+                return Ok(None);
+            }
+        }
+        self.pos += 1;
+        Ok(Some(self.ch[self.pos - 1] as i32))
+    }
+}
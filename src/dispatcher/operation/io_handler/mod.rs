@@ -0,0 +1,80 @@
+//! The input and output streams used by the script operations
+
+mod input_reader;
+use self::input_reader::InputReader;
+use dispatcher::operation::RuntimeError;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+/// What reading does once the input is exhausted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EofMode {
+    /// Leave the current cell as it is
+    Unchanged,
+    /// Store 0 in the current cell
+    Zero,
+    /// Store -1 in the current cell
+    MinusOne,
+}
+
+impl FromStr for EofMode {
+    type Err = String;
+    /// Parses `unchanged`, `0` or `-1`
+    fn from_str(s: &str) -> Result<EofMode, String> {
+        match s {
+            "unchanged" => Ok(EofMode::Unchanged),
+            "0" => Ok(EofMode::Zero),
+            "-1" => Ok(EofMode::MinusOne),
+            _ => Err(format!("Unknown end of input behaviour '{}', expected unchanged, 0 or -1", s)),
+        }
+    }
+}
+
+/// Holds the input the scripts read from and the output they write to
+pub struct IoHandler<R, W> {
+    input: InputReader<R>,
+    output: W,
+    /// What `read` returns at the end of the input
+    pub eof: EofMode,
+}
+
+impl<R: Read, W: Write> IoHandler<R, W> {
+    /// Creates a new handler over the given streams
+    pub fn new(input: R, output: W) -> IoHandler<R, W> {
+        IoHandler {
+            input: InputReader::new(input),
+            output,
+            eof: EofMode::Unchanged,
+        }
+    }
+    /// Returns the next character from the input
+    ///
+    /// At the end of the input returns what `eof` says to store, `None` meaning
+    /// the cell should be left unchanged.
+    pub fn read(&mut self) -> Result<Option<i32>, RuntimeError> {
+        match self.input.next()? {
+            Some(ch) => Ok(Some(ch)),
+            None => match self.eof {
+                EofMode::Unchanged => Ok(None),
+                EofMode::Zero => Ok(Some(0)),
+                EofMode::MinusOne => Ok(Some(-1)),
+            },
+        }
+    }
+    /// Writes a single byte to the output
+    pub fn write(&mut self, byte: u8) -> Result<(), RuntimeError> {
+        self.output.write_all(&[byte]).map_err(|e| RuntimeError::Io(e.to_string()))
+    }
+    /// Flushes everything written so far
+    pub fn flush(&mut self) -> Result<(), RuntimeError> {
+        self.output.flush().map_err(|e| RuntimeError::Io(e.to_string()))
+    }
+    /// Returns the output stream
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+    /// Returns the output stream for changing it
+    pub fn output_mut(&mut self) -> &mut W {
+        &mut self.output
+    }
+}
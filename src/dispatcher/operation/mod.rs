@@ -6,8 +6,17 @@ mod base_operation;
 mod function;
 mod array_handler;
 mod stack_handler;
+mod io_handler;
+mod context;
+mod runtime_error;
+mod cell_type;
 
-pub use self::base_operation::Operation;
+pub use self::base_operation::{Operation, LinearLoop, Snapshot, size};
 pub use self::base_operation::Operation::*;
 pub use self::array_handler::ArrayHandler;
-pub use self::function::FunctionHolder;
\ No newline at end of file
+pub use self::function::{Function, FunctionHolder};
+pub use self::stack_handler::StackHandler;
+pub use self::io_handler::{IoHandler, EofMode};
+pub use self::context::Context;
+pub use self::runtime_error::RuntimeError;
+pub use self::cell_type::CellType;
@@ -0,0 +1,40 @@
+//! The errors which can stop a script while it is running
+
+use std::fmt;
+
+/// Everything that can go wrong while executing operations.
+///
+/// None of them affect the array, so the interpreter can keep going after reporting one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RuntimeError {
+    /// Peeking or popping more elements than there are in the stack
+    StackUnderflow,
+    /// Calling a function number which was not defined (yet)
+    UnknownFunction(usize),
+    /// Calling a function with a negative number
+    NegativeFunction(i64),
+    /// A value went out of the range of the cells (only for `CellType::Trap`)
+    Overflow,
+    /// Moving the pointer outside a bounded array, past the end at the given position (-1 or the size)
+    OutOfTape(i64),
+    /// The fuel given for the run was used up before the end
+    ///
+    /// The run is only suspended: running the same operations again continues it.
+    OutOfFuel,
+    /// Reading the input or writing the output failed
+    Io(String),
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RuntimeError::StackUnderflow => write!(f, "not enough elements in the stack"),
+            RuntimeError::UnknownFunction(n) => write!(f, "function {} is not defined", n),
+            RuntimeError::NegativeFunction(n) => write!(f, "cannot call function {}", n),
+            RuntimeError::Overflow => write!(f, "value out of the range of the cells"),
+            RuntimeError::OutOfTape(n) => write!(f, "pointer moved off the tape, to cell {}", n),
+            RuntimeError::OutOfFuel => write!(f, "ran out of fuel"),
+            RuntimeError::Io(ref s) => write!(f, "I/O failure: {}", s),
+        }
+    }
+}
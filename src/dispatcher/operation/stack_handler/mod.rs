@@ -1,10 +1,10 @@
-//! Handles the global stack of the interpreter.
+//! Handles the stack of the interpreter.
 
-use std::sync::Mutex;
+use dispatcher::operation::{CellType, RuntimeError};
 
 /// The structure which holds the stack
 pub struct StackHandler {
-    stk: Vec<i32>,
+    stk: Vec<i64>,
 }
 
 impl StackHandler {
@@ -15,19 +15,30 @@ impl StackHandler {
         }
     }
     /// Returns the top of the stack without popping it
-    pub fn peek(&self) -> i32 {
-        self.stk[self.stk.len() - 1]
+    ///
+    /// # Errors
+    /// Returns `StackUnderflow` if the stack is empty.
+    pub fn peek(&self) -> Result<i64, RuntimeError> {
+        self.stk.last().cloned().ok_or(RuntimeError::StackUnderflow)
     }
-    /// Pops and returns the top of the stack
-    pub fn pop(&mut self, i: usize) -> i32 {
-        let mut diff = 0;
+    /// Pops `i` elements and returns their sum
+    ///
+    /// The sum wraps around, it is up to the array to bring it in the range of the cells.
+    ///
+    /// # Errors
+    /// Returns `StackUnderflow` without popping anything if there are less than `i` elements.
+    pub fn pop(&mut self, i: usize) -> Result<i64, RuntimeError> {
+        if i > self.stk.len() {
+            return Err(RuntimeError::StackUnderflow);
+        }
+        let mut diff: i64 = 0;
         for _ in 0..i {
-            diff += self.stk.pop().expect("Popping from empty stack");
+            diff = diff.wrapping_add(self.stk.pop().unwrap());
         }
-        diff
+        Ok(diff)
     }
     /// Pushes an element to the stack
-    pub fn push(&mut self, el: i32) {
+    pub fn push(&mut self, el: i64) {
         self.stk.push(el);
     }
     /// Prints the debug information
@@ -35,13 +46,48 @@ impl StackHandler {
         println!("Stack size: {}", self.stk.len());
         println!("Stack: {:?}", self.stk);
     }
+    /// Wraps the elements into the range of `cells`
+    pub fn set_cells(&mut self, cells: CellType) {
+        for val in self.stk.iter_mut() {
+            *val = cells.wrap(*val).unwrap_or(i64::from(*val as i32));
+        }
+    }
     /// Returns the length of the stack
     pub fn len(&self) -> usize {
         self.stk.len()
     }
+    /// Checks if the stack has no elements
+    pub fn is_empty(&self) -> bool {
+        self.stk.is_empty()
+    }
+    /// Returns the elements of the stack, the top being the last one
+    pub fn as_slice(&self) -> &[i64] {
+        &self.stk
+    }
+}
+impl Default for StackHandler {
+    fn default() -> StackHandler {
+        StackHandler::new()
+    }
 }
 
-lazy_static!{
-    /// The global stack used by the operations
-    pub static ref STACK_HOLDER: Mutex<StackHandler> = Mutex::new(StackHandler::new());
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::StackHandler;
+    use dispatcher::operation::RuntimeError;
+    #[test]
+    fn empty_stack_errors() {
+        let mut sh = StackHandler::new();
+        assert_eq!(sh.peek(), Err(RuntimeError::StackUnderflow));
+        assert_eq!(sh.pop(1), Err(RuntimeError::StackUnderflow));
+    }
+    #[test]
+    fn underflow_pops_nothing() {
+        let mut sh = StackHandler::new();
+        sh.push(3);
+        sh.push(4);
+        assert_eq!(sh.pop(3), Err(RuntimeError::StackUnderflow));
+        assert_eq!(sh.len(), 2);
+        assert_eq!(sh.pop(2), Ok(7));
+    }
+}
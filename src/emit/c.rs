@@ -0,0 +1,495 @@
+//! Emits a program as a single C file
+
+use dispatcher::operation::*;
+use emit::Target;
+use std::convert::TryFrom;
+use std::fmt::Write;
+
+// Everything the operations need, configured by the defines before it; the functions are
+// inline so the ones a program does not use are left out without warnings
+const RUNTIME: &str = r#"#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+/* An array of cells, the ones left of where the pointer started coming before `origin` */
+typedef struct {
+    int64_t *cells;
+    size_t len;
+    size_t pos;
+    size_t origin;
+} tape;
+
+static tape *T;
+static int64_t *stack;
+static size_t stack_len, stack_cap;
+
+static inline void fail(const char *msg) {
+    fflush(stdout);
+    fprintf(stderr, "Runtime error: %s\n", msg);
+    exit(1);
+}
+
+static inline void fail_n(const char *fmt, long long n) {
+    char msg[64];
+    snprintf(msg, sizeof msg, fmt, n);
+    fail(msg);
+}
+
+static inline void *alloc(void *p, size_t n) {
+    p = realloc(p, n * sizeof(int64_t));
+    if (!p) {
+        fail("out of memory");
+    }
+    return p;
+}
+
+static inline tape *new_tape(size_t len, int mid) {
+    tape *t = malloc(sizeof(tape));
+    if (!t) {
+        fail("out of memory");
+    }
+    t->cells = calloc(len, sizeof(int64_t));
+    if (!t->cells) {
+        fail("out of memory");
+    }
+    t->len = len;
+    t->pos = t->origin = mid ? len / 2 : 0;
+    return t;
+}
+
+/* Returns the index of the cell `off` away from the pointer, growing the array if it has to */
+static inline size_t reach(int32_t off) {
+    int64_t i = (int64_t)T->pos + off;
+    size_t n, k;
+    if (i >= 0 && i < (int64_t)T->len) {
+        return (size_t)i;
+    }
+    if (TAPE_BOUND) {
+        fail_n("pointer moved off the tape, to cell %lld", i < 0 ? -1LL : (long long)T->len);
+    }
+    while (i >= (int64_t)T->len) {
+        n = T->len;
+        T->cells = alloc(T->cells, n * 2);
+        for (k = n; k < n * 2; k++) {
+            T->cells[k] = 0;
+        }
+        T->len = n * 2;
+    }
+    while (i < 0) {
+        n = T->len;
+        T->cells = alloc(T->cells, n * 2);
+        for (k = n; k-- > 0;) {
+            T->cells[k + n] = T->cells[k];
+            T->cells[k] = 0;
+        }
+        T->len = n * 2;
+        T->pos += n;
+        T->origin += n;
+        i += n;
+    }
+    return (size_t)i;
+}
+
+#define CUR (T->cells[T->pos])
+
+#if CELL_TRAP
+static inline int64_t wrap(int64_t v) {
+    if (v < INT32_MIN || v > INT32_MAX) {
+        fail("value out of the range of the cells");
+    }
+    return v;
+}
+
+static inline int64_t add(int64_t a, int64_t b) {
+    if ((b > 0 && a > INT64_MAX - b) || (b < 0 && a < INT64_MIN - b)) {
+        fail("value out of the range of the cells");
+    }
+    return wrap(a + b);
+}
+
+static inline int64_t mul(int64_t a, int64_t b) {
+    if (a != 0 && b != 0 && ((a == -1 && b == INT64_MIN) || (b == -1 && a == INT64_MIN) ||
+            (a > 0 ? (b > 0 ? a > INT64_MAX / b : b < INT64_MIN / a)
+                   : (b > 0 ? a < INT64_MIN / b : b < INT64_MAX / a)))) {
+        fail("value out of the range of the cells");
+    }
+    return wrap(a * b);
+}
+
+/* How many times `step` has to be added to `val` to reach 0, running forever if it never does */
+static inline int64_t iterations(int64_t val, int64_t step) {
+    if (step == 0) {
+        for (;;) {
+        }
+    }
+    if (val % step != 0 || -val / step < 0) {
+        fail("value out of the range of the cells");
+    }
+    return -val / step;
+}
+#else
+static inline int64_t wrap(int64_t v) {
+    return (int64_t)((uint64_t)v & CELL_MASK);
+}
+
+static inline int64_t add(int64_t a, int64_t b) {
+    return wrap((int64_t)((uint64_t)a + (uint64_t)b));
+}
+
+static inline int64_t mul(int64_t a, int64_t b) {
+    return wrap((int64_t)((uint64_t)a * (uint64_t)b));
+}
+
+/* How many times `step` has to be added to `val` to reach 0, running forever if it never does */
+static inline int64_t iterations(int64_t val, int64_t step) {
+    uint64_t v = (0 - (uint64_t)val) & CELL_MASK, s = (uint64_t)step & CELL_MASK, inverse;
+    int twos = 0, i;
+    if (v == 0) {
+        return 0;
+    }
+    if (s == 0) {
+        for (;;) {
+        }
+    }
+    /* Solve s * n = v modulo the number of values, dividing both by the powers of 2 of s */
+    while (!((s >> twos) & 1)) {
+        if ((v >> twos) & 1) {
+            for (;;) {
+            }
+        }
+        twos++;
+    }
+    v >>= twos;
+    s >>= twos;
+    inverse = s;
+    for (i = 0; i < 5; i++) {
+        inverse *= 2 - s * inverse;
+    }
+    return (int64_t)((v * inverse) & (CELL_MASK >> twos));
+}
+#endif
+
+static inline void add_at(int32_t off, int64_t v) {
+    size_t i = reach(off);
+    T->cells[i] = add(T->cells[i], v);
+}
+
+static inline void set_at(int32_t off, int64_t v) {
+    size_t i = reach(off);
+    T->cells[i] = wrap(v);
+}
+
+static inline int64_t get_at(int32_t off) {
+    return T->cells[reach(off)];
+}
+
+static inline void move(int32_t off) {
+    T->pos = reach(off);
+}
+
+static inline void push(int64_t v) {
+    if (stack_len == stack_cap) {
+        stack_cap = stack_cap ? stack_cap * 2 : 16;
+        stack = alloc(stack, stack_cap);
+    }
+    stack[stack_len++] = v;
+}
+
+static inline void peek_at(int32_t off) {
+    size_t i = reach(off);
+    if (!stack_len) {
+        fail("not enough elements in the stack");
+    }
+    T->cells[i] = add(T->cells[i], stack[stack_len - 1]);
+}
+
+/* Pops `n` numbers, adding their sum */
+static inline void pop_at(int32_t off, size_t n) {
+    size_t i = reach(off);
+    uint64_t sum = 0;
+    if (n > stack_len) {
+        fail("not enough elements in the stack");
+    }
+    while (n--) {
+        sum += (uint64_t)stack[--stack_len];
+    }
+    T->cells[i] = add(T->cells[i], (int64_t)sum);
+}
+
+/* Reads the next character of the input, decoding UTF-8, or returns 0 at the end */
+static inline int next_char(int64_t *ch) {
+    int c = getchar(), extra, d;
+    if (c == EOF) {
+        return 0;
+    }
+    extra = c < 0x80 ? 0 : c >= 0xf8 ? -1 : c >= 0xf0 ? 3 : c >= 0xe0 ? 2 : c >= 0xc0 ? 1 : -1;
+    if (extra < 0) {
+        *ch = 0xfffd;
+        return 1;
+    }
+    *ch = extra ? c & (0x3f >> extra) : c;
+    while (extra--) {
+        d = getchar();
+        if (d == EOF || (d & 0xc0) != 0x80) {
+            if (d != EOF) {
+                ungetc(d, stdin);
+            }
+            *ch = 0xfffd;
+            return 1;
+        }
+        *ch = *ch << 6 | (d & 0x3f);
+    }
+    return 1;
+}
+
+static inline void read_at(int32_t off) {
+    size_t i = reach(off);
+    int64_t ch;
+    if (next_char(&ch)) {
+        T->cells[i] = wrap(ch);
+    } else if (EOF_VALUE != 1) {
+        T->cells[i] = wrap(EOF_VALUE);
+    }
+}
+
+static inline void write_at(int32_t off) {
+    putchar((unsigned char)(uint64_t)get_at(off));
+}
+
+/* Makes an array for a function called on a separate array the current one, copying the current
+   number and the `args` after it, and returns the array of the caller */
+static inline tape *enter(size_t args) {
+    tape *caller = T, *t = TAPE_BOUND ? new_tape(TAPE_BOUND, 0) : new_tape((args + 1) * 2, 0);
+    size_t i;
+    T = t;
+    reach((int32_t)args);
+    T = caller;
+    reach((int32_t)args);
+    for (i = 0; i <= args; i++) {
+        t->cells[t->pos + i] = caller->cells[caller->pos + i];
+    }
+    T = t;
+    return caller;
+}
+
+static inline void leave(tape *caller) {
+    free(T->cells);
+    free(T);
+    T = caller;
+}
+
+static inline void debug(void) {
+    size_t i;
+    for (i = 0; i < T->len; i++) {
+        if (T->cells[i]) {
+            fprintf(stderr, "%lld: %lld\n", (long long)i - (long long)T->origin, (long long)T->cells[i]);
+        }
+    }
+    fprintf(stderr, "Pointer at %lld\n", (long long)T->pos - (long long)T->origin);
+    fprintf(stderr, "Stack size: %lu\nStack: [", (unsigned long)stack_len);
+    for (i = 0; i < stack_len; i++) {
+        fprintf(stderr, i ? ", %lld" : "%lld", (long long)stack[i]);
+    }
+    fprintf(stderr, "]\nNumber of functions: %d\n", FUNCTIONS);
+}
+"#;
+
+// Calling functions by their number, once the table of functions is declared
+const CALLS: &str = r#"
+static inline void check(int64_t n) {
+    if (n < 0) {
+        fail_n("cannot call function %lld", (long long)n);
+    }
+    if (n >= FUNCTIONS) {
+        fail_n("function %lld is not defined", (long long)n);
+    }
+}
+
+static inline void call(int64_t n) {
+    check(n);
+    funs[n]();
+}
+
+static inline void call_separate(int64_t n, size_t args) {
+    tape *caller;
+    check(n);
+    caller = enter(args);
+    separate_funs[n]();
+    leave(caller);
+}
+"#;
+
+// Writes the lines of a C program
+struct Emitter {
+    out: String,
+    depth: usize,
+    functions: usize,
+}
+
+/// Returns a C program running `ops`, see `emit`
+pub fn program(ops: &[Operation], fh: &FunctionHolder, target: Target) -> String {
+    let functions = fh.no_functions();
+    let mut em = Emitter { out: String::new(), depth: 0, functions };
+    let (trap, mask) = match target.cells {
+        CellType::U8 => (0, "0xffu"),
+        CellType::U16 => (0, "0xffffu"),
+        CellType::U32 => (0, "0xffffffffu"),
+        CellType::I64 => (0, "UINT64_MAX"),
+        CellType::Trap => (1, "UINT64_MAX"),
+    };
+    let eof = match target.eof {
+        EofMode::Unchanged => 1,
+        EofMode::Zero => 0,
+        EofMode::MinusOne => -1,
+    };
+    em.line("/* Emitted by the Brainfuck interpreter */");
+    em.line(&format!("#define CELL_TRAP {}", trap));
+    em.line(&format!("#define CELL_MASK ((uint64_t){})", mask));
+    em.line(&format!("#define TAPE_BOUND {}", target.tape.unwrap_or(0)));
+    em.line("/* What reading stores at the end of the input, 1 leaving the cell unchanged */");
+    em.line(&format!("#define EOF_VALUE {}", eof));
+    em.line(&format!("#define FUNCTIONS {}", functions));
+    em.out.push_str(RUNTIME);
+    em.line("");
+    for n in 0..functions {
+        em.line(&format!("static void fun_{0}(void);\nstatic void fun_{0}_separate(void);", n));
+    }
+    // An array with no elements is not valid C
+    let table = |suffix| (0..functions.max(1)).map(|n| if n < functions { format!("fun_{}{}", n, suffix) } else { "0".to_string() })
+        .collect::<Vec<_>>().join(", ");
+    em.line(&format!("static void (*const funs[])(void) = {{{}}};", table("")));
+    em.line(&format!("static void (*const separate_funs[])(void) = {{{}}};", table("_separate")));
+    em.out.push_str(CALLS);
+    for n in 0..functions {
+        let fun = fh.get(n as i64).expect("stable function");
+        em.function(&format!("fun_{}", n), fun.ops());
+        em.function(&format!("fun_{}_separate", n), fun.separate_ops());
+    }
+    em.function("run", ops);
+    em.line("");
+    em.line("int main(void) {");
+    em.line("    T = TAPE_BOUND ? new_tape(TAPE_BOUND, 0) : new_tape(1024, 1);");
+    em.line("    run();");
+    em.line("    return fflush(stdout) != 0;");
+    em.line("}");
+    em.out
+}
+
+impl Emitter {
+    // Appends a line, indented to the current depth
+    fn line(&mut self, code: &str) {
+        for _ in 0..self.depth {
+            self.out.push_str("    ");
+        }
+        self.out.push_str(code);
+        self.out.push('\n');
+    }
+    // Appends a function without arguments running `ops`
+    fn function(&mut self, name: &str, ops: &[Operation]) {
+        self.line("");
+        self.line(&format!("static void {}(void) {{", name));
+        self.block(ops);
+        self.line("}");
+    }
+    // Appends `ops` one level deeper
+    fn block(&mut self, ops: &[Operation]) {
+        self.depth += 1;
+        for op in ops {
+            self.op(op);
+        }
+        self.depth -= 1;
+    }
+    fn op(&mut self, op: &Operation) {
+        match *op {
+            Add(off, i) => self.line(&format!("add_at({}, {});", off, int(i))),
+            Move(i) => self.line(&format!("move({});", i)),
+            Set(off, i) => self.line(&format!("set_at({}, {});", off, int(i))),
+            MoveTo(ref lin) => {
+                self.line("if (CUR) {");
+                self.depth += 1;
+                self.line(&format!("int64_t n = iterations(CUR, {});", int(lin.step)));
+                self.line("CUR = 0;");
+                for &(off, val) in &lin.sets {
+                    self.line(&format!("set_at({}, {});", off, int(val)));
+                }
+                for &(off, mult) in &lin.adds {
+                    self.line(&format!("add_at({}, mul(n, {}));", off, int(mult)));
+                }
+                self.depth -= 1;
+                self.line("}");
+            }
+            SkipMove(i) => self.line(&format!("while (CUR) move({});", i)),
+            Read(off) => self.line(&format!("read_at({});", off)),
+            Write(off) => self.line(&format!("write_at({});", off)),
+            While(ref body) => {
+                self.line("while (CUR) {");
+                self.block(body);
+                self.line("}");
+            }
+            InsFuns => self.line("set_at(0, FUNCTIONS);"),
+            CallFun => self.line("call(CUR);"),
+            CallFSep(args) => self.line(&format!("call_separate(CUR, {});", args)),
+            Call(n) if n < self.functions => self.line(&format!("fun_{}();", n)),
+            Call(n) => self.line(&format!("call({});", n)),
+            CallSep(n, args) => self.line(&format!("call_separate({}, {});", n, args)),
+            Separate(args, ref body) => {
+                self.line(&format!("{{ tape *caller = enter({});", args));
+                self.block(body);
+                self.line("leave(caller); }");
+            }
+            Debug => self.line("debug();"),
+            PeekStack(off) => self.line(&format!("peek_at({});", off)),
+            PopStack(off, i) => self.line(&format!("pop_at({}, {});", off, i)),
+            PushStack(off) => self.line(&format!("push(get_at({}));", off)),
+            StackLen(off) => self.line(&format!("set_at({}, (int64_t)stack_len);", off)),
+            Restore(ref snap) => {
+                for &(off, val) in &snap.cells {
+                    self.line(&format!("set_at({}, {});", off, int(val)));
+                }
+                for &val in &snap.stack {
+                    self.line(&format!("push({});", int(val)));
+                }
+                if !snap.output.is_empty() {
+                    let mut text = String::new();
+                    for &byte in &snap.output {
+                        write!(text, "\\{:03o}", byte).expect("writing to a string");
+                    }
+                    self.line(&format!("fwrite(\"{}\", 1, {}, stdout);", text, snap.output.len()));
+                }
+                self.line(&format!("move({});", snap.pointer));
+            }
+            EmptyOp => {}
+        }
+    }
+}
+
+// Returns a C expression for `i`, which needs 64 bits unless it fits in an int
+fn int(i: i64) -> String {
+    if i == i64::MIN {
+        String::from("INT64_MIN")
+    } else if i32::try_from(i).is_ok() {
+        i.to_string()
+    } else {
+        format!("INT64_C({})", i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{int, program};
+    use dispatcher::FunctionHolder;
+    use dispatcher::Operation::*;
+    use emit::Target;
+    #[test]
+    fn integers_fit_their_literals() {
+        assert_eq!(int(-7), "-7");
+        assert_eq!(int(1 << 40), "INT64_C(1099511627776)");
+        assert_eq!(int(i64::MIN), "INT64_MIN");
+    }
+    #[test]
+    fn programs_without_functions_have_a_table() {
+        let code = program(&[Add(0, 1), Move(2)], &FunctionHolder::new(), Target::default());
+        assert!(code.contains("#define FUNCTIONS 0"));
+        assert!(code.contains("add_at(0, 1);\n    move(2);"));
+    }
+}
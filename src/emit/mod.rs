@@ -0,0 +1,113 @@
+//! Turns compiled operations into standalone programs in other languages.
+//!
+//! The programs behave like `Compiler::execute` on a new array and stack: they write the
+//! same output, and stop with the same runtime errors, printed to stderr.
+//!
+//! A Rust module can be emitted by a build script, to run a script as ordinary compiled code:
+//!
+//! ```no_run
+//! // build.rs, with Brainfuck among the build-dependencies
+//! use Brainfuck::Compiler;
+//! use Brainfuck::emit::Language;
+//! use std::{env, fs, path::Path};
+//!
+//! let mut compiler = Compiler::new();
+//! compiler.try_compile_file("hello_world").unwrap();
+//! let out = Path::new(&env::var("OUT_DIR").unwrap()).join("hello_world.rs");
+//! fs::write(out, compiler.emit(Language::Rust)).unwrap();
+//! ```
+//!
+//! The crate then includes it in a module of its own, and calls `hello_world::run(input, output)`
+//! with any reader and writer; it returns the message of the runtime error stopping it, if any:
+//!
+//! ```text
+//! mod hello_world { include!(concat!(env!("OUT_DIR"), "/hello_world.rs")); }
+//! ```
+//!
+//! A WebAssembly module keeps the array and the stack in its linear memory, and leaves its
+//! input and output to the runtime running it, through functions it imports from `env`:
+//!
+//! * `read() -> i32` returns the next byte of the input, or -1 at its end
+//! * `write(at: i32, len: i32)` writes `len` bytes of its memory to the output
+//! * `debug(at: i32, len: i32)` writes debug information, meant for stderr
+//! * `error(at: i32, len: i32)` takes the message of the runtime error stopping the program,
+//!   which traps right after
+//!
+//! It exports its memory as `memory`, and `run`, which runs the program.
+
+mod c;
+mod rust;
+mod wat;
+
+use dispatcher::operation::*;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+/// The languages programs can be emitted in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Language {
+    /// A single C file, needing only the standard library
+    C,
+    /// A Rust module with a `run(input, output)` function, needing only the standard library
+    Rust,
+    /// A WebAssembly text module exporting a `run` function and its memory, with its input and
+    /// output going through imported functions
+    Wat,
+}
+
+impl FromStr for Language {
+    type Err = String;
+    /// Parses `c`, `rust` or `wat`
+    fn from_str(s: &str) -> Result<Language, String> {
+        match s {
+            "c" => Ok(Language::C),
+            "rust" => Ok(Language::Rust),
+            "wat" => Ok(Language::Wat),
+            _ => Err(format!("Unknown language '{}', expected c, rust or wat", s)),
+        }
+    }
+}
+
+/// The settings of the interpreter a program is emitted for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Target {
+    /// The type of the cells
+    pub cells: CellType,
+    /// What reading does once the input is exhausted
+    pub eof: EofMode,
+    /// The size of the array if it is bounded
+    pub tape: Option<usize>,
+}
+
+impl Target {
+    /// Returns the settings of `ctx`
+    pub fn of<R: Read, W: Write>(ctx: &Context<R, W>) -> Target {
+        Target { cells: ctx.cells(), eof: ctx.io.eof, tape: ctx.ah.bound() }
+    }
+}
+
+impl Default for Target {
+    /// The default settings of a `Compiler`
+    fn default() -> Target {
+        Target { cells: CellType::default(), eof: EofMode::Unchanged, tape: None }
+    }
+}
+
+/// Emits a program running `ops` with the stable functions of `fh`
+///
+/// # Examples
+/// ```
+/// use Brainfuck::emit::{emit, Language, Target};
+/// use Brainfuck::dispatcher::FunctionHolder;
+/// use Brainfuck::dispatcher::Operation::*;
+///
+/// let program = emit(Language::C, &[Add(0, 65), Write(0)], &FunctionHolder::new(), Target::default());
+/// assert!(program.contains("add_at(0, 65);"));
+/// ```
+pub fn emit(lang: Language, ops: &[Operation], fh: &FunctionHolder, target: Target) -> String {
+    match lang {
+        Language::C => c::program(ops, fh, target),
+        Language::Rust => rust::program(ops, fh, target),
+        Language::Wat => wat::program(ops, fh, target),
+    }
+}
@@ -0,0 +1,443 @@
+//! Emits a program as a self-contained Rust module
+
+use dispatcher::operation::*;
+use emit::Target;
+use std::fmt::Write;
+
+// Everything the operations need, configured by the constants before it; the items a program
+// does not use are allowed, as the module ends up in the crate including it
+const RUNTIME: &str = r#"
+use std::io::{self, BufWriter, Read, Write};
+
+type Res<T> = Result<T, String>;
+
+/// Runs the program, reading `input` and writing to `output`
+///
+/// Returns the message of the runtime error stopping it, if any.
+pub fn run<R: Read, W: Write>(mut input: R, output: W) -> Result<(), String> {
+    let mut output = BufWriter::new(output);
+    let result = {
+        let mut m = Machine::new(&mut input, &mut output);
+        program(&mut m)
+    };
+    let flushed = output.flush().map_err(io_failure);
+    result.and(flushed)
+}
+
+fn io_failure(e: io::Error) -> String {
+    format!("I/O failure: {}", e)
+}
+
+fn overflow() -> String {
+    String::from("value out of the range of the cells")
+}
+
+fn underflow() -> String {
+    String::from("not enough elements in the stack")
+}
+
+// Stands for a loop which never ends
+fn forever() -> ! {
+    loop {
+        ::std::thread::park();
+    }
+}
+
+#[allow(dead_code)]
+fn wrap(v: i64) -> Res<i64> {
+    if !CELL_TRAP {
+        Ok((v as u64 & CELL_MASK) as i64)
+    } else if v < i64::from(i32::MIN) || v > i64::from(i32::MAX) {
+        Err(overflow())
+    } else {
+        Ok(v)
+    }
+}
+
+#[allow(dead_code)]
+fn add(a: i64, b: i64) -> Res<i64> {
+    if CELL_TRAP {
+        wrap(a.checked_add(b).ok_or_else(overflow)?)
+    } else {
+        wrap(a.wrapping_add(b))
+    }
+}
+
+#[allow(dead_code)]
+fn mul(a: i64, b: i64) -> Res<i64> {
+    if CELL_TRAP {
+        wrap(a.checked_mul(b).ok_or_else(overflow)?)
+    } else {
+        wrap(a.wrapping_mul(b))
+    }
+}
+
+// How many times `step` has to be added to `val` to reach 0, running forever if it never does
+#[allow(dead_code)]
+fn iterations(val: i64, step: i64) -> Res<i64> {
+    if CELL_TRAP {
+        if step == 0 {
+            forever();
+        }
+        return if val % step == 0 && -val / step >= 0 { Ok(-val / step) } else { Err(overflow()) };
+    }
+    let (v, s) = (val.wrapping_neg() as u64 & CELL_MASK, step as u64 & CELL_MASK);
+    if v == 0 {
+        return Ok(0);
+    }
+    // Solve s * n = v modulo the number of values, dividing both by the powers of 2 of s
+    let twos = s.trailing_zeros();
+    if s == 0 || v.trailing_zeros() < twos {
+        forever();
+    }
+    let (v, s) = (v >> twos, s >> twos);
+    let mut inverse = s;
+    for _ in 0..5 {
+        inverse = inverse.wrapping_mul(2u64.wrapping_sub(s.wrapping_mul(inverse)));
+    }
+    Ok((v.wrapping_mul(inverse) & (CELL_MASK >> twos)) as i64)
+}
+
+// An array of cells, the ones left of where the pointer started coming before `origin`
+struct Tape {
+    cells: Vec<i64>,
+    pos: usize,
+    origin: usize,
+}
+
+impl Tape {
+    fn new(len: usize, origin: usize) -> Tape {
+        Tape { cells: vec![0; len], pos: origin, origin }
+    }
+    // Returns the index of the cell `off` away from the pointer, growing the array if it has to
+    fn reach(&mut self, off: i32) -> Res<usize> {
+        let i = self.pos as i64 + i64::from(off);
+        if i >= 0 && i < self.cells.len() as i64 {
+            return Ok(i as usize);
+        }
+        if TAPE_BOUND != 0 {
+            return Err(format!("pointer moved off the tape, to cell {}", if i < 0 { -1 } else { self.cells.len() as i64 }));
+        }
+        if i >= 0 {
+            let len = (i as usize + 1).max(self.cells.len() * 2);
+            self.cells.resize(len, 0);
+            return Ok(i as usize);
+        }
+        let n = ((-i) as usize).max(self.cells.len());
+        let mut cells = vec![0; n];
+        cells.extend_from_slice(&self.cells);
+        self.cells = cells;
+        self.pos += n;
+        self.origin += n;
+        Ok((i + n as i64) as usize)
+    }
+}
+
+// The state of a running program
+struct Machine<'a> {
+    tape: Tape,
+    stack: Vec<i64>,
+    input: &'a mut dyn Read,
+    line: Vec<char>,
+    next: usize,
+    output: &'a mut dyn Write,
+}
+
+#[allow(dead_code)]
+impl<'a> Machine<'a> {
+    fn new(input: &'a mut dyn Read, output: &'a mut dyn Write) -> Machine<'a> {
+        let tape = if TAPE_BOUND != 0 { Tape::new(TAPE_BOUND, 0) } else { Tape::new(1024, 512) };
+        Machine { tape, stack: Vec::new(), input, line: Vec::new(), next: 0, output }
+    }
+    fn cur(&self) -> i64 {
+        self.tape.cells[self.tape.pos]
+    }
+    fn add_at(&mut self, off: i32, v: i64) -> Res<()> {
+        let i = self.tape.reach(off)?;
+        self.tape.cells[i] = add(self.tape.cells[i], v)?;
+        Ok(())
+    }
+    fn set_at(&mut self, off: i32, v: i64) -> Res<()> {
+        let i = self.tape.reach(off)?;
+        self.tape.cells[i] = wrap(v)?;
+        Ok(())
+    }
+    fn get_at(&mut self, off: i32) -> Res<i64> {
+        let i = self.tape.reach(off)?;
+        Ok(self.tape.cells[i])
+    }
+    fn move_by(&mut self, off: i32) -> Res<()> {
+        self.tape.pos = self.tape.reach(off)?;
+        Ok(())
+    }
+    fn skip_move(&mut self, off: i32) -> Res<()> {
+        while self.cur() != 0 {
+            self.move_by(off)?;
+        }
+        Ok(())
+    }
+    fn peek_at(&mut self, off: i32) -> Res<()> {
+        self.get_at(off)?;
+        let v = *self.stack.last().ok_or_else(underflow)?;
+        self.add_at(off, v)
+    }
+    // Pops `n` numbers, adding their sum
+    fn pop_at(&mut self, off: i32, n: usize) -> Res<()> {
+        self.get_at(off)?;
+        if n > self.stack.len() {
+            return Err(underflow());
+        }
+        let at = self.stack.len() - n;
+        let sum = self.stack.drain(at..).fold(0i64, |sum, v| sum.wrapping_add(v));
+        self.add_at(off, sum)
+    }
+    fn push_at(&mut self, off: i32) -> Res<()> {
+        let v = self.get_at(off)?;
+        self.stack.push(v);
+        Ok(())
+    }
+    fn stack_len_at(&mut self, off: i32) -> Res<()> {
+        let len = self.stack.len() as i64;
+        self.set_at(off, len)
+    }
+    // Returns the next character of the input, reading it a line at a time
+    fn next_char(&mut self) -> Res<Option<i64>> {
+        if self.next == self.line.len() {
+            // Whatever the program asks before reading has to be seen first
+            self.output.flush().map_err(io_failure)?;
+            let mut line = Vec::new();
+            let mut byte = [0u8];
+            while self.input.read(&mut byte).map_err(io_failure)? != 0 {
+                line.push(byte[0]);
+                if byte[0] == b'\n' {
+                    break;
+                }
+            }
+            self.line = String::from_utf8_lossy(&line).chars().collect();
+            self.next = 0;
+            if self.line.is_empty() {
+                return Ok(None);
+            }
+        }
+        self.next += 1;
+        Ok(Some(i64::from(self.line[self.next - 1] as u32)))
+    }
+    fn read_at(&mut self, off: i32) -> Res<()> {
+        let i = self.tape.reach(off)?;
+        if let Some(ch) = self.next_char()?.or(EOF_VALUE) {
+            self.tape.cells[i] = wrap(ch)?;
+        }
+        Ok(())
+    }
+    fn write_at(&mut self, off: i32) -> Res<()> {
+        let v = self.get_at(off)?;
+        self.output.write_all(&[v as u8]).map_err(io_failure)
+    }
+    fn write_bytes(&mut self, bytes: &[u8]) -> Res<()> {
+        self.output.write_all(bytes).map_err(io_failure)
+    }
+    // Makes an array for a function called on a separate array the current one, copying the
+    // current number and the `args` after it, and returns the array of the caller
+    fn enter(&mut self, args: usize) -> Res<Tape> {
+        let mut tape = if TAPE_BOUND != 0 { Tape::new(TAPE_BOUND, 0) } else { Tape::new((args + 1) * 2, 0) };
+        tape.reach(args as i32)?;
+        self.tape.reach(args as i32)?;
+        let pos = self.tape.pos;
+        tape.cells[..=args].copy_from_slice(&self.tape.cells[pos..=pos + args]);
+        Ok(::std::mem::replace(&mut self.tape, tape))
+    }
+    fn leave(&mut self, caller: Tape) {
+        self.tape = caller;
+    }
+    // Checks that `n` is the number of a function
+    fn check(n: i64) -> Res<usize> {
+        if n < 0 {
+            Err(format!("cannot call function {}", n))
+        } else if n as u64 >= FUNCTIONS as u64 {
+            Err(format!("function {} is not defined", n))
+        } else {
+            Ok(n as usize)
+        }
+    }
+    fn call(&mut self, n: i64) -> Res<()> {
+        FUNS[Machine::check(n)?](self)
+    }
+    fn call_separate(&mut self, n: i64, args: usize) -> Res<()> {
+        let n = Machine::check(n)?;
+        let caller = self.enter(args)?;
+        SEPARATE_FUNS[n](self)?;
+        self.leave(caller);
+        Ok(())
+    }
+    fn debug(&self) {
+        let tape = &self.tape;
+        for (i, &v) in tape.cells.iter().enumerate().filter(|&(_, &v)| v != 0) {
+            eprintln!("{}: {}", i as i64 - tape.origin as i64, v);
+        }
+        eprintln!("Pointer at {}", tape.pos as i64 - tape.origin as i64);
+        eprintln!("Stack size: {}\nStack: {:?}", self.stack.len(), self.stack);
+        eprintln!("Number of functions: {}", FUNCTIONS);
+    }
+}
+"#;
+
+// Writes the lines of a Rust module
+struct Emitter {
+    out: String,
+    depth: usize,
+    functions: usize,
+}
+
+/// Returns a Rust module running `ops`, see `emit`
+pub fn program(ops: &[Operation], fh: &FunctionHolder, target: Target) -> String {
+    let functions = fh.no_functions();
+    let mut em = Emitter { out: String::new(), depth: 0, functions };
+    let (trap, mask) = match target.cells {
+        CellType::U8 => (false, "0xff"),
+        CellType::U16 => (false, "0xffff"),
+        CellType::U32 => (false, "0xffff_ffff"),
+        CellType::I64 => (false, "!0"),
+        CellType::Trap => (true, "!0"),
+    };
+    let eof = match target.eof {
+        EofMode::Unchanged => "None",
+        EofMode::Zero => "Some(0)",
+        EofMode::MinusOne => "Some(-1)",
+    };
+    em.line("// Emitted by the Brainfuck interpreter, to be included in a module of its own");
+    em.line("");
+    em.line(&format!("const CELL_TRAP: bool = {};", trap));
+    em.line("#[allow(dead_code)]");
+    em.line(&format!("const CELL_MASK: u64 = {};", mask));
+    em.line(&format!("const TAPE_BOUND: usize = {};", target.tape.unwrap_or(0)));
+    em.line("// What reading stores at the end of the input, `None` leaving the cell unchanged");
+    em.line(&format!("const EOF_VALUE: Option<i64> = {};", eof));
+    em.line(&format!("const FUNCTIONS: usize = {};", functions));
+    em.out.push_str(RUNTIME);
+    let table = |suffix| (0..functions).map(|n| format!("fun_{}{}", n, suffix)).collect::<Vec<_>>().join(", ");
+    em.line("");
+    em.line(&format!("const FUNS: [fn(&mut Machine) -> Res<()>; FUNCTIONS] = [{}];", table("")));
+    em.line(&format!("const SEPARATE_FUNS: [fn(&mut Machine) -> Res<()>; FUNCTIONS] = [{}];", table("_separate")));
+    for n in 0..functions {
+        let fun = fh.get(n as i64).expect("stable function");
+        em.function(&format!("fun_{}", n), fun.ops());
+        em.function(&format!("fun_{}_separate", n), fun.separate_ops());
+    }
+    em.function("program", ops);
+    em.out
+}
+
+impl Emitter {
+    // Appends a line, indented to the current depth
+    fn line(&mut self, code: &str) {
+        for _ in 0..self.depth {
+            self.out.push_str("    ");
+        }
+        self.out.push_str(code);
+        self.out.push('\n');
+    }
+    // Appends a function running `ops` on a machine
+    fn function(&mut self, name: &str, ops: &[Operation]) {
+        self.line("");
+        self.line(&format!("fn {}(m: &mut Machine) -> Res<()> {{", name));
+        self.block(ops);
+        self.line("    Ok(())");
+        self.line("}");
+    }
+    // Appends `ops` one level deeper
+    fn block(&mut self, ops: &[Operation]) {
+        self.depth += 1;
+        for op in ops {
+            self.op(op);
+        }
+        self.depth -= 1;
+    }
+    fn op(&mut self, op: &Operation) {
+        match *op {
+            Add(off, i) => self.line(&format!("m.add_at({}, {})?;", off, int(i))),
+            Move(i) => self.line(&format!("m.move_by({})?;", i)),
+            Set(off, i) => self.line(&format!("m.set_at({}, {})?;", off, int(i))),
+            MoveTo(ref lin) => {
+                self.line("if m.cur() != 0 {");
+                self.depth += 1;
+                let n = if lin.adds.is_empty() { "" } else { "let n = " };
+                self.line(&format!("{}iterations(m.cur(), {})?;", n, int(lin.step)));
+                self.line("m.set_at(0, 0)?;");
+                for &(off, val) in &lin.sets {
+                    self.line(&format!("m.set_at({}, {})?;", off, int(val)));
+                }
+                for &(off, mult) in &lin.adds {
+                    self.line(&format!("m.add_at({}, mul(n, {})?)?;", off, int(mult)));
+                }
+                self.depth -= 1;
+                self.line("}");
+            }
+            SkipMove(i) => self.line(&format!("m.skip_move({})?;", i)),
+            Read(off) => self.line(&format!("m.read_at({})?;", off)),
+            Write(off) => self.line(&format!("m.write_at({})?;", off)),
+            While(ref body) => {
+                self.line("while m.cur() != 0 {");
+                self.block(body);
+                self.line("}");
+            }
+            InsFuns => self.line("m.set_at(0, FUNCTIONS as i64)?;"),
+            CallFun => self.line("m.call(m.cur())?;"),
+            CallFSep(args) => self.line(&format!("m.call_separate(m.cur(), {})?;", args)),
+            Call(n) if n < self.functions => self.line(&format!("fun_{}(m)?;", n)),
+            Call(n) => self.line(&format!("m.call({})?;", n)),
+            CallSep(n, args) => self.line(&format!("m.call_separate({}, {})?;", n, args)),
+            Separate(args, ref body) => {
+                self.line("{");
+                self.line(&format!("    let caller = m.enter({})?;", args));
+                self.block(body);
+                self.line("    m.leave(caller);");
+                self.line("}");
+            }
+            Debug => self.line("m.debug();"),
+            PeekStack(off) => self.line(&format!("m.peek_at({})?;", off)),
+            PopStack(off, i) => self.line(&format!("m.pop_at({}, {})?;", off, i)),
+            PushStack(off) => self.line(&format!("m.push_at({})?;", off)),
+            StackLen(off) => self.line(&format!("m.stack_len_at({})?;", off)),
+            Restore(ref snap) => {
+                for &(off, val) in &snap.cells {
+                    self.line(&format!("m.set_at({}, {})?;", off, int(val)));
+                }
+                for &val in &snap.stack {
+                    self.line(&format!("m.stack.push({});", int(val)));
+                }
+                if !snap.output.is_empty() {
+                    let mut text = String::new();
+                    for &byte in &snap.output {
+                        write!(text, "\\x{:02x}", byte).expect("writing to a string");
+                    }
+                    self.line(&format!("m.write_bytes(b\"{}\")?;", text));
+                }
+                self.line(&format!("m.move_by({})?;", snap.pointer));
+            }
+            EmptyOp => {}
+        }
+    }
+}
+
+// Returns a Rust expression for `i`, whose type is inferred from where it is used
+fn int(i: i64) -> String {
+    if i == i64::MIN {
+        String::from("i64::MIN")
+    } else {
+        i.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::program;
+    use dispatcher::FunctionHolder;
+    use dispatcher::Operation::*;
+    use emit::Target;
+    #[test]
+    fn operations_are_calls_on_the_machine() {
+        let code = program(&[Add(0, i64::MIN), While(vec![Move(2)])], &FunctionHolder::new(), Target::default());
+        assert!(code.contains("const FUNS: [fn(&mut Machine) -> Res<()>; FUNCTIONS] = [];"));
+        assert!(code.contains("    m.add_at(0, i64::MIN)?;\n    while m.cur() != 0 {\n        m.move_by(2)?;\n    }\n"));
+    }
+}
@@ -0,0 +1,636 @@
+//! Emits a program as a WebAssembly text module
+
+use dispatcher::operation::*;
+use emit::Target;
+use std::fmt::Write;
+
+// Where the texts start in memory, after the digits of numbers being written, a byte being
+// written and the message of an error being put together
+const TEXTS: usize = 288;
+
+// The texts the runtime writes, named by the globals holding their addresses, each ending in a 0
+const MESSAGES: &[(&str, &str)] = &[
+    ("msg_none", ""),
+    ("msg_overflow", "value out of the range of the cells"),
+    ("msg_underflow", "not enough elements in the stack"),
+    ("msg_off_tape", "pointer moved off the tape, to cell "),
+    ("msg_cannot_call", "cannot call function "),
+    ("msg_function", "function "),
+    ("msg_not_defined", " is not defined"),
+    ("msg_no_memory", "out of memory"),
+    ("msg_colon", ": "),
+    ("msg_newline", "\n"),
+    ("msg_pointer", "Pointer at "),
+    ("msg_stack_size", "Stack size: "),
+    ("msg_stack", "\nStack: ["),
+    ("msg_comma", ", "),
+    ("msg_functions", "]\nNumber of functions: "),
+];
+
+// Everything the operations need, configured by the globals before it
+const RUNTIME: &str = r#"
+  ;; Memory starts with the digits of a number being written, ending at 24, a byte being written
+  ;; at 24 and the message of an error being put together at 32, then the texts. The stack
+  ;; follows from STACK, then every array in use: the first one, then the one of each function
+  ;; called on a separate array after a header keeping the array of its caller
+  (memory (export "memory") 1)
+
+  (type $op (func))
+
+  (global $stack_len (mut i32) (i32.const 0))
+  (global $stack_cap (mut i32) (i32.const 0))
+  (global $tapes (mut i32) (i32.const 0))
+  ;; The current array, the last one: where its cells start after $tapes, how many there are,
+  ;; the pointer and where it started, the cells left of it coming before
+  (global $cells (mut i32) (i32.const 0))
+  (global $len (mut i32) (i32.const 0))
+  (global $pos (mut i32) (i32.const 0))
+  (global $origin (mut i32) (i32.const 0))
+  ;; A byte of the input read ahead of the character it starts, or -2
+  (global $unread (mut i32) (i32.const -2))
+
+  ;; Runs the program on a new array and stack
+  (func (export "run")
+    (global.set $stack_len (i32.const 0))
+    (global.set $stack_cap (i32.const 64))
+    (global.set $tapes (i32.add (global.get $STACK) (i32.const 512)))
+    (global.set $cells (i32.const 0))
+    (global.set $unread (i32.const -2))
+    (global.set $len (select (global.get $TAPE_BOUND) (i32.const 1024) (global.get $TAPE_BOUND)))
+    (global.set $pos (select (i32.const 0) (i32.const 512) (global.get $TAPE_BOUND)))
+    (global.set $origin (global.get $pos))
+    (call $reserve (call $end))
+    (memory.fill (call $base) (i32.const 0) (i32.shl (global.get $len) (i32.const 3)))
+    (call $program))
+
+  (func $strlen (param $at i32) (result i32)
+    (local $end i32)
+    (local.set $end (local.get $at))
+    (block (loop
+      (br_if 1 (i32.eqz (i32.load8_u (local.get $end))))
+      (local.set $end (i32.add (local.get $end) (i32.const 1)))
+      (br 0)))
+    (i32.sub (local.get $end) (local.get $at)))
+
+  ;; Writes the digits of `n` ending at 24, returning where they start
+  (func $format (param $n i64) (result i32)
+    (local $at i32) (local $left i64)
+    (local.set $at (i32.const 24))
+    (local.set $left (select (i64.sub (i64.const 0) (local.get $n)) (local.get $n)
+      (i64.lt_s (local.get $n) (i64.const 0))))
+    (loop
+      (local.set $at (i32.sub (local.get $at) (i32.const 1)))
+      (i64.store8 (local.get $at) (i64.add (i64.const 48) (i64.rem_u (local.get $left) (i64.const 10))))
+      (local.set $left (i64.div_u (local.get $left) (i64.const 10)))
+      (br_if 0 (i64.ne (local.get $left) (i64.const 0))))
+    (if (i64.lt_s (local.get $n) (i64.const 0))
+      (then
+        (local.set $at (i32.sub (local.get $at) (i32.const 1)))
+        (i32.store8 (local.get $at) (i32.const 45))))
+    (local.get $at))
+
+  (func $fail (param $msg i32)
+    (call $error (local.get $msg) (call $strlen (local.get $msg)))
+    unreachable)
+
+  ;; Stops with the message made of `prefix`, `n` and `suffix`
+  (func $fail_n (param $prefix i32) (param $n i64) (param $suffix i32)
+    (local $len i32) (local $at i32) (local $more i32)
+    (local.set $len (call $strlen (local.get $prefix)))
+    (memory.copy (i32.const 32) (local.get $prefix) (local.get $len))
+    (local.set $at (call $format (local.get $n)))
+    (local.set $more (i32.sub (i32.const 24) (local.get $at)))
+    (memory.copy (i32.add (i32.const 32) (local.get $len)) (local.get $at) (local.get $more))
+    (local.set $len (i32.add (local.get $len) (local.get $more)))
+    (local.set $more (call $strlen (local.get $suffix)))
+    (memory.copy (i32.add (i32.const 32) (local.get $len)) (local.get $suffix) (local.get $more))
+    (call $error (i32.const 32) (i32.add (local.get $len) (local.get $more)))
+    unreachable)
+
+  (func $say (param $msg i32)
+    (call $write_debug (local.get $msg) (call $strlen (local.get $msg))))
+
+  (func $say_num (param $n i64)
+    (local $at i32)
+    (local.set $at (call $format (local.get $n)))
+    (call $write_debug (local.get $at) (i32.sub (i32.const 24) (local.get $at))))
+
+  ;; Stands for a loop which never ends
+  (func $forever
+    (loop (br 0)))
+
+  (func $wrap (param $v i64) (result i64)
+    (if (global.get $CELL_TRAP)
+      (then
+        (if (i64.ne (local.get $v) (i64.extend_i32_s (i32.wrap_i64 (local.get $v))))
+          (then (call $fail (global.get $msg_overflow))))
+        (return (local.get $v))))
+    (i64.and (local.get $v) (global.get $CELL_MASK)))
+
+  (func $add (param $a i64) (param $b i64) (result i64)
+    (local $sum i64)
+    (local.set $sum (i64.add (local.get $a) (local.get $b)))
+    (if (i32.and (global.get $CELL_TRAP) (i64.lt_s (i64.and (i64.xor (local.get $a) (local.get $sum))
+        (i64.xor (local.get $b) (local.get $sum))) (i64.const 0)))
+      (then (call $fail (global.get $msg_overflow))))
+    (call $wrap (local.get $sum)))
+
+  (func $mul (param $a i64) (param $b i64) (result i64)
+    (local $product i64)
+    (local.set $product (i64.mul (local.get $a) (local.get $b)))
+    (if (i32.and (global.get $CELL_TRAP) (i64.ne (local.get $b) (i64.const 0)))
+      (then
+        (if (i32.and (i64.eq (local.get $a) (i64.const -9223372036854775808)) (i64.eq (local.get $b) (i64.const -1)))
+          (then (call $fail (global.get $msg_overflow))))
+        (if (i32.and (i64.eq (local.get $b) (i64.const -9223372036854775808)) (i64.eq (local.get $a) (i64.const -1)))
+          (then (call $fail (global.get $msg_overflow))))
+        (if (i64.ne (i64.div_s (local.get $product) (local.get $b)) (local.get $a))
+          (then (call $fail (global.get $msg_overflow))))))
+    (call $wrap (local.get $product)))
+
+  ;; How many times `step` has to be added to `val` to reach 0, running forever if it never does
+  (func $iterations (param $val i64) (param $step i64) (result i64)
+    (local $v i64) (local $s i64) (local $twos i64) (local $inverse i64) (local $i i32)
+    (if (global.get $CELL_TRAP)
+      (then
+        (if (i64.eqz (local.get $step))
+          (then (call $forever)))
+        (if (i32.or (i64.ne (i64.rem_s (local.get $val) (local.get $step)) (i64.const 0))
+            (i64.lt_s (i64.div_s (i64.sub (i64.const 0) (local.get $val)) (local.get $step)) (i64.const 0)))
+          (then (call $fail (global.get $msg_overflow))))
+        (return (i64.div_s (i64.sub (i64.const 0) (local.get $val)) (local.get $step)))))
+    (local.set $v (i64.and (i64.sub (i64.const 0) (local.get $val)) (global.get $CELL_MASK)))
+    (local.set $s (i64.and (local.get $step) (global.get $CELL_MASK)))
+    (if (i64.eqz (local.get $v))
+      (then (return (i64.const 0))))
+    ;; Solve s * n = v modulo the number of values, dividing both by the powers of 2 of s
+    (local.set $twos (i64.ctz (local.get $s)))
+    (if (i64.lt_u (i64.ctz (local.get $v)) (local.get $twos))
+      (then (call $forever)))
+    (local.set $v (i64.shr_u (local.get $v) (local.get $twos)))
+    (local.set $s (i64.shr_u (local.get $s) (local.get $twos)))
+    (local.set $inverse (local.get $s))
+    (loop
+      (local.set $inverse (i64.mul (local.get $inverse)
+        (i64.sub (i64.const 2) (i64.mul (local.get $s) (local.get $inverse)))))
+      (local.set $i (i32.add (local.get $i) (i32.const 1)))
+      (br_if 0 (i32.lt_u (local.get $i) (i32.const 5))))
+    (i64.and (i64.mul (local.get $v) (local.get $inverse))
+      (i64.shr_u (global.get $CELL_MASK) (local.get $twos))))
+
+  ;; Makes the memory reach the address `end`
+  (func $reserve (param $end i64)
+    (local $pages i64)
+    (local.set $pages (i64.shr_u (i64.add (local.get $end) (i64.const 65535)) (i64.const 16)))
+    (if (i64.gt_u (local.get $pages) (i64.extend_i32_u (memory.size)))
+      (then
+        (if (i64.gt_u (local.get $pages) (i64.const 65536))
+          (then (call $fail (global.get $msg_no_memory))))
+        (if (i32.eq (memory.grow (i32.sub (i32.wrap_i64 (local.get $pages)) (memory.size))) (i32.const -1))
+          (then (call $fail (global.get $msg_no_memory)))))))
+
+  ;; The address of the cells of the current array
+  (func $base (result i32)
+    (i32.add (global.get $tapes) (global.get $cells)))
+
+  ;; The address right after the current array, where memory in use ends
+  (func $end (result i64)
+    (i64.add (i64.extend_i32_u (call $base)) (i64.shl (i64.extend_i32_u (global.get $len)) (i64.const 3))))
+
+  ;; Returns the index of the cell `off` away from the pointer, growing the array if it has to
+  (func $reach (param $off i32) (result i32)
+    (local $i i64) (local $len i64) (local $n i64)
+    (local.set $i (i64.add (i64.extend_i32_u (global.get $pos)) (i64.extend_i32_s (local.get $off))))
+    (local.set $len (i64.extend_i32_u (global.get $len)))
+    (if (i32.and (i64.ge_s (local.get $i) (i64.const 0)) (i64.lt_s (local.get $i) (local.get $len)))
+      (then (return (i32.wrap_i64 (local.get $i)))))
+    (if (global.get $TAPE_BOUND)
+      (then (call $fail_n (global.get $msg_off_tape)
+        (select (i64.const -1) (local.get $len) (i64.lt_s (local.get $i) (i64.const 0))) (global.get $msg_none))))
+    (if (i64.ge_s (local.get $i) (i64.const 0))
+      (then
+        (local.set $n (i64.sub (call $max (i64.add (local.get $i) (i64.const 1)) (i64.shl (local.get $len) (i64.const 1)))
+          (local.get $len)))
+        (call $reserve (i64.add (call $end) (i64.shl (local.get $n) (i64.const 3))))
+        (memory.fill (i32.wrap_i64 (call $end)) (i32.const 0) (i32.wrap_i64 (i64.shl (local.get $n) (i64.const 3))))
+        (global.set $len (i32.wrap_i64 (i64.add (local.get $len) (local.get $n))))
+        (return (i32.wrap_i64 (local.get $i)))))
+    ;; The new cells come first, the ones there moving after them
+    (local.set $n (call $max (i64.sub (i64.const 0) (local.get $i)) (local.get $len)))
+    (call $reserve (i64.add (call $end) (i64.shl (local.get $n) (i64.const 3))))
+    (memory.copy (i32.add (call $base) (i32.wrap_i64 (i64.shl (local.get $n) (i64.const 3)))) (call $base)
+      (i32.wrap_i64 (i64.shl (local.get $len) (i64.const 3))))
+    (memory.fill (call $base) (i32.const 0) (i32.wrap_i64 (i64.shl (local.get $n) (i64.const 3))))
+    (global.set $len (i32.wrap_i64 (i64.add (local.get $len) (local.get $n))))
+    (global.set $pos (i32.add (global.get $pos) (i32.wrap_i64 (local.get $n))))
+    (global.set $origin (i32.add (global.get $origin) (i32.wrap_i64 (local.get $n))))
+    (i32.wrap_i64 (i64.add (local.get $i) (local.get $n))))
+
+  (func $max (param $a i64) (param $b i64) (result i64)
+    (select (local.get $a) (local.get $b) (i64.gt_s (local.get $a) (local.get $b))))
+
+  ;; The address of the cell `off` away from the pointer
+  (func $at (param $off i32) (result i32)
+    (local $i i32)
+    (local.set $i (call $reach (local.get $off)))
+    (i32.add (call $base) (i32.shl (local.get $i) (i32.const 3))))
+
+  (func $cur (result i64)
+    (i64.load (i32.add (call $base) (i32.shl (global.get $pos) (i32.const 3)))))
+
+  (func $add_at (param $off i32) (param $v i64)
+    (local $at i32)
+    (local.set $at (call $at (local.get $off)))
+    (i64.store (local.get $at) (call $add (i64.load (local.get $at)) (local.get $v))))
+
+  (func $set_at (param $off i32) (param $v i64)
+    (local $at i32)
+    (local.set $at (call $at (local.get $off)))
+    (i64.store (local.get $at) (call $wrap (local.get $v))))
+
+  (func $get_at (param $off i32) (result i64)
+    (i64.load (call $at (local.get $off))))
+
+  (func $move (param $off i32)
+    (global.set $pos (call $reach (local.get $off))))
+
+  (func $skip_move (param $off i32)
+    (block (loop
+      (br_if 1 (i64.eqz (call $cur)))
+      (call $move (local.get $off))
+      (br 0))))
+
+  (func $push (param $v i64)
+    (local $size i32)
+    (if (i32.eq (global.get $stack_len) (global.get $stack_cap))
+      (then
+        ;; The arrays move up to make room
+        (local.set $size (i32.shl (global.get $stack_cap) (i32.const 3)))
+        (call $reserve (i64.add (call $end) (i64.extend_i32_u (local.get $size))))
+        (memory.copy (i32.add (global.get $tapes) (local.get $size)) (global.get $tapes)
+          (i32.wrap_i64 (i64.sub (call $end) (i64.extend_i32_u (global.get $tapes)))))
+        (global.set $tapes (i32.add (global.get $tapes) (local.get $size)))
+        (global.set $stack_cap (i32.shl (global.get $stack_cap) (i32.const 1)))))
+    (i64.store (i32.add (global.get $STACK) (i32.shl (global.get $stack_len) (i32.const 3))) (local.get $v))
+    (global.set $stack_len (i32.add (global.get $stack_len) (i32.const 1))))
+
+  (func $peek_at (param $off i32)
+    (drop (call $at (local.get $off)))
+    (if (i32.eqz (global.get $stack_len))
+      (then (call $fail (global.get $msg_underflow))))
+    (call $add_at (local.get $off) (i64.load (i32.add (global.get $STACK)
+      (i32.shl (i32.sub (global.get $stack_len) (i32.const 1)) (i32.const 3))))))
+
+  ;; Pops `n` numbers, adding their sum
+  (func $pop_at (param $off i32) (param $n i64)
+    (local $sum i64)
+    (drop (call $at (local.get $off)))
+    (if (i64.gt_u (local.get $n) (i64.extend_i32_u (global.get $stack_len)))
+      (then (call $fail (global.get $msg_underflow))))
+    (block (loop
+      (br_if 1 (i64.eqz (local.get $n)))
+      (global.set $stack_len (i32.sub (global.get $stack_len) (i32.const 1)))
+      (local.set $sum (i64.add (local.get $sum)
+        (i64.load (i32.add (global.get $STACK) (i32.shl (global.get $stack_len) (i32.const 3))))))
+      (local.set $n (i64.sub (local.get $n) (i64.const 1)))
+      (br 0)))
+    (call $add_at (local.get $off) (local.get $sum)))
+
+  (func $push_at (param $off i32)
+    (call $push (call $get_at (local.get $off))))
+
+  (func $stack_len_at (param $off i32)
+    (call $set_at (local.get $off) (i64.extend_i32_u (global.get $stack_len))))
+
+  ;; Returns the next byte of the input, or -1 at its end
+  (func $byte (result i32)
+    (local $b i32)
+    (if (i32.ne (global.get $unread) (i32.const -2))
+      (then
+        (local.set $b (global.get $unread))
+        (global.set $unread (i32.const -2))
+        (return (local.get $b))))
+    (call $read))
+
+  ;; Returns the next character of the input, decoding UTF-8, or -1 at its end
+  (func $next_char (result i64)
+    (local $c i32) (local $extra i32) (local $d i32)
+    (local.set $c (call $byte))
+    (if (i32.lt_s (local.get $c) (i32.const 0))
+      (then (return (i64.const -1))))
+    (local.set $extra (i32.const -1))
+    (if (i32.lt_u (local.get $c) (i32.const 0xf8)) (then (local.set $extra (i32.const 3))))
+    (if (i32.lt_u (local.get $c) (i32.const 0xf0)) (then (local.set $extra (i32.const 2))))
+    (if (i32.lt_u (local.get $c) (i32.const 0xe0)) (then (local.set $extra (i32.const 1))))
+    (if (i32.lt_u (local.get $c) (i32.const 0xc0)) (then (local.set $extra (i32.const -1))))
+    (if (i32.lt_u (local.get $c) (i32.const 0x80)) (then (local.set $extra (i32.const 0))))
+    (if (i32.lt_s (local.get $extra) (i32.const 0))
+      (then (return (i64.const 0xfffd))))
+    (local.set $c (i32.and (local.get $c) (i32.shr_u (i32.const 0x7f) (local.get $extra))))
+    (block (loop
+      (br_if 1 (i32.eqz (local.get $extra)))
+      (local.set $d (call $byte))
+      (if (i32.ne (i32.and (local.get $d) (i32.const 0xc0)) (i32.const 0x80))
+        (then
+          (if (i32.ge_s (local.get $d) (i32.const 0))
+            (then (global.set $unread (local.get $d))))
+          (return (i64.const 0xfffd))))
+      (local.set $c (i32.or (i32.shl (local.get $c) (i32.const 6)) (i32.and (local.get $d) (i32.const 0x3f))))
+      (local.set $extra (i32.sub (local.get $extra) (i32.const 1)))
+      (br 0)))
+    (i64.extend_i32_u (local.get $c)))
+
+  (func $read_at (param $off i32)
+    (local $at i32) (local $ch i64)
+    (local.set $at (call $at (local.get $off)))
+    (local.set $ch (call $next_char))
+    (if (i64.lt_s (local.get $ch) (i64.const 0))
+      (then
+        (if (i64.eq (global.get $EOF_VALUE) (i64.const 1))
+          (then (return)))
+        (local.set $ch (global.get $EOF_VALUE))))
+    (i64.store (local.get $at) (call $wrap (local.get $ch))))
+
+  (func $write_at (param $off i32)
+    (i64.store8 (i32.const 24) (call $get_at (local.get $off)))
+    (call $write (i32.const 24) (i32.const 1)))
+
+  ;; Makes an array for a function called on a separate array the current one, copying the
+  ;; current number and the `args` after it
+  (func $enter (param $args i32)
+    (local $header i32) (local $len i32)
+    (if (i32.and (i32.ne (global.get $TAPE_BOUND) (i32.const 0)) (i32.ge_u (local.get $args) (global.get $TAPE_BOUND)))
+      (then (call $fail_n (global.get $msg_off_tape) (i64.extend_i32_u (global.get $TAPE_BOUND)) (global.get $msg_none))))
+    (drop (call $reach (local.get $args)))
+    (local.set $len (select (global.get $TAPE_BOUND)
+      (i32.shl (i32.add (local.get $args) (i32.const 1)) (i32.const 1)) (global.get $TAPE_BOUND)))
+    (local.set $header (i32.wrap_i64 (call $end)))
+    (call $reserve (i64.add (call $end) (i64.add (i64.const 16) (i64.shl (i64.extend_i32_u (local.get $len)) (i64.const 3)))))
+    (i32.store offset=0 (local.get $header) (global.get $cells))
+    (i32.store offset=4 (local.get $header) (global.get $len))
+    (i32.store offset=8 (local.get $header) (global.get $pos))
+    (i32.store offset=12 (local.get $header) (global.get $origin))
+    (memory.fill (i32.add (local.get $header) (i32.const 16)) (i32.const 0) (i32.shl (local.get $len) (i32.const 3)))
+    (memory.copy (i32.add (local.get $header) (i32.const 16))
+      (i32.add (call $base) (i32.shl (global.get $pos) (i32.const 3)))
+      (i32.shl (i32.add (local.get $args) (i32.const 1)) (i32.const 3)))
+    (global.set $cells (i32.sub (i32.add (local.get $header) (i32.const 16)) (global.get $tapes)))
+    (global.set $len (local.get $len))
+    (global.set $pos (i32.const 0))
+    (global.set $origin (i32.const 0)))
+
+  ;; Makes the array of the caller the current one again
+  (func $leave
+    (local $header i32)
+    (local.set $header (i32.sub (call $base) (i32.const 16)))
+    (global.set $cells (i32.load offset=0 (local.get $header)))
+    (global.set $len (i32.load offset=4 (local.get $header)))
+    (global.set $pos (i32.load offset=8 (local.get $header)))
+    (global.set $origin (i32.load offset=12 (local.get $header))))
+
+  ;; Checks that `n` is the number of a function, returning it
+  (func $check (param $n i64) (result i32)
+    (if (i64.lt_s (local.get $n) (i64.const 0))
+      (then (call $fail_n (global.get $msg_cannot_call) (local.get $n) (global.get $msg_none))))
+    (if (i64.ge_s (local.get $n) (i64.extend_i32_u (global.get $FUNCTIONS)))
+      (then (call $fail_n (global.get $msg_function) (local.get $n) (global.get $msg_not_defined))))
+    (i32.wrap_i64 (local.get $n)))
+
+  (func $call (param $n i64)
+    (call_indirect (type $op) (call $check (local.get $n))))
+
+  ;; The versions of the functions run on a separate array come after the others in the table
+  (func $call_separate (param $n i64) (param $args i32)
+    (local $fun i32)
+    (local.set $fun (call $check (local.get $n)))
+    (call $enter (local.get $args))
+    (call_indirect (type $op) (i32.add (local.get $fun) (global.get $FUNCTIONS)))
+    (call $leave))
+
+  (func $debug
+    (local $i i32) (local $v i64)
+    (block (loop
+      (br_if 1 (i32.ge_u (local.get $i) (global.get $len)))
+      (local.set $v (i64.load (i32.add (call $base) (i32.shl (local.get $i) (i32.const 3)))))
+      (if (i64.ne (local.get $v) (i64.const 0))
+        (then
+          (call $say_num (i64.sub (i64.extend_i32_u (local.get $i)) (i64.extend_i32_u (global.get $origin))))
+          (call $say (global.get $msg_colon))
+          (call $say_num (local.get $v))
+          (call $say (global.get $msg_newline))))
+      (local.set $i (i32.add (local.get $i) (i32.const 1)))
+      (br 0)))
+    (call $say (global.get $msg_pointer))
+    (call $say_num (i64.sub (i64.extend_i32_u (global.get $pos)) (i64.extend_i32_u (global.get $origin))))
+    (call $say (global.get $msg_newline))
+    (call $say (global.get $msg_stack_size))
+    (call $say_num (i64.extend_i32_u (global.get $stack_len)))
+    (call $say (global.get $msg_stack))
+    (local.set $i (i32.const 0))
+    (block (loop
+      (br_if 1 (i32.ge_u (local.get $i) (global.get $stack_len)))
+      (if (local.get $i)
+        (then (call $say (global.get $msg_comma))))
+      (call $say_num (i64.load (i32.add (global.get $STACK) (i32.shl (local.get $i) (i32.const 3)))))
+      (local.set $i (i32.add (local.get $i) (i32.const 1)))
+      (br 0)))
+    (call $say (global.get $msg_functions))
+    (call $say_num (i64.extend_i32_u (global.get $FUNCTIONS)))
+    (call $say (global.get $msg_newline)))
+"#;
+
+// Writes the functions of a module, keeping the bytes they write to be put in memory
+struct Emitter {
+    out: String,
+    depth: usize,
+    functions: usize,
+    data: Vec<u8>,
+}
+
+/// Returns a WebAssembly text module running `ops`, see `emit`
+pub fn program(ops: &[Operation], fh: &FunctionHolder, target: Target) -> String {
+    let functions = fh.no_functions();
+    let mut em = Emitter { out: String::new(), depth: 1, functions, data: Vec::new() };
+    let mut texts = String::new();
+    for &(name, text) in MESSAGES {
+        let at = em.bytes(text.as_bytes());
+        em.data.push(0);
+        writeln!(texts, "  (global ${} i32 (i32.const {}))", name, at).expect("writing to a string");
+    }
+    for n in 0..functions {
+        let fun = fh.get(n as i64).expect("stable function");
+        em.function(&format!("fun_{}", n), fun.ops());
+        em.function(&format!("fun_{}_separate", n), fun.separate_ops());
+    }
+    em.function("program", ops);
+    let (trap, mask) = match target.cells {
+        CellType::U8 => (0, 0xffi64),
+        CellType::U16 => (0, 0xffff),
+        CellType::U32 => (0, 0xffff_ffff),
+        CellType::I64 => (0, -1),
+        CellType::Trap => (1, -1),
+    };
+    let eof = match target.eof {
+        EofMode::Unchanged => 1,
+        EofMode::Zero => 0,
+        EofMode::MinusOne => -1,
+    };
+    let mut out = String::new();
+    let mut line = |code: &str| {
+        out.push_str(code);
+        out.push('\n');
+    };
+    line(";; Emitted by the Brainfuck interpreter");
+    line("(module");
+    line("  ;; Returns the next byte of the input, or -1 at its end");
+    line("  (import \"env\" \"read\" (func $read (result i32)))");
+    line("  ;; Writes `len` bytes of memory from `at` to the output");
+    line("  (import \"env\" \"write\" (func $write (param $at i32) (param $len i32)))");
+    line("  ;; Writes `len` bytes of memory from `at` to stderr, for debug information");
+    line("  (import \"env\" \"debug\" (func $write_debug (param $at i32) (param $len i32)))");
+    line("  ;; Takes the message of the runtime error stopping the program, which traps right after");
+    line("  (import \"env\" \"error\" (func $error (param $at i32) (param $len i32)))");
+    line("");
+    line(&format!("  (global $CELL_TRAP i32 (i32.const {}))", trap));
+    line(&format!("  (global $CELL_MASK i64 (i64.const {}))", mask));
+    line(&format!("  (global $TAPE_BOUND i32 (i32.const {}))", target.tape.unwrap_or(0)));
+    line("  ;; What reading stores at the end of the input, 1 leaving the cell unchanged");
+    line(&format!("  (global $EOF_VALUE i64 (i64.const {}))", eof));
+    line(&format!("  (global $FUNCTIONS i32 (i32.const {}))", functions));
+    line(&format!("  (global $STACK i32 (i32.const {}))", (TEXTS + em.data.len()).div_ceil(8) * 8));
+    out.push_str(&texts);
+    out.push_str(RUNTIME);
+    out.push_str(&em.out);
+    out.push('\n');
+    out.push_str(&format!("  (table {} funcref)\n", functions * 2));
+    if functions > 0 {
+        let table = |suffix| (0..functions).map(|n| format!(" $fun_{}{}", n, suffix)).collect::<String>();
+        out.push_str(&format!("  (elem (i32.const 0) func{}{})\n", table(""), table("_separate")));
+    }
+    out.push_str(&format!("  (data (i32.const {}) \"{}\"))\n", TEXTS, string(&em.data)));
+    out
+}
+
+impl Emitter {
+    // Appends a line, indented to the current depth
+    fn line(&mut self, code: &str) {
+        for _ in 0..self.depth {
+            self.out.push_str("  ");
+        }
+        self.out.push_str(code);
+        self.out.push('\n');
+    }
+    // Puts `bytes` in memory, returning their address
+    fn bytes(&mut self, bytes: &[u8]) -> usize {
+        self.data.extend_from_slice(bytes);
+        TEXTS + self.data.len() - bytes.len()
+    }
+    // Appends a function without arguments running `ops`
+    fn function(&mut self, name: &str, ops: &[Operation]) {
+        self.line("");
+        self.line(&format!("(func ${} (type $op)", name));
+        self.line("  (local $n i64)");
+        self.block(ops);
+        self.line(")");
+    }
+    // Appends `ops` one level deeper
+    fn block(&mut self, ops: &[Operation]) {
+        self.depth += 1;
+        for op in ops {
+            self.op(op);
+        }
+        self.depth -= 1;
+    }
+    fn op(&mut self, op: &Operation) {
+        match *op {
+            Add(off, i) => self.line(&format!("(call $add_at (i32.const {}) (i64.const {}))", off, i)),
+            Move(i) => self.line(&format!("(call $move (i32.const {}))", i)),
+            Set(off, i) => self.line(&format!("(call $set_at (i32.const {}) (i64.const {}))", off, i)),
+            MoveTo(ref lin) => {
+                self.line("(if (i64.ne (call $cur) (i64.const 0))");
+                self.line("  (then");
+                self.depth += 2;
+                self.line(&format!("(local.set $n (call $iterations (call $cur) (i64.const {})))", lin.step));
+                self.line("(call $set_at (i32.const 0) (i64.const 0))");
+                for &(off, val) in &lin.sets {
+                    self.line(&format!("(call $set_at (i32.const {}) (i64.const {}))", off, val));
+                }
+                for &(off, mult) in &lin.adds {
+                    self.line(&format!("(call $add_at (i32.const {}) (call $mul (local.get $n) (i64.const {})))", off, mult));
+                }
+                self.depth -= 2;
+                self.line("))");
+            }
+            SkipMove(i) => self.line(&format!("(call $skip_move (i32.const {}))", i)),
+            Read(off) => self.line(&format!("(call $read_at (i32.const {}))", off)),
+            Write(off) => self.line(&format!("(call $write_at (i32.const {}))", off)),
+            While(ref body) => {
+                self.line("(block (loop");
+                self.line("  (br_if 1 (i64.eqz (call $cur)))");
+                self.block(body);
+                self.line("  (br 0)))");
+            }
+            InsFuns => self.line("(call $set_at (i32.const 0) (i64.extend_i32_u (global.get $FUNCTIONS)))"),
+            CallFun => self.line("(call $call (call $cur))"),
+            CallFSep(args) => self.line(&format!("(call $call_separate (call $cur) (i32.const {}))", args as u32)),
+            Call(n) if n < self.functions => self.line(&format!("(call $fun_{})", n)),
+            Call(n) => self.line(&format!("(call $call (i64.const {}))", n)),
+            CallSep(n, args) => self.line(&format!("(call $call_separate (i64.const {}) (i32.const {}))", n, args as u32)),
+            Separate(args, ref body) => {
+                self.line(&format!("(call $enter (i32.const {}))", args as u32));
+                self.block(body);
+                self.line("(call $leave)");
+            }
+            Debug => self.line("(call $debug)"),
+            PeekStack(off) => self.line(&format!("(call $peek_at (i32.const {}))", off)),
+            PopStack(off, i) => self.line(&format!("(call $pop_at (i32.const {}) (i64.const {}))", off, i)),
+            PushStack(off) => self.line(&format!("(call $push_at (i32.const {}))", off)),
+            StackLen(off) => self.line(&format!("(call $stack_len_at (i32.const {}))", off)),
+            Restore(ref snap) => {
+                for &(off, val) in &snap.cells {
+                    self.line(&format!("(call $set_at (i32.const {}) (i64.const {}))", off, val));
+                }
+                for &val in &snap.stack {
+                    self.line(&format!("(call $push (i64.const {}))", val));
+                }
+                if !snap.output.is_empty() {
+                    let at = self.bytes(&snap.output);
+                    self.line(&format!("(call $write (i32.const {}) (i32.const {}))", at, snap.output.len()));
+                }
+                self.line(&format!("(call $move (i32.const {}))", snap.pointer));
+            }
+            EmptyOp => {}
+        }
+    }
+}
+
+// Returns the contents of a string holding `bytes`, escaping the ones which are not printable
+fn string(bytes: &[u8]) -> String {
+    let mut text = String::new();
+    for &byte in bytes {
+        if byte.is_ascii_graphic() && byte != b'"' && byte != b'\\' || byte == b' ' {
+            text.push(byte as char);
+        } else {
+            write!(text, "\\{:02x}", byte).expect("writing to a string");
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{program, string};
+    use dispatcher::FunctionHolder;
+    use dispatcher::Operation::*;
+    use emit::Target;
+    #[test]
+    fn bytes_are_escaped() {
+        assert_eq!(string(b"a \"b\"\\\n\xff"), "a \\22b\\22\\5c\\0a\\ff");
+    }
+    #[test]
+    fn programs_without_functions_have_an_empty_table() {
+        let code = program(&[Add(0, 1), Move(2)], &FunctionHolder::new(), Target::default());
+        assert!(code.contains("  (table 0 funcref)\n  (data"));
+        assert!(code.contains("    (call $add_at (i32.const 0) (i64.const 1))\n    (call $move (i32.const 2))\n"));
+    }
+}
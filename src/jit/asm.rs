@@ -0,0 +1,251 @@
+//! Encodes the few x86-64 instructions the generated code uses
+
+use std::convert::TryFrom;
+
+pub const RAX: u8 = 0;
+pub const RCX: u8 = 1;
+pub const RDX: u8 = 2;
+pub const RBX: u8 = 3;
+pub const RSP: u8 = 4;
+pub const RBP: u8 = 5;
+pub const RSI: u8 = 6;
+pub const RDI: u8 = 7;
+pub const R12: u8 = 12;
+pub const R13: u8 = 13;
+pub const R14: u8 = 14;
+pub const R15: u8 = 15;
+
+/// The condition of a conditional jump
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cond {
+    /// The last operation overflowed
+    Overflow = 0,
+    /// Unsigned lower
+    Below = 2,
+    /// Equal, or the result was 0
+    Equal = 4,
+    /// Not equal, or the result was not 0
+    NotEqual = 5,
+    /// The result was negative
+    Sign = 8,
+    /// The result was not negative
+    NotSign = 9,
+}
+
+/// A place in memory: `base + index * 8 + disp`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mem {
+    pub base: u8,
+    pub index: Option<u8>,
+    pub disp: i32,
+}
+
+/// A place in the code to jump to, bound once it is known
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Label(usize);
+
+/// Appends instructions to a buffer, keeping track of the jumps to labels
+#[derive(Default)]
+pub struct Asm {
+    code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    // The places of the 32 bit distances to fill in, and the labels they jump to
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Asm {
+    /// Creates an empty buffer
+    pub fn new() -> Asm {
+        Asm::default()
+    }
+    /// Returns the code, with the jumps filled in
+    ///
+    /// # Panics
+    /// Panics if a label jumped to was never bound.
+    pub fn finish(mut self) -> Vec<u8> {
+        for &(at, Label(n)) in &self.fixups {
+            let to = self.labels[n].expect("jump to a label which was never bound");
+            let rel = i32::try_from(to as i64 - (at as i64 + 4)).expect("jump too far");
+            self.code[at..at + 4].copy_from_slice(&rel.to_le_bytes());
+        }
+        self.code
+    }
+    /// Creates a label to be bound later
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+    /// Binds `label` to the next instruction
+    pub fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.code.len());
+    }
+    // Appends the REX prefix, if any is needed
+    fn rex(&mut self, wide: bool, reg: u8, index: u8, base: u8) {
+        let rex = 0x40 | (wide as u8) << 3 | (reg >> 3) << 2 | (index >> 3) << 1 | base >> 3;
+        if rex != 0x40 {
+            self.code.push(rex);
+        }
+    }
+    // Appends an instruction with a register and a register operand
+    fn op_reg(&mut self, wide: bool, opcode: &[u8], reg: u8, rm: u8) {
+        self.rex(wide, reg, 0, rm);
+        self.code.extend_from_slice(opcode);
+        self.code.push(0xc0 | (reg & 7) << 3 | rm & 7);
+    }
+    // Appends an instruction with a register and a memory operand, always through a SIB byte
+    // and a 32 bit displacement
+    fn op_mem(&mut self, wide: bool, opcode: &[u8], reg: u8, mem: Mem) {
+        let index = mem.index.unwrap_or(RSP);
+        self.rex(wide, reg, index, mem.base);
+        self.code.extend_from_slice(opcode);
+        self.code.push(0x84 | (reg & 7) << 3);
+        self.code.push(if mem.index.is_some() { 0xc0 } else { 0 } | (index & 7) << 3 | mem.base & 7);
+        self.code.extend_from_slice(&mem.disp.to_le_bytes());
+    }
+    fn imm32(&mut self, imm: i32) {
+        self.code.extend_from_slice(&imm.to_le_bytes());
+    }
+    /// `mov dst, src`
+    pub fn mov(&mut self, dst: u8, src: u8) {
+        self.op_reg(true, &[0x8b], dst, src);
+    }
+    /// `mov dst32, src32`, clearing the upper half of `dst`
+    pub fn mov32(&mut self, dst: u8, src: u8) {
+        self.op_reg(false, &[0x8b], dst, src);
+    }
+    /// `movsxd dst, src32`
+    pub fn movsxd(&mut self, dst: u8, src: u8) {
+        self.op_reg(true, &[0x63], dst, src);
+    }
+    /// `mov dst, imm`
+    pub fn mov_imm(&mut self, dst: u8, imm: i64) {
+        match i32::try_from(imm) {
+            Ok(imm) => {
+                self.op_reg(true, &[0xc7], 0, dst);
+                self.imm32(imm);
+            }
+            Err(_) => {
+                self.rex(true, 0, 0, dst);
+                self.code.push(0xb8 | dst & 7);
+                self.code.extend_from_slice(&imm.to_le_bytes());
+            }
+        }
+    }
+    /// `mov dst, [mem]`
+    pub fn load(&mut self, dst: u8, mem: Mem) {
+        self.op_mem(true, &[0x8b], dst, mem);
+    }
+    /// `mov [mem], src`
+    pub fn store(&mut self, mem: Mem, src: u8) {
+        self.op_mem(true, &[0x89], src, mem);
+    }
+    /// `mov qword [mem], imm`, sign extending `imm`
+    pub fn store_imm(&mut self, mem: Mem, imm: i32) {
+        self.op_mem(true, &[0xc7], 0, mem);
+        self.imm32(imm);
+    }
+    /// `add dst, src`
+    pub fn add(&mut self, dst: u8, src: u8) {
+        self.op_reg(true, &[0x03], dst, src);
+    }
+    /// `add dst, imm`
+    pub fn add_imm(&mut self, dst: u8, imm: i32) {
+        self.op_reg(true, &[0x81], 0, dst);
+        self.imm32(imm);
+    }
+    /// `sub dst, imm`
+    pub fn sub_imm(&mut self, dst: u8, imm: i32) {
+        self.op_reg(true, &[0x81], 5, dst);
+        self.imm32(imm);
+    }
+    /// `and dst, imm`, sign extending `imm`
+    pub fn and_imm(&mut self, dst: u8, imm: i32) {
+        self.op_reg(true, &[0x81], 4, dst);
+        self.imm32(imm);
+    }
+    /// `imul dst, src`
+    pub fn imul(&mut self, dst: u8, src: u8) {
+        self.op_reg(true, &[0x0f, 0xaf], dst, src);
+    }
+    /// `imul dst, src, imm`
+    pub fn imul_imm(&mut self, dst: u8, src: u8, imm: i32) {
+        self.op_reg(true, &[0x69], dst, src);
+        self.imm32(imm);
+    }
+    /// `neg dst`
+    pub fn neg(&mut self, dst: u8) {
+        self.op_reg(true, &[0xf7], 3, dst);
+    }
+    /// `cmp a, b`
+    pub fn cmp(&mut self, a: u8, b: u8) {
+        self.op_reg(true, &[0x3b], a, b);
+    }
+    /// `cmp qword [mem], imm`
+    pub fn cmp_mem_imm(&mut self, mem: Mem, imm: i32) {
+        self.op_mem(true, &[0x81], 7, mem);
+        self.imm32(imm);
+    }
+    /// `test a, b`
+    pub fn test(&mut self, a: u8, b: u8) {
+        self.op_reg(true, &[0x85], b, a);
+    }
+    /// Jumps to `to` if `cond` holds
+    pub fn jump_if(&mut self, cond: Cond, to: Label) {
+        self.code.extend_from_slice(&[0x0f, 0x80 | cond as u8]);
+        self.fixup(to);
+    }
+    /// Jumps to `to`
+    pub fn jump(&mut self, to: Label) {
+        self.code.push(0xe9);
+        self.fixup(to);
+    }
+    fn fixup(&mut self, to: Label) {
+        self.fixups.push((self.code.len(), to));
+        self.imm32(0);
+    }
+    /// `call [mem]`
+    pub fn call_mem(&mut self, mem: Mem) {
+        self.op_mem(false, &[0xff], 2, mem);
+    }
+    /// `push reg`
+    pub fn push(&mut self, reg: u8) {
+        self.rex(false, 0, 0, reg);
+        self.code.push(0x50 | reg & 7);
+    }
+    /// `pop reg`
+    pub fn pop(&mut self, reg: u8) {
+        self.rex(false, 0, 0, reg);
+        self.code.push(0x58 | reg & 7);
+    }
+    /// `ret`
+    pub fn ret(&mut self) {
+        self.code.push(0xc3);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn encodings() {
+        let mut asm = Asm::new();
+        asm.mov(RAX, R12);
+        asm.load(RCX, Mem { base: RBX, index: Some(R12), disp: 0 });
+        asm.store_imm(Mem { base: R15, index: None, disp: 16 }, -1);
+        asm.mov_imm(RDX, 1 << 40);
+        asm.push(R15);
+        assert_eq!(asm.finish(), vec![0x49, 0x8b, 0xc4, 0x4a, 0x8b, 0x8c, 0xe3, 0, 0, 0, 0,
+            0x49, 0xc7, 0x84, 0x27, 16, 0, 0, 0, 0xff, 0xff, 0xff, 0xff,
+            0x48, 0xba, 0, 0, 0, 0, 0, 1, 0, 0, 0x41, 0x57]);
+    }
+    #[test]
+    fn jumps_are_filled_in() {
+        let mut asm = Asm::new();
+        let (back, ahead) = (asm.label(), asm.label());
+        asm.bind(back);
+        asm.jump_if(Cond::Equal, ahead);
+        asm.jump(back);
+        asm.bind(ahead);
+        assert_eq!(asm.finish(), vec![0x0f, 0x84, 5, 0, 0, 0, 0xe9, 0xf5, 0xff, 0xff, 0xff]);
+    }
+}
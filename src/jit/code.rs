@@ -0,0 +1,601 @@
+//! Generates the machine code of a sequence of operations, and the helpers it calls back
+
+use bytecode::Machine;
+use dispatcher::operation::*;
+use jit::asm::*;
+use jit::memory::Executable;
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::mem;
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+
+// Where the code finds the fields of the `State`
+const BASE: i32 = 0;
+const LEN: i32 = 8;
+const POS: i32 = 16;
+const REACH: i32 = 24;
+const MOVE: i32 = 32;
+const EXECUTE: i32 = 40;
+const CALL: i32 = 48;
+const SEPARATE: i32 = 56;
+
+// What the code returns: the error of a failed helper is kept in the `State`
+const OK: i64 = 0;
+const FAILED: i64 = 1;
+const OVERFLOW: i64 = 2;
+
+// How many calls the code makes into itself before the deeper ones are left to a `Machine`,
+// which keeps its frames off the stack
+const DEPTH: usize = 256;
+
+// The registers holding the state while the code runs; the helpers keep them
+const STATE: u8 = R15;
+const CELLS: u8 = RBX;
+const POINTER: u8 = R12;
+const SIZE: u8 = R13;
+// The number of iterations of a linear loop, and what it adds to a cell
+const COUNT: u8 = R14;
+const PRODUCT: u8 = RBP;
+
+fn field(disp: i32) -> Mem {
+    Mem { base: STATE, index: None, disp }
+}
+
+const CURRENT: Mem = Mem { base: CELLS, index: Some(POINTER), disp: 0 };
+
+/// Everything the code and the helpers it calls work on, laid out as the code expects
+#[repr(C)]
+pub struct State<'a, R: 'a, W: 'a> {
+    // The cells of the current array, their number and the index of the pointer, copied
+    // from the array when the code starts and after each helper, which can move them
+    base: *mut i64,
+    len: usize,
+    pos: usize,
+    reach: extern "C" fn(*mut State<'a, R, W>, i64) -> i64,
+    move_by: extern "C" fn(*mut State<'a, R, W>, i64) -> i64,
+    execute: extern "C" fn(*mut State<'a, R, W>, usize) -> i64,
+    call: extern "C" fn(*mut State<'a, R, W>, usize) -> i64,
+    separate: extern "C" fn(*mut State<'a, R, W>, usize) -> i64,
+    ctx: &'a mut Context<R, W>,
+    funs: &'a mut Functions,
+    // The error of the helper that failed
+    error: Option<RuntimeError>,
+    // The number of calls being run natively
+    depth: usize,
+}
+
+impl<'a, R: Read, W: Write> State<'a, R, W> {
+    /// Creates the state for running code on `ctx`, compiling the functions into `funs`
+    pub fn new(ctx: &'a mut Context<R, W>, funs: &'a mut Functions) -> State<'a, R, W> {
+        State {
+            base: ptr::null_mut(),
+            len: 0,
+            pos: 0,
+            reach: reach::<R, W>,
+            move_by: move_by::<R, W>,
+            execute: execute::<R, W>,
+            call: call::<R, W>,
+            separate: separate::<R, W>,
+            ctx,
+            funs,
+            error: None,
+            depth: 0,
+        }
+    }
+    // Copies where the cells are from the array
+    fn load(&mut self) {
+        let (base, len, pos) = self.ctx.ah.raw_parts();
+        self.base = base;
+        self.len = len;
+        self.pos = pos;
+    }
+    // Finishes a helper: the code continues with the array as the helper left it
+    fn finish(&mut self, res: Result<(), RuntimeError>) -> i64 {
+        self.load();
+        match res {
+            Ok(()) => OK,
+            Err(e) => {
+                self.error = Some(e);
+                FAILED
+            }
+        }
+    }
+}
+
+// The helpers the code calls, each starting from the pointer the code left in the state
+
+extern "C" fn reach<R: Read, W: Write>(state: *mut State<R, W>, off: i64) -> i64 {
+    let state = unsafe { &mut *state };
+    state.ctx.ah.set_index(state.pos);
+    match state.ctx.ah.get_at(off as i32) {
+        Ok(_) => {
+            state.load();
+            state.pos as i64 + off
+        }
+        Err(e) => {
+            state.finish(Err(e));
+            -1
+        }
+    }
+}
+
+extern "C" fn move_by<R: Read, W: Write>(state: *mut State<R, W>, off: i64) -> i64 {
+    let state = unsafe { &mut *state };
+    state.ctx.ah.set_index(state.pos);
+    let res = state.ctx.ah.move_r(off as i32);
+    state.finish(res)
+}
+
+extern "C" fn execute<R: Read, W: Write>(state: *mut State<R, W>, op: usize) -> i64 {
+    let (state, op) = unsafe { (&mut *state, &*(op as *const Operation)) };
+    state.ctx.ah.set_index(state.pos);
+    let res = op.execute(&mut *state.ctx);
+    state.finish(res)
+}
+
+extern "C" fn call<R: Read, W: Write>(state: *mut State<R, W>, op: usize) -> i64 {
+    let (state, op) = unsafe { (&mut *state, &*(op as *const Operation)) };
+    state.ctx.ah.set_index(state.pos);
+    if state.depth >= DEPTH {
+        let res = Machine::new().run(state.ctx, slice::from_ref(op));
+        return state.finish(res);
+    }
+    state.depth += 1;
+    let res = match *op {
+        CallFun => {
+            let n = state.ctx.ah.get();
+            call_fun(state, op, n, None)
+        }
+        CallFSep(args) => {
+            let n = state.ctx.ah.get();
+            call_fun(state, op, n, Some(args))
+        }
+        Call(n) => call_fun(state, op, n as i64, None),
+        CallSep(n, args) => call_fun(state, op, n as i64, Some(args)),
+        _ => unreachable!("only calls are compiled to calls"),
+    };
+    state.depth -= 1;
+    state.finish(res)
+}
+
+extern "C" fn separate<R: Read, W: Write>(state: *mut State<R, W>, body: usize) -> i64 {
+    let (state, body) = unsafe { (&mut *state, &*(body as *const Body)) };
+    state.ctx.ah.set_index(state.pos);
+    let res = run_separate(state, body.args, &body.code);
+    state.finish(res)
+}
+
+// Runs the function number `n` called by `op`, on a separate array with `args` copied if
+// there are any, or runs `op` on a `Machine` if the function could not be compiled
+fn call_fun<R: Read, W: Write>(state: &mut State<R, W>, op: &Operation, n: i64, args: Option<usize>) -> Result<(), RuntimeError> {
+    let native = match state.funs.get(&state.ctx.fh, n, state.ctx.cells())? {
+        Some(native) => native,
+        None => return Machine::new().run(state.ctx, slice::from_ref(op)),
+    };
+    match args {
+        Some(args) => {
+            let code = native.separate.clone();
+            run_separate(state, args, &code)
+        }
+        None => {
+            let code = native.ops.clone();
+            code.run(state)
+        }
+    }
+}
+
+// Runs `code` on a separate array, putting the current one back even if it fails
+fn run_separate<R: Read, W: Write>(state: &mut State<R, W>, args: usize, code: &Compiled) -> Result<(), RuntimeError> {
+    let ah = state.ctx.separate_array(args)?;
+    let caller = mem::replace(&mut state.ctx.ah, ah);
+    let res = code.run(state);
+    state.ctx.ah = caller;
+    res
+}
+
+/// Operations compiled to machine code, with the ones it runs through the helpers
+pub struct Compiled {
+    code: Executable,
+    // Only kept for the code, which points to them: boxed so they never move
+    #[allow(dead_code, clippy::vec_box)]
+    ops: Vec<Box<Operation>>,
+    #[allow(dead_code, clippy::vec_box)]
+    bodies: Vec<Box<Body>>,
+}
+
+// The body of a function inlined on a separate array
+struct Body {
+    args: usize,
+    code: Compiled,
+}
+
+impl Compiled {
+    /// Compiles `ops` for cells of type `cells`, or returns `None` if the system does not
+    /// give executable memory for the code
+    pub fn new(ops: &[Operation], cells: CellType) -> Option<Compiled> {
+        let mut gen = Gen { asm: Asm::new(), cells, ops: Vec::new(), bodies: Vec::new(), settles: Vec::new() };
+        let code = gen.function(ops);
+        Some(Compiled {
+            code: Executable::new(&code)?,
+            ops: gen.ops,
+            bodies: gen.bodies,
+        })
+    }
+    /// Runs the code on the context of `state`
+    pub fn run<R: Read, W: Write>(&self, state: &mut State<R, W>) -> Result<(), RuntimeError> {
+        state.load();
+        let status = unsafe {
+            let entry: extern "C" fn(*mut State<R, W>) -> i64 = mem::transmute(self.code.as_ptr());
+            entry(state)
+        };
+        state.ctx.ah.set_index(state.pos);
+        match status {
+            OK => Ok(()),
+            OVERFLOW => Err(RuntimeError::Overflow),
+            _ => Err(state.error.take().expect("failed helper without an error")),
+        }
+    }
+}
+
+// Both versions of a function, compiled the first time it is called
+struct Native {
+    fun: Arc<Function>,
+    cells: CellType,
+    ops: Arc<Compiled>,
+    separate: Arc<Compiled>,
+}
+
+/// The functions compiled so far
+#[derive(Default)]
+pub struct Functions {
+    funs: Vec<Option<Native>>,
+}
+
+impl Functions {
+    // Returns the function number `n` of `fh`, compiling it if it was not called before
+    // with cells of the same type, or `None` if it could not be compiled
+    fn get(&mut self, fh: &FunctionHolder, n: i64, cells: CellType) -> Result<Option<&Native>, RuntimeError> {
+        let fun = fh.get(n)?;
+        let n = n as usize;
+        if self.funs.len() <= n {
+            self.funs.resize_with(n + 1, || None);
+        }
+        let fresh = match self.funs[n] {
+            Some(ref native) => !Arc::ptr_eq(&native.fun, &fun) || native.cells != cells,
+            None => true,
+        };
+        if fresh {
+            let ops = match Compiled::new(fun.ops(), cells) {
+                Some(ops) => Arc::new(ops),
+                None => return Ok(None),
+            };
+            let separate = if fun.separate_ops() == fun.ops() {
+                ops.clone()
+            } else {
+                match Compiled::new(fun.separate_ops(), cells) {
+                    Some(separate) => Arc::new(separate),
+                    None => return Ok(None),
+                }
+            };
+            self.funs[n] = Some(Native { fun, cells, ops, separate });
+        }
+        Ok(self.funs[n].as_ref())
+    }
+}
+
+// Generates the code of a sequence of operations
+struct Gen {
+    asm: Asm,
+    cells: CellType,
+    #[allow(clippy::vec_box)]
+    ops: Vec<Box<Operation>>,
+    #[allow(clippy::vec_box)]
+    bodies: Vec<Box<Body>>,
+    // The exits moving the pointer to a cell away from it before they fail, with the offset,
+    // where they continue and whether the cell is off a bounded array
+    settles: Vec<(Label, i32, Label, bool)>,
+}
+
+// The labels every operation can jump to when it fails
+#[derive(Clone, Copy)]
+struct Exits {
+    failed: Label,
+    overflow: Label,
+}
+
+impl Gen {
+    // Returns a function running `ops` on the state it is given
+    fn function(&mut self, ops: &[Operation]) -> Vec<u8> {
+        let exits = Exits { failed: self.asm.label(), overflow: self.asm.label() };
+        let exit = self.asm.label();
+        // Six registers and the return address keep the stack aligned for the helpers
+        for &reg in &[RBX, RBP, POINTER, SIZE, COUNT, STATE] {
+            self.asm.push(reg);
+        }
+        self.asm.sub_imm(RSP, 8);
+        self.asm.mov(STATE, RDI);
+        self.reload();
+        for op in ops {
+            self.op(op, exits);
+        }
+        self.asm.mov_imm(RAX, OK);
+        self.asm.bind(exit);
+        self.asm.store(field(POS), POINTER);
+        self.asm.add_imm(RSP, 8);
+        for &reg in &[STATE, COUNT, SIZE, POINTER, RBP, RBX] {
+            self.asm.pop(reg);
+        }
+        self.asm.ret();
+        self.asm.bind(exits.failed);
+        self.asm.mov_imm(RAX, FAILED);
+        self.asm.jump(exit);
+        self.asm.bind(exits.overflow);
+        self.asm.mov_imm(RAX, OVERFLOW);
+        self.asm.jump(exit);
+        for (label, off, to, off_tape) in mem::take(&mut self.settles) {
+            self.asm.bind(label);
+            if off_tape {
+                // Fails again, stopping at the end of the array
+                self.helper(MOVE, i64::from(off));
+            } else {
+                self.asm.add_imm(POINTER, off);
+            }
+            self.asm.jump(to);
+        }
+        mem::replace(&mut self.asm, Asm::new()).finish()
+    }
+    // Loads where the cells are from the state
+    fn reload(&mut self) {
+        self.asm.load(CELLS, field(BASE));
+        self.asm.load(SIZE, field(LEN));
+        self.asm.load(POINTER, field(POS));
+    }
+    // Calls the helper in the field `helper` of the state with `arg`, returning its result in RAX
+    fn helper(&mut self, helper: i32, arg: i64) {
+        self.asm.store(field(POS), POINTER);
+        self.asm.mov(RDI, STATE);
+        self.asm.mov_imm(RSI, arg);
+        self.asm.call_mem(field(helper));
+        self.reload();
+    }
+    // Calls a helper running `op`, failing if it does
+    fn helper_op(&mut self, helper: i32, op: &Operation, exits: Exits) {
+        let op = Box::new(op.clone());
+        let arg = &*op as *const Operation as i64;
+        self.ops.push(op);
+        self.helper(helper, arg);
+        self.asm.test(RAX, RAX);
+        self.asm.jump_if(Cond::NotEqual, exits.failed);
+    }
+    // Returns the cell `off` away from the pointer, growing the array if it has to; uses RAX
+    fn cell(&mut self, off: i32, exits: Exits) -> Mem {
+        if off == 0 {
+            return CURRENT;
+        }
+        let inside = self.asm.label();
+        self.asm.mov(RAX, POINTER);
+        self.asm.add_imm(RAX, off);
+        self.asm.cmp(RAX, SIZE);
+        self.asm.jump_if(Cond::Below, inside);
+        self.helper(REACH, i64::from(off));
+        self.asm.test(RAX, RAX);
+        self.asm.jump_if(Cond::Sign, exits.failed);
+        self.asm.bind(inside);
+        Mem { base: CELLS, index: Some(RAX), disp: 0 }
+    }
+    // Returns the exits of an operation on the cell `off` away from the pointer, which move the
+    // pointer to that cell, or to the end of the array on the way to it, as the interpreter does
+    fn on_cell(&mut self, off: i32, exits: Exits) -> Exits {
+        if off == 0 {
+            return exits;
+        }
+        let (failed, overflow) = (self.asm.label(), self.asm.label());
+        self.settles.push((failed, off, exits.failed, true));
+        self.settles.push((overflow, off, exits.overflow, false));
+        Exits { failed, overflow }
+    }
+    // Brings `reg` in the range of the cells after an addition or multiplication; uses RDX
+    fn wrap(&mut self, reg: u8, exits: Exits) {
+        match self.cells {
+            CellType::U8 => self.asm.and_imm(reg, 0xff),
+            CellType::U16 => self.asm.and_imm(reg, 0xffff),
+            CellType::U32 => self.asm.mov32(reg, reg),
+            CellType::I64 => {}
+            CellType::Trap => {
+                self.asm.jump_if(Cond::Overflow, exits.overflow);
+                self.asm.movsxd(RDX, reg);
+                self.asm.cmp(RDX, reg);
+                self.asm.jump_if(Cond::NotEqual, exits.overflow);
+            }
+        }
+    }
+    fn set(&mut self, off: i32, val: i64, exits: Exits) {
+        let cell = self.cell(off, exits);
+        match self.cells.wrap(val) {
+            Ok(val) => match i32::try_from(val) {
+                Ok(val) => self.asm.store_imm(cell, val),
+                Err(_) => {
+                    self.asm.mov_imm(RCX, val);
+                    self.asm.store(cell, RCX);
+                }
+            },
+            Err(_) => self.asm.jump(exits.overflow),
+        }
+    }
+    fn move_by(&mut self, i: i32, exits: Exits) {
+        let inside = self.asm.label();
+        self.asm.add_imm(POINTER, i);
+        self.asm.cmp(POINTER, SIZE);
+        self.asm.jump_if(Cond::Below, inside);
+        self.asm.sub_imm(POINTER, i);
+        self.helper(MOVE, i64::from(i));
+        self.asm.test(RAX, RAX);
+        self.asm.jump_if(Cond::NotEqual, exits.failed);
+        self.asm.bind(inside);
+    }
+    // Runs the loop while the current cell is not 0, with `body` generating its iterations
+    fn repeat<F: FnMut(&mut Gen)>(&mut self, mut body: F) {
+        let (start, end) = (self.asm.label(), self.asm.label());
+        self.asm.cmp_mem_imm(CURRENT, 0);
+        self.asm.jump_if(Cond::Equal, end);
+        self.asm.bind(start);
+        body(self);
+        self.asm.cmp_mem_imm(CURRENT, 0);
+        self.asm.jump_if(Cond::NotEqual, start);
+        self.asm.bind(end);
+    }
+    // Runs the linear loop `op` which adds or subtracts 1 each iteration, so it runs as many
+    // times as the value of the cell says
+    fn linear_loop(&mut self, op: &Operation, lin: &LinearLoop, exits: Exits) {
+        let end = self.asm.label();
+        self.asm.load(COUNT, CURRENT);
+        self.asm.test(COUNT, COUNT);
+        self.asm.jump_if(Cond::Equal, end);
+        if lin.step == 1 {
+            self.asm.neg(COUNT);
+        }
+        if self.cells == CellType::Trap {
+            // Going away from 0 overflows before the loop ends, after the iterations left to
+            // the helper which keep the cell in range
+            let ends = self.asm.label();
+            self.asm.test(COUNT, COUNT);
+            self.asm.jump_if(Cond::NotSign, ends);
+            self.helper_op(EXECUTE, op, exits);
+            self.asm.jump(end);
+            self.asm.bind(ends);
+        }
+        self.asm.store_imm(CURRENT, 0);
+        for &(off, val) in &lin.sets {
+            self.set(off, val, exits);
+        }
+        for &(off, mult) in &lin.adds {
+            match i32::try_from(mult) {
+                Ok(mult) => self.asm.imul_imm(PRODUCT, COUNT, mult),
+                Err(_) => {
+                    self.asm.mov_imm(PRODUCT, mult);
+                    self.asm.imul(PRODUCT, COUNT);
+                }
+            }
+            self.wrap(PRODUCT, exits);
+            let cell = self.cell(off, exits);
+            self.asm.load(RCX, cell);
+            self.asm.add(RCX, PRODUCT);
+            self.wrap(RCX, exits);
+            self.asm.store(cell, RCX);
+        }
+        self.asm.bind(end);
+    }
+    fn op(&mut self, op: &Operation, exits: Exits) {
+        match *op {
+            Add(off, i) => {
+                let exits = self.on_cell(off, exits);
+                let cell = self.cell(off, exits);
+                self.asm.load(RCX, cell);
+                match i32::try_from(i) {
+                    Ok(i) => self.asm.add_imm(RCX, i),
+                    Err(_) => {
+                        self.asm.mov_imm(RDX, i);
+                        self.asm.add(RCX, RDX);
+                    }
+                }
+                self.wrap(RCX, exits);
+                self.asm.store(cell, RCX);
+            }
+            Move(i) => self.move_by(i, exits),
+            Set(off, i) => {
+                let exits = self.on_cell(off, exits);
+                self.set(off, i, exits)
+            }
+            While(ref body) => self.repeat(|gen| {
+                for op in body {
+                    gen.op(op, exits);
+                }
+            }),
+            SkipMove(i) => self.repeat(|gen| gen.move_by(i, exits)),
+            MoveTo(ref lin) if lin.step == 1 || lin.step == -1 => self.linear_loop(op, lin, exits),
+            CallFun | CallFSep(_) | Call(_) | CallSep(..) => self.helper_op(CALL, op, exits),
+            Separate(args, ref body) => match Compiled::new(body, self.cells) {
+                Some(code) => {
+                    let body = Box::new(Body { args, code });
+                    let arg = &*body as *const Body as i64;
+                    self.bodies.push(body);
+                    self.helper(SEPARATE, arg);
+                    self.asm.test(RAX, RAX);
+                    self.asm.jump_if(Cond::NotEqual, exits.failed);
+                }
+                // Left to the interpreter if there is no executable memory for the body
+                None => self.helper_op(EXECUTE, op, exits),
+            },
+            EmptyOp => {}
+            MoveTo(_) | Read(_) | Write(_) | InsFuns | Debug | PeekStack(_) | PopStack(..) | PushStack(_) |
+            StackLen(_) | Restore(_) => self.helper_op(EXECUTE, op, exits),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn fields_are_where_the_code_expects() {
+        let mut ctx = Context::new("".as_bytes(), Vec::new());
+        let mut funs = Functions::default();
+        let state = State::new(&mut ctx, &mut funs);
+        let start = &state as *const State<_, _> as usize;
+        let offset = |field: usize| (field - start) as i32;
+        assert_eq!(offset(&state.base as *const _ as usize), BASE);
+        assert_eq!(offset(&state.len as *const _ as usize), LEN);
+        assert_eq!(offset(&state.pos as *const _ as usize), POS);
+        assert_eq!(offset(&state.reach as *const _ as usize), REACH);
+        assert_eq!(offset(&state.move_by as *const _ as usize), MOVE);
+        assert_eq!(offset(&state.execute as *const _ as usize), EXECUTE);
+        assert_eq!(offset(&state.call as *const _ as usize), CALL);
+        assert_eq!(offset(&state.separate as *const _ as usize), SEPARATE);
+    }
+    #[test]
+    fn cells_wrap_and_trap() {
+        let ops = [Add(0, 250), Add(0, 10), Write(0), Set(1, -1), While(vec![Add(0, -1), Add(2, 3)]), Write(2)];
+        let mut ctx = Context::new("".as_bytes(), Vec::new());
+        ctx.set_cells(CellType::U8);
+        let mut funs = Functions::default();
+        Compiled::new(&ops, CellType::U8).unwrap().run(&mut State::new(&mut ctx, &mut funs)).unwrap();
+        assert_eq!(ctx.io.output(), &[4, 12]);
+        assert_eq!(ctx.ah.values(), vec![(1, 255), (2, 12)]);
+        let mut ctx = Context::new("".as_bytes(), Vec::new());
+        let res = Compiled::new(&[Set(0, i64::from(i32::MAX)), Add(0, 1)], CellType::Trap).unwrap()
+            .run(&mut State::new(&mut ctx, &mut funs));
+        assert_eq!(res, Err(RuntimeError::Overflow));
+        assert_eq!(ctx.ah.get(), i64::from(i32::MAX));
+        // Overflowing a cell away from the pointer leaves the pointer on it
+        let res = Compiled::new(&[Add(3, 1), Set(2, i64::MAX)], CellType::Trap).unwrap().run(&mut State::new(&mut ctx, &mut funs));
+        assert_eq!(res, Err(RuntimeError::Overflow));
+        assert_eq!((ctx.ah.pointer(), ctx.ah.values()), (2, vec![(0, i64::from(i32::MAX)), (3, 1)]));
+    }
+    #[test]
+    fn helpers_grow_the_arrays_and_call_functions() {
+        let mut ctx = Context::new("".as_bytes(), Vec::new());
+        ctx.fh.add_temp(vec![Move(-3000), Add(0, 2), Write(0), Move(3000), Write(1)]);
+        ctx.fh.push_funs();
+        let mut funs = Functions::default();
+        let ops = [Add(2000, 7), Set(0, 5), Set(1, 9), CallSep(0, 1), Call(0)];
+        Compiled::new(&ops, CellType::Trap).unwrap().run(&mut State::new(&mut ctx, &mut funs)).unwrap();
+        assert_eq!(ctx.io.output(), &[2, 9, 2, 9]);
+        assert_eq!(ctx.ah.values(), vec![(-3000, 2), (0, 5), (1, 9), (2000, 7)]);
+        ctx.set_tape(Some(10));
+        let res = Compiled::new(&[Add(0, 1), CallSep(0, 0)], CellType::Trap).unwrap().run(&mut State::new(&mut ctx, &mut funs));
+        assert_eq!(res, Err(RuntimeError::OutOfTape(-1)));
+        assert_eq!(ctx.ah.values(), vec![(0, 1)]);
+    }
+    #[test]
+    fn deep_recursion_is_left_to_the_machine() {
+        let mut ctx = Context::new("".as_bytes(), Vec::new());
+        ctx.fh.add_temp(vec![Add(0, -1), While(vec![Call(0)])]);
+        ctx.fh.push_funs();
+        let mut funs = Functions::default();
+        let ops = [Set(0, 200_000), Call(0), Add(0, 1)];
+        Compiled::new(&ops, CellType::Trap).unwrap().run(&mut State::new(&mut ctx, &mut funs)).unwrap();
+        assert_eq!(ctx.ah.values(), vec![(0, 1)]);
+    }
+}
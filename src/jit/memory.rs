@@ -0,0 +1,59 @@
+//! Memory the generated code is copied to and run from
+
+use std::os::raw::{c_int, c_long, c_void};
+use std::ptr;
+
+const PROT_READ: c_int = 1;
+const PROT_WRITE: c_int = 2;
+const PROT_EXEC: c_int = 4;
+const MAP_PRIVATE: c_int = 2;
+const MAP_ANONYMOUS: c_int = 0x20;
+
+extern "C" {
+    fn mmap(addr: *mut c_void, len: usize, prot: c_int, flags: c_int, fd: c_int, offset: c_long) -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: c_int) -> c_int;
+    fn munmap(addr: *mut c_void, len: usize) -> c_int;
+}
+
+/// Machine code in memory which can be executed but not written
+pub struct Executable {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// The memory is only written before it becomes executable, and owned by a single value
+unsafe impl Send for Executable {}
+unsafe impl Sync for Executable {}
+
+impl Executable {
+    /// Copies `code` to new memory and makes it executable
+    ///
+    /// Returns `None` if the system does not give executable memory.
+    pub fn new(code: &[u8]) -> Option<Executable> {
+        let len = code.len().max(1);
+        unsafe {
+            let ptr = mmap(ptr::null_mut(), len, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+            if ptr as isize == -1 {
+                return None;
+            }
+            let mem = Executable { ptr: ptr as *mut u8, len };
+            ptr::copy_nonoverlapping(code.as_ptr(), mem.ptr, code.len());
+            if mprotect(ptr, len, PROT_READ | PROT_EXEC) != 0 {
+                return None;
+            }
+            Some(mem)
+        }
+    }
+    /// Returns the address of the first instruction
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr
+    }
+}
+
+impl Drop for Executable {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.ptr as *mut c_void, self.len);
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Compiles the operations to x86-64 machine code and runs it.
+//!
+//! The code keeps the array in registers and reaches the cells directly, running loops,
+//! additions, sets, moves and the linear loops counting down by 1 without leaving it.
+//! Everything else, the stack, function calls, input and output and making the array
+//! grow, calls back into Rust helpers which run it on the `Context`.
+//!
+//! Only available on x86-64 Linux; see `Jit::is_supported`.
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod asm;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod code;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod memory;
+
+use bytecode::Machine;
+use dispatcher::operation::*;
+use std::io::{Read, Write};
+
+/// Runs operations compiled to machine code on a `Context`
+///
+/// Functions are compiled the first time they are called and kept. Calls recurse on the
+/// stack of the interpreter until they are a few hundred deep, then the deeper ones run on a
+/// `bytecode::Machine`, and there is no fuel: runs limited by fuel are left to the interpreter.
+///
+/// # Examples
+/// ```
+/// use Brainfuck::dispatcher::Context;
+/// use Brainfuck::dispatcher::Operation::*;
+/// use Brainfuck::jit::Jit;
+///
+/// let mut ctx = Context::new("".as_bytes(), Vec::new());
+/// let mut jit = Jit::new();
+/// jit.run(&mut ctx, &[Set(0, 3), While(vec![Add(1, 21), Add(0, -1)]), Write(1)]).unwrap();
+/// assert_eq!(ctx.io.output(), b"?");
+/// ```
+#[derive(Default)]
+pub struct Jit {
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    funs: code::Functions,
+}
+
+impl Jit {
+    /// Creates a compiler with no functions compiled yet
+    pub fn new() -> Jit {
+        Jit::default()
+    }
+    /// Checks if machine code can be generated and run here: on x86-64 Linux, if the system
+    /// gives executable memory
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    pub fn is_supported() -> bool {
+        use std::sync::Once;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        static CHECK: Once = Once::new();
+        static SUPPORTED: AtomicBool = AtomicBool::new(false);
+        CHECK.call_once(|| SUPPORTED.store(memory::Executable::new(&[0xc3]).is_some(), Ordering::SeqCst));
+        SUPPORTED.load(Ordering::SeqCst)
+    }
+    /// Checks if machine code can be generated and run here: on x86-64 Linux, if the system
+    /// gives executable memory
+    #[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+    pub fn is_supported() -> bool {
+        false
+    }
+    /// Compiles and executes `ops`, ignoring the fuel of `ctx`
+    ///
+    /// Where machine code is not supported, or the system gives no executable memory for it,
+    /// runs them on a `bytecode::Machine` instead.
+    ///
+    /// # Errors
+    /// Stops at the first operation that fails, returning its error, with the array
+    /// of the outermost call back in `ctx`.
+    pub fn run<R: Read, W: Write>(&mut self, ctx: &mut Context<R, W>, ops: &[Operation]) -> Result<(), RuntimeError> {
+        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+        {
+            if Jit::is_supported() {
+                if let Some(code) = code::Compiled::new(ops, ctx.cells()) {
+                    return code.run(&mut code::State::new(ctx, &mut self.funs));
+                }
+            }
+        }
+        Machine::new().run(ctx, ops)
+    }
+}
@@ -1,5 +1,14 @@
 //! Collects all separate modules and imports the extern crate `lazy_static`.
-//! Imports only the functions/structures needed for running the program.
+//!
+//! The interpreter is split in stages, each available on its own:
+//! * `reader` filters a script, keeping where each character was found
+//! * `dispatcher` parses it into a tree of `Operation`s and holds everything needed to run them
+//! * `optimiser` rewrites the operations into faster equivalent ones
+//! * `bytecode` lowers them to flat instructions and runs them without recursion
+//! * `jit` compiles them to x86-64 machine code
+//! * `emit` turns them into standalone programs in other languages
+//! * `precompiled` stores them in files which load without parsing
+//! * `Compiler` puts the stages together and keeps the state between scripts
 //!
 //! # Examples
 //!
@@ -8,17 +17,49 @@
 //!
 //! and run the program: `run(&mut Compiler);`
 //!
-mod reader;
+//! The stages can also be used directly, for example to inspect the operations
+//! before running them:
+//!
+//! ```
+//! use Brainfuck::reader::Reader;
+//! use Brainfuck::dispatcher::{parse, Context};
+//! use Brainfuck::dispatcher::Operation::*;
+//! use Brainfuck::optimiser::optimise_code;
+//!
+//! let mut ctx = Context::new("".as_bytes(), Vec::new());
+//! let mut rd = Reader::from_string(String::from("~++++++++[>++++++++<-]>+.<~ !-|"));
+//! let ops = parse(&mut rd, &mut ctx.fh).unwrap();
+//! ctx.fh.push_funs();
+//! let ops = optimise_code(ops, ctx.cells());
+//! // The function is stored as it was parsed
+//! let loop_body = vec![Move(1), Add(0, 8), Move(-1), Add(0, -1)];
+//! assert_eq!(ctx.fh.get(0).unwrap().ops()[..2], [Add(0, 8), While(loop_body)]);
+//! ctx.run(&ops).unwrap();
+//! assert_eq!(ctx.io.output(), b"A");
+//! assert_eq!(ctx.ah.values(), vec![(1, 65)]);
+//! ```
+//!
+#![allow(non_snake_case)]
+
+pub mod reader;
 
-mod dispatcher;
+pub mod dispatcher;
 
 mod compiler;
-pub use compiler::Compiler;
+pub use compiler::{Compiler, CompileReport, CompileError};
 
 mod cmd_loop;
 pub use cmd_loop::run;
 
-mod optimiser;
+pub mod optimiser;
+
+pub mod bytecode;
+
+pub mod jit;
+
+pub mod emit;
+
+pub mod precompiled;
 
 #[macro_use]
 extern crate lazy_static;
@@ -1,17 +1,119 @@
+#![allow(non_snake_case)]
+
 extern crate Brainfuck;
 
 use Brainfuck::Compiler;
+use Brainfuck::emit::Language;
+use Brainfuck::optimiser::Pass;
 use Brainfuck::run;
-use std::collections::VecDeque;
 use std::env;
+use std::fs;
+use std::process;
+
+const USAGE: &str = "Usage: Brainfuck [options] [filename(s)]\n\
+Options:\n\
+\x20   --eof=unchanged|0|-1    what ',' does once the input is exhausted (default unchanged)\n\
+\x20   --cells=u8|u16|u32|i64|trap\n\
+\x20                           type of the cells, trap being 32 bit cells that stop on overflow (default trap)\n\
+\x20   --tape=N|unbounded      fixed number of cells, moving off either end being an error (default unbounded)\n\
+\x20   -O0|-O1|-O2|-O3         optimisation level, from none to all the passes (default -O3)\n\
+\x20   --passes=P,...          run only the listed passes: fold, loops, offsets,\n\
+\x20                           constants, calls, inline, trim, prefix\n\
+\x20   --dump-ir               print the operations before and after each pass to stderr\n\
+\x20   --backend=tree|bytecode|jit\n\
+\x20                           run the operations as a tree (default), as flat instructions\n\
+\x20                           or as machine code, on x86-64 Linux\n\
+\x20   --emit=c|rust|wat       print the files as a standalone program instead of running them\n\
+\x20   --save=FILE             store the compiled files in FILE instead of running them, to be\n\
+\x20                           loaded like a script without parsing them again";
 
 fn main() {
-    let mut args: VecDeque<String> = env::args().collect();
-    args.pop_front();
     let mut compiler = Compiler::new();
-    while !args.is_empty() {
-        compiler.compile_file(args.pop_front().unwrap());
-        compiler.execute(true);
+    let mut files = Vec::new();
+    let mut emit = None;
+    let mut save = None;
+    for arg in env::args().skip(1) {
+        if let Some(lang) = arg.strip_prefix("--emit=") {
+            match lang.parse::<Language>() {
+                Ok(lang) => emit = Some(lang),
+                Err(e) => {
+                    println!("{}\n{}", e, USAGE);
+                    process::exit(1);
+                }
+            }
+        } else if let Some(path) = arg.strip_prefix("--save=") {
+            save = Some(path.to_string());
+        } else if arg.starts_with('-') {
+            if let Err(e) = set_option(&mut compiler, &arg) {
+                println!("{}\n{}", e, USAGE);
+                process::exit(1);
+            }
+        } else {
+            files.push(arg);
+        }
+    }
+    if emit.is_some() || save.is_some() {
+        for file in files {
+            if let Err(e) = compiler.try_compile_file(&file) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        if let Some(lang) = emit {
+            print!("{}", compiler.emit(lang));
+        }
+        if let Some(path) = save {
+            if let Err(e) = fs::write(&path, compiler.precompiled().to_bytes()) {
+                eprintln!("Could not write to file '{}': {}", path, e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    for file in files {
+        compiler.compile_file(file);
+        if let Err(e) = compiler.execute(true) {
+            println!("Runtime error: {}", e);
+        }
     }
     run(&mut compiler);
 }
+
+// Applies a single `--name=value` or `-O<level>` option to the compiler
+fn set_option(cmp: &mut Compiler, arg: &str) -> Result<(), String> {
+    if let Some(level) = arg.strip_prefix("-O") {
+        return match level.parse().ok().and_then(Pass::level) {
+            Some(passes) => {
+                set_passes(cmp, passes);
+                Ok(())
+            }
+            None => Err(format!("Invalid optimisation level '{}', expected 0, 1, 2 or 3", level)),
+        };
+    }
+    let mut parts = arg.trim_start_matches('-').splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some("eof"), Some(val)) => cmp.set_eof(val.parse()?),
+        (Some("cells"), Some(val)) => cmp.set_cell_type(val.parse()?),
+        (Some("tape"), Some("unbounded")) => cmp.set_tape(None),
+        (Some("tape"), Some(val)) => match val.parse() {
+            Ok(size) if size > 0 => cmp.set_tape(Some(size)),
+            _ => return Err(format!("Invalid tape size '{}', expected a positive number or unbounded", val)),
+        },
+        (Some("passes"), Some(val)) => {
+            // Unknown names are reported before any pass is changed
+            let passes = val.split(',').filter(|p| !p.is_empty()).map(str::parse).collect::<Result<Vec<Pass>, _>>()?;
+            set_passes(cmp, &passes);
+        }
+        (Some("dump-ir"), None) => cmp.optimiser_mut().set_dump(true),
+        (Some("backend"), Some(val)) => cmp.set_backend(val.parse()?),
+        _ => return Err(format!("Unknown option '{}'", arg)),
+    }
+    Ok(())
+}
+
+// Runs exactly `passes` on the scripts, keeping the other settings of the optimiser
+fn set_passes(cmp: &mut Compiler, passes: &[Pass]) {
+    for &pass in Pass::ALL.iter() {
+        cmp.optimiser_mut().set_pass(pass, passes.contains(&pass));
+    }
+}
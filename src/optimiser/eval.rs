@@ -0,0 +1,285 @@
+//! Evaluates operations when compiling
+
+use dispatcher::operation::*;
+use super::known_value;
+use std::collections::{BTreeMap, HashSet};
+
+// The most operations evaluated when compiling a single script
+const EVAL_FUEL: u64 = 1_000_000;
+// The most operations evaluated for a single loop with a known counter
+const LOOP_FUEL: usize = 10_000;
+
+/// Runs the longest start of `ops` which reads no input and only calls functions known
+/// when compiling, replacing it with a `Restore` of the array, stack and output it leaves.
+///
+/// The operations must be the first to run on an empty array and stack, with a tape of
+/// `bound` cells as in `Context::set_tape`. The evaluation stops before an operation which
+/// fails, so it still fails when the script runs, and gives up on operations running too long.
+///
+/// # Examples
+/// ```
+/// use Brainfuck::optimiser::evaluate_prefix;
+/// use Brainfuck::dispatcher::{CellType, FunctionHolder, Snapshot};
+/// use Brainfuck::dispatcher::Operation::*;
+///
+/// let ops = vec![Set(1, 72), Write(1), PushStack(1), Move(2), Read(0), Write(0)];
+/// assert_eq!(evaluate_prefix(ops, CellType::U8, &FunctionHolder::new(), None),
+///            vec![Restore(Snapshot { cells: vec![(1, 72)], stack: vec![72], output: b"H".to_vec(), pointer: 2 }),
+///                 Read(0), Write(0)]);
+/// ```
+pub fn evaluate_prefix(ops: Vec<Operation>, cells: CellType, fh: &FunctionHolder, bound: Option<usize>) -> Vec<Operation> {
+    // Find how many operations can be evaluated, the state being left half changed by the next one
+    let mut ctx = scratch(cells, fh, bound);
+    ctx.set_fuel(Some(EVAL_FUEL));
+    let (mut known, mut calling) = (HashSet::new(), Vec::new());
+    let done = ops.iter()
+        .take_while(|op| pure(op, fh, &mut known, &mut calling) && op.execute(&mut ctx).is_ok())
+        .count();
+    if done == 0 {
+        return ops;
+    }
+    let mut ctx = scratch(cells, fh, bound);
+    ctx.run(&ops[..done]).expect("Evaluated operations failed when evaluated again");
+    let snap = Snapshot {
+        cells: ctx.ah.values().into_iter().map(|(place, val)| (place as i32, val)).collect(),
+        stack: ctx.stack.as_slice().to_vec(),
+        output: ctx.io.output().clone(),
+        pointer: ctx.ah.pointer() as i32,
+    };
+    let mut result = Vec::with_capacity(ops.len() - done + 1);
+    if snap != (Snapshot { cells: vec![], stack: vec![], output: vec![], pointer: 0 }) {
+        result.push(Restore(snap));
+    }
+    result.extend(ops.into_iter().skip(done));
+    result
+}
+// Creates an empty context to evaluate operations in, with the functions of `fh`
+fn scratch(cells: CellType, fh: &FunctionHolder, bound: Option<usize>) -> Context<&'static [u8], Vec<u8>> {
+    let mut ctx = Context::new(&[][..], Vec::new());
+    ctx.set_cells(cells);
+    ctx.set_tape(bound);
+    ctx.fh = fh.clone();
+    ctx
+}
+// Checks if `op` reads no input and only calls functions known when compiling,
+// which do not call themselves, so the evaluation cannot recurse too deep
+//
+// `known` holds the functions found pure and `calling` the ones being checked.
+fn pure(op: &Operation, fh: &FunctionHolder, known: &mut HashSet<usize>, calling: &mut Vec<usize>) -> bool {
+    match *op {
+        Read(_) | InsFuns | CallFun | CallFSep(_) | Debug => false,
+        While(ref body) | Separate(_, ref body) => body.iter().all(|op| pure(op, fh, known, calling)),
+        Call(n) | CallSep(n, _) => {
+            if known.contains(&n) {
+                return true;
+            }
+            let fun = match fh.compiled(n) {
+                Some(fun) if !calling.contains(&n) => fun,
+                _ => return false,
+            };
+            calling.push(n);
+            let res = fun.ops().iter().chain(fun.separate_ops()).all(|op| pure(op, fh, known, calling));
+            calling.pop();
+            if res {
+                known.insert(n);
+            }
+            res
+        }
+        _ => true,
+    }
+}
+
+// What is known about a cell while evaluating a loop
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Value {
+    // The cell holds this number
+    Known(i64),
+    // The cell holds the number it had before the loop plus this one
+    Plus(i64),
+}
+
+// Evaluates a loop on the cells it uses, knowing only what the operations before it set
+struct LoopEval<'a> {
+    cells: CellType,
+    // The operations before the loop, for the values of the cells it did not change yet
+    before: &'a [Operation],
+    // The offset of the pointer at the loop from the one at the end of `before`
+    base: i32,
+    // The cells used, by offset from the pointer at the loop, and whether they were changed
+    tape: BTreeMap<i32, (Value, bool)>,
+    pointer: i32,
+    fuel: usize,
+}
+
+impl<'a> LoopEval<'a> {
+    fn get(&mut self, o: i32) -> Value {
+        if let Some(&(val, _)) = self.tape.get(&o) {
+            return val;
+        }
+        let val = match known_value(self.before, self.base + o, self.cells) {
+            Some(v) => Value::Known(v),
+            None => Value::Plus(0),
+        };
+        self.tape.insert(o, (val, false));
+        val
+    }
+    fn known(&mut self, o: i32) -> Option<i64> {
+        match self.get(o) {
+            Value::Known(v) => Some(v),
+            Value::Plus(_) => None,
+        }
+    }
+    fn set(&mut self, o: i32, v: i64) -> Option<()> {
+        let v = self.cells.wrap(v).ok()?;
+        self.tape.insert(o, (Value::Known(v), true));
+        Some(())
+    }
+    fn add(&mut self, o: i32, v: i64) -> Option<()> {
+        let val = match self.get(o) {
+            Value::Known(x) => Value::Known(self.cells.add(x, v).ok()?),
+            // Trapping cells fail if any value on the way is out of range, so the
+            // additions must all go the same way to be done at once
+            Value::Plus(d) if !self.cells.wraps() => {
+                if d != 0 && (d > 0) != (v > 0) {
+                    return None;
+                }
+                Value::Plus(d.checked_add(v).filter(|d| d.abs() <= i64::from(u32::MAX))?)
+            }
+            Value::Plus(d) => Value::Plus(self.cells.wrap(d.wrapping_add(v)).ok()?),
+        };
+        self.tape.insert(o, (val, true));
+        Some(())
+    }
+    fn burn(&mut self) -> Option<()> {
+        self.fuel = self.fuel.checked_sub(1)?;
+        Some(())
+    }
+    // Runs `ops`, returning None for anything which cannot be evaluated
+    fn run(&mut self, ops: &[Operation]) -> Option<()> {
+        for op in ops {
+            self.burn()?;
+            let p = self.pointer;
+            match *op {
+                Add(o, v) => self.add(p + o, v)?,
+                Set(o, v) => self.set(p + o, v)?,
+                Move(i) => self.pointer += i,
+                While(ref body) => {
+                    while self.known(self.pointer)? != 0 {
+                        self.burn()?;
+                        self.run(body)?;
+                    }
+                }
+                MoveTo(ref lin) => {
+                    let val = self.known(p)?;
+                    if val == 0 {
+                        continue;
+                    }
+                    let n = self.cells.iterations(val, lin.step).ok()??;
+                    self.set(p, 0)?;
+                    for &(o, v) in &lin.sets {
+                        self.set(p + o, v)?;
+                    }
+                    for &(o, mult) in &lin.adds {
+                        let v = self.cells.mul(n, mult).ok()?;
+                        self.add(p + o, v)?;
+                    }
+                }
+                SkipMove(i) => {
+                    while self.known(self.pointer)? != 0 {
+                        self.burn()?;
+                        self.pointer += i;
+                    }
+                }
+                EmptyOp => {}
+                _ => return None,
+            }
+        }
+        Some(())
+    }
+}
+
+/// Evaluates `lp`, a loop whose counter is set by the operations `before` it, returning
+/// the changes it makes to the cells and the pointer, with offsets from the pointer at
+/// the end of `before`, which is moved by `base` before the loop.
+///
+/// Returns None if the counter is not known, the loop uses anything but the cells
+/// (input, output, the stack or functions), depends on cells not known or runs too long.
+pub fn evaluate_loop(lp: &Operation, before: &[Operation], base: i32, cells: CellType) -> Option<Vec<Operation>> {
+    let mut eval = LoopEval { cells, before, base, tape: BTreeMap::new(), pointer: 0, fuel: LOOP_FUEL };
+    eval.known(0)?;
+    eval.run(std::slice::from_ref(lp))?;
+    let mut changes = Vec::new();
+    for (&o, &(val, changed)) in &eval.tape {
+        match val {
+            Value::Known(v) if changed => changes.push(Set(base + o, v)),
+            Value::Plus(d) if changed && d != 0 => changes.push(Add(base + o, d)),
+            _ => {}
+        }
+    }
+    if base + eval.pointer != 0 {
+        changes.push(Move(base + eval.pointer));
+    }
+    Some(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{evaluate_loop, evaluate_prefix};
+    use dispatcher::{CellType, FunctionHolder, LinearLoop, Snapshot};
+    use dispatcher::Operation::*;
+    #[test]
+    fn stops_before_failures() {
+        let ops = vec![Add(0, 2), PushStack(0), PopStack(1, 2), Write(1)];
+        assert_eq!(evaluate_prefix(ops, CellType::Trap, &FunctionHolder::new(), None),
+                   vec![Restore(Snapshot { cells: vec![(0, 2)], stack: vec![2], output: vec![], pointer: 0 }),
+                        PopStack(1, 2), Write(1)]);
+        // Moving off a bounded tape fails
+        let ops = vec![Add(0, 1), Move(-1)];
+        assert_eq!(evaluate_prefix(ops, CellType::Trap, &FunctionHolder::new(), Some(10)),
+                   vec![Restore(Snapshot { cells: vec![(0, 1)], stack: vec![], output: vec![], pointer: 0 }), Move(-1)]);
+        // Never ending loops run out of fuel
+        let ops = vec![PushStack(0), Add(0, 1), While(vec![Add(1, 1)])];
+        assert_eq!(evaluate_prefix(ops, CellType::U8, &FunctionHolder::new(), None),
+                   vec![Restore(Snapshot { cells: vec![(0, 1)], stack: vec![0], output: vec![], pointer: 0 }),
+                        While(vec![Add(1, 1)])]);
+    }
+    #[test]
+    fn calls_known_functions() {
+        let mut fh = FunctionHolder::new();
+        fh.add_temp(vec![While(vec![Call(0)])]);
+        fh.add_temp(vec![Add(0, 5), Write(0)]);
+        fh.add_temp(vec![Read(0)]);
+        fh.push_funs();
+        let ops = vec![Call(1), Move(1), Call(2), Call(1)];
+        assert_eq!(evaluate_prefix(ops, CellType::U8, &fh, None),
+                   vec![Restore(Snapshot { cells: vec![(0, 5)], stack: vec![], output: vec![5], pointer: 1 }),
+                        Call(2), Call(1)]);
+        // Recursion is left for the run
+        let ops = vec![Set(0, 1), Call(0)];
+        assert_eq!(evaluate_prefix(ops, CellType::U8, &fh, None),
+                   vec![Restore(Snapshot { cells: vec![(0, 1)], stack: vec![], output: vec![], pointer: 0 }), Call(0)]);
+    }
+    #[test]
+    fn loops_with_known_counters() {
+        // [-]++++[>+++<-] with the pointer moved to the loop
+        let before = [Set(2, 4)];
+        let lp = MoveTo(LinearLoop { step: -1, sets: vec![], adds: vec![(1, 3)] });
+        assert_eq!(evaluate_loop(&lp, &before, 2, CellType::Trap), Some(vec![Set(2, 0), Add(3, 12), Move(2)]));
+        // [-]>[-]<++[>+++[>++<-]<-]
+        let before = [Set(0, 2), Set(1, 0)];
+        let lp = While(vec![Add(0, -1), Add(1, 3), Move(1), While(vec![Add(0, -1), Add(1, 2)]), Move(-1)]);
+        assert_eq!(evaluate_loop(&lp, &before, 0, CellType::U8), Some(vec![Set(0, 0), Set(1, 0), Add(2, 12)]));
+        let before = [Set(1, 1), Set(2, 0)];
+        assert_eq!(evaluate_loop(&SkipMove(1), &before, 1, CellType::U8), Some(vec![Move(2)]));
+        // The counter or an inner one is not known
+        assert_eq!(evaluate_loop(&lp, &[Set(1, 2)], 0, CellType::U8), None);
+        assert_eq!(evaluate_loop(&lp, &[Set(0, 2)], 0, CellType::U8), None);
+        // Trapping cells cannot add both ways to a cell not known
+        let lp = While(vec![Add(0, -1), Add(1, 1), Add(1, -2)]);
+        assert_eq!(evaluate_loop(&lp, &[Set(0, 2)], 0, CellType::Trap), None);
+        assert_eq!(evaluate_loop(&lp, &[Set(0, 2)], 0, CellType::U8), Some(vec![Set(0, 0), Add(1, 254)]));
+        // Never ending loops and loops writing output are left to run
+        assert_eq!(evaluate_loop(&While(vec![Add(1, 1)]), &[Set(0, 1)], 0, CellType::U8), None);
+        assert_eq!(evaluate_loop(&While(vec![Write(0), Add(0, -1)]), &[Set(0, 1)], 0, CellType::U8), None);
+    }
+}
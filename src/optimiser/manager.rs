@@ -0,0 +1,276 @@
+//! Runs the optimisation passes chosen by the user, dumping the operations between them
+
+use dispatcher::operation::*;
+use super::{constant_loops, direct_calls, evaluate_prefix, fold, inline_calls, loops, offset_moves, trim};
+use std::fmt;
+use std::str::FromStr;
+
+/// A single optimisation pass
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pass {
+    /// Merges neighbouring operations and removes the ones with no effect (`+-`, `><`, `[-][+]`)
+    Fold,
+    /// Replaces loops matching a pattern with a single operation (`[-]`, `[>]`, `[->+<]`)
+    Loops,
+    /// Folds the pointer moves into the offsets of the operations on cells
+    Offsets,
+    /// Evaluates loops whose counter is known when compiling (`[-]++[>+++<-]`)
+    Constants,
+    /// Calls functions directly when their number is known when compiling
+    Calls,
+    /// Replaces the direct calls of small functions with their operations
+    Inline,
+    /// Drops the changes at the end of functions called on a separate array
+    Trim,
+    /// Evaluates the start of scripts running on an empty array and stack when compiling
+    Prefix,
+}
+
+use self::Pass::*;
+
+// The order the passes run in; folding again cleans up after the passes before it
+const PIPELINE: [Pass; 8] = [Fold, Loops, Fold, Offsets, Constants, Calls, Inline, Fold];
+
+impl Pass {
+    /// All the passes
+    pub const ALL: [Pass; 8] = [Fold, Loops, Offsets, Constants, Calls, Inline, Trim, Prefix];
+    /// Returns the passes of an optimisation level, from 0 (none) to 3 (all)
+    pub fn level(level: u32) -> Option<&'static [Pass]> {
+        match level {
+            0 => Some(&[]),
+            1 => Some(&[Fold, Loops]),
+            2 => Some(&[Fold, Loops, Offsets, Constants, Calls, Trim]),
+            3 => Some(&Pass::ALL),
+            _ => None,
+        }
+    }
+    /// Returns the name of the pass, as used on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            Fold => "fold",
+            Loops => "loops",
+            Offsets => "offsets",
+            Constants => "constants",
+            Calls => "calls",
+            Inline => "inline",
+            Trim => "trim",
+            Prefix => "prefix",
+        }
+    }
+}
+
+impl FromStr for Pass {
+    type Err = String;
+    /// Parses the name of a pass
+    fn from_str(s: &str) -> Result<Pass, String> {
+        match Pass::ALL.iter().find(|pass| pass.name() == s) {
+            Some(&pass) => Ok(pass),
+            None => Err(format!("Unknown pass '{}', expected fold, loops, offsets, constants, calls, inline, trim or prefix", s)),
+        }
+    }
+}
+
+impl fmt::Display for Pass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Chooses which passes run on the compiled operations and whether the operations are
+/// dumped to stderr before and after each of them.
+///
+/// The passes always run in the same order, whichever of them are switched on.
+///
+/// # Examples
+/// ```
+/// use Brainfuck::optimiser::{Optimiser, Pass};
+/// use Brainfuck::dispatcher::{CellType, FunctionHolder};
+/// use Brainfuck::dispatcher::Operation::*;
+///
+/// let ops = vec![Move(1), Add(0, 1), Move(-1)];
+/// let fh = FunctionHolder::new();
+/// let mut opt = Optimiser::new();
+/// assert_eq!(opt.optimise(ops.clone(), CellType::Trap, &fh, None), vec![Add(1, 1)]);
+/// opt.set_pass(Pass::Offsets, false);
+/// assert_eq!(opt.optimise(ops.clone(), CellType::Trap, &fh, None), ops);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Optimiser {
+    passes: Vec<Pass>,
+    dump: bool,
+}
+
+impl Default for Optimiser {
+    fn default() -> Optimiser {
+        Optimiser::new()
+    }
+}
+
+impl Optimiser {
+    /// Creates an optimiser running all the passes, like level 3
+    pub fn new() -> Optimiser {
+        Optimiser::with_passes(&Pass::ALL)
+    }
+    /// Creates an optimiser running only `passes`
+    pub fn with_passes(passes: &[Pass]) -> Optimiser {
+        Optimiser { passes: passes.to_vec(), dump: false }
+    }
+    /// Creates an optimiser running the passes of an optimisation level
+    ///
+    /// # Errors
+    /// Returns a message if the level is above 3.
+    pub fn with_level(level: u32) -> Result<Optimiser, String> {
+        match Pass::level(level) {
+            Some(passes) => Ok(Optimiser::with_passes(passes)),
+            None => Err(format!("Unknown optimisation level {}, expected 0, 1, 2 or 3", level)),
+        }
+    }
+    /// Switches a pass on or off
+    pub fn set_pass(&mut self, pass: Pass, on: bool) {
+        self.passes.retain(|&p| p != pass);
+        if on {
+            self.passes.push(pass);
+        }
+    }
+    /// Checks if a pass is switched on
+    pub fn runs(&self, pass: Pass) -> bool {
+        self.passes.contains(&pass)
+    }
+    /// Chooses whether the operations are dumped to stderr before and after each pass
+    pub fn set_dump(&mut self, dump: bool) {
+        self.dump = dump;
+    }
+    /// Optimises a sequence of operations, inlining the functions in `fh`, to run on a tape
+    /// of `bound` cells as in `Context::set_tape`
+    ///
+    /// On a bounded tape the moves are kept far enough apart to still fail where the pointer
+    /// would leave the tape, and the loops moving the pointer are not evaluated or replaced.
+    pub fn optimise(&self, ops: Vec<Operation>, cells: CellType, fh: &FunctionHolder, bound: Option<usize>) -> Vec<Operation> {
+        self.run_passes("code", ops, cells, fh, bound.is_some())
+    }
+    /// Optimises operations which run first on an empty array and stack, like `optimise`,
+    /// then evaluates their start (see `evaluate_prefix`) with a tape of `bound` cells
+    pub fn optimise_start(&self, ops: Vec<Operation>, cells: CellType, fh: &FunctionHolder, bound: Option<usize>) -> Vec<Operation> {
+        let ops = self.optimise(ops, cells, fh, bound);
+        if !self.runs(Prefix) {
+            return ops;
+        }
+        let before = if self.dump { Some(ops.clone()) } else { None };
+        let ops = evaluate_prefix(ops, cells, fh, bound);
+        if let Some(before) = before {
+            self.dump_after("code", Prefix, &before, &ops);
+        }
+        ops
+    }
+    /// Optimises the body of a function, inlining the functions already in `fh`,
+    /// and the version of it run on a separate array, for a tape of `bound` cells like `optimise`
+    pub fn optimise_function(&self, fun: Function, cells: CellType, fh: &FunctionHolder, bound: Option<usize>) -> Function {
+        let name = format!("function {}", fh.curr_funs());
+        let ops = self.run_passes(&name, fun.into_ops(), cells, fh, bound.is_some());
+        if !self.runs(Trim) {
+            return Function::new(ops);
+        }
+        let separate = trim(ops.clone(), cells, bound.is_some());
+        self.dump_after(&format!("{} (separate array)", name), Trim, &ops, &separate);
+        Function::with_separate(ops, separate)
+    }
+    // Runs the passes which are switched on, in the order of the pipeline
+    fn run_passes(&self, name: &str, mut ops: Vec<Operation>, cells: CellType, fh: &FunctionHolder, bounded: bool) -> Vec<Operation> {
+        if self.dump {
+            eprintln!("=== {}: input ===\n{}", name, dump(&ops));
+        }
+        for &pass in PIPELINE.iter().filter(|&&pass| self.runs(pass)) {
+            let before = if self.dump { Some(ops.clone()) } else { None };
+            ops = match pass {
+                Fold => fold(ops, cells, bounded),
+                Loops => loops(ops, cells, bounded),
+                Offsets => offset_moves(ops, bounded),
+                // The loops are evaluated on a growing array, which they might not fit in
+                Constants if bounded => ops,
+                Constants => constant_loops(ops, cells),
+                Calls => direct_calls(ops, cells),
+                Inline => inline_calls(ops, fh),
+                Trim | Prefix => ops,
+            };
+            if let Some(before) = before {
+                self.dump_after(name, pass, &before, &ops);
+            }
+        }
+        ops
+    }
+    // Prints the operations after a pass, if dumps are switched on
+    fn dump_after(&self, name: &str, pass: Pass, before: &[Operation], after: &[Operation]) {
+        if !self.dump {
+            return;
+        }
+        if before == after {
+            eprintln!("=== {}: after {} (unchanged) ===", name, pass);
+        } else {
+            eprintln!("=== {}: after {} ===\n{}", name, pass, dump(after));
+        }
+    }
+}
+
+/// Formats operations one per line, indenting the bodies of loops and separate runs
+///
+/// # Examples
+/// ```
+/// use Brainfuck::optimiser::dump;
+/// use Brainfuck::dispatcher::Operation::*;
+///
+/// assert_eq!(dump(&[Add(0, 1), While(vec![Move(1)])]), "Add(0, 1)\nWhile\n  Move(1)\n");
+/// ```
+pub fn dump(ops: &[Operation]) -> String {
+    let mut out = String::new();
+    dump_into(&mut out, ops, 0);
+    out
+}
+// Appends the operations to `out`, indented by `depth` levels
+fn dump_into(out: &mut String, ops: &[Operation], depth: usize) {
+    for op in ops {
+        for _ in 0..depth {
+            out.push_str("  ");
+        }
+        match *op {
+            While(ref body) => {
+                out.push_str("While\n");
+                dump_into(out, body, depth + 1);
+            }
+            Separate(args, ref body) => {
+                out.push_str(&format!("Separate({})\n", args));
+                dump_into(out, body, depth + 1);
+            }
+            ref x => out.push_str(&format!("{:?}\n", x)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Optimiser, Pass};
+    use dispatcher::{CellType, FunctionHolder};
+    use dispatcher::Operation::*;
+    #[test]
+    fn levels() {
+        // +[->>+<<]>>.
+        let ops = vec![Add(0, 1), While(vec![Add(0, -1), Move(2), Add(0, 1), Move(-2)]), Move(2), Write(0)];
+        let fh = FunctionHolder::new();
+        let opt = |level| Optimiser::with_level(level).unwrap().optimise(ops.clone(), CellType::Trap, &fh, None);
+        assert_eq!(opt(0), ops);
+        assert!(matches!(opt(1)[..], [Add(0, 1), MoveTo(_), Move(2), Write(0)]));
+        assert!(matches!(opt(2)[..], [Add(0, 1), MoveTo(_), Write(2), Move(2)]));
+        assert!(Optimiser::with_level(4).is_err());
+    }
+    #[test]
+    fn passes_by_name() {
+        for &pass in Pass::ALL.iter() {
+            assert_eq!(pass.name().parse(), Ok(pass));
+        }
+        assert!("unroll".parse::<Pass>().is_err());
+        let mut opt = Optimiser::with_level(1).unwrap();
+        assert!(!opt.runs(Pass::Inline));
+        opt.set_pass(Pass::Inline, true);
+        opt.set_pass(Pass::Fold, false);
+        assert!(opt.runs(Pass::Inline) && !opt.runs(Pass::Fold));
+    }
+}
@@ -1,82 +1,302 @@
 //! Optimises the operations for the best run time
 //!
+//! The optimisations are split into named passes (see `Pass`), which an `Optimiser`
+//! runs in order, so each of them can be switched off and its effect dumped.
+//!
 //! Provides specialised optimisations for loops
+//!
+//! Pointer moves are folded into the offsets of the operations on cells, so straight-line
+//! code and loop bodies move the pointer at most once before anything that needs it in place.
+//!
+//! Constants are folded in the range of the `CellType` the operations will run with,
+//! so the same script can optimise differently for different cells.
+//!
+//! On a bounded tape every cell the pointer passed through before is still reached, so
+//! moving off the tape fails at the same point however the operations are optimised.
+
+mod eval;
+mod manager;
+use self::eval::evaluate_loop;
+pub use self::eval::evaluate_prefix;
+pub use self::manager::{dump, Optimiser, Pass};
 
 use dispatcher::operation::*;
-use std::collections::{VecDeque, HashMap};
+use std::collections::BTreeMap;
+
+//The largest function inlined, in number of operations
+const INLINE_SIZE: usize = 32;
 
 /// Takes an unoptimised sequence of operations and optimises it as
 /// explained in README.md at [GitHub](https://github.com/VladMoldoveanu/Brainfuck_stack-and-functions).
 ///
-pub fn optimise_code(ops: VecDeque<Operation>) -> VecDeque<Operation> {
-    let mut optimised: Vec<Operation> = Vec::with_capacity(ops.len());
-    for i in ops.into_iter() {
-        reduce_top(&mut optimised, i);
+/// Runs all the passes, without any functions to inline.
+///
+/// # Examples
+/// ```
+/// use Brainfuck::optimiser::optimise_code;
+/// use Brainfuck::dispatcher::Operation::*;
+///
+/// use Brainfuck::dispatcher::CellType;
+///
+/// assert_eq!(optimise_code(vec![Add(0, 3), Add(0, -2), Move(2), Move(-3), Set(0, 4)], CellType::Trap),
+///            vec![Add(0, 1), Set(-1, 4), Move(-1)]);
+/// assert_eq!(optimise_code(vec![Add(0, 200), Add(0, 56)], CellType::U8), vec![]);
+/// // >+>++<<-
+/// assert_eq!(optimise_code(vec![Move(1), Add(0, 1), Move(1), Add(0, 2), Move(-2), Add(0, -1)], CellType::Trap),
+///            vec![Add(1, 1), Add(2, 2), Add(0, -1)]);
+/// ```
+pub fn optimise_code(ops: Vec<Operation>, cells: CellType) -> Vec<Operation> {
+    Optimiser::new().optimise(ops, cells, &FunctionHolder::new(), None)
+}
+/// Replaces the direct calls of small functions which do not call any functions themselves
+/// with their operations, or with a `Separate` operation running them for calls on a separate array.
+///
+/// # Examples
+/// ```
+/// use Brainfuck::optimiser::inline_calls;
+/// use Brainfuck::dispatcher::Operation::*;
+/// use Brainfuck::dispatcher::FunctionHolder;
+///
+/// let mut fh = FunctionHolder::new();
+/// fh.add_temp(vec![Add(0, 1), Write(0)]);
+/// fh.push_funs();
+/// assert_eq!(inline_calls(vec![Set(0, 0), Call(0), Set(0, 0), CallSep(0, 1)], &fh),
+///            vec![Set(0, 0), Add(0, 1), Write(0), Set(0, 0), Separate(1, vec![Add(0, 1), Write(0)])]);
+/// ```
+pub fn inline_calls(ops: Vec<Operation>, fh: &FunctionHolder) -> Vec<Operation> {
+    let mut result: Vec<Operation> = Vec::with_capacity(ops.len());
+    for op in ops.into_iter() {
+        match op {
+            Call(n) => match fh.compiled(n) {
+                Some(fun) if inlinable(fun.ops()) => result.extend_from_slice(fun.ops()),
+                _ => result.push(Call(n)),
+            },
+            CallSep(n, args) => match fh.compiled(n) {
+                Some(fun) if inlinable(fun.separate_ops()) => {
+                    result.push(Separate(args, fun.separate_ops().to_vec()));
+                }
+                _ => result.push(CallSep(n, args)),
+            },
+            While(body) => result.push(While(inline_calls(body, fh))),
+            Separate(args, body) => result.push(Separate(args, inline_calls(body, fh))),
+            x => result.push(x),
+        }
+    }
+    result
+}
+//Checks if a function is small enough to be inlined and cannot call itself
+fn inlinable(ops: &[Operation]) -> bool {
+    size(ops) <= INLINE_SIZE && calls_nothing(ops)
+}
+//Checks if `ops` do not call any functions
+fn calls_nothing(ops: &[Operation]) -> bool {
+    ops.iter().all(|op| match *op {
+        CallFun | CallFSep(_) | Call(_) | CallSep(_, _) => false,
+        While(ref body) | Separate(_, ref body) => calls_nothing(body),
+        _ => true,
+    })
+}
+//Applies `f` to the operations inside loops and separate runs
+fn map_bodies<F: FnMut(Vec<Operation>) -> Vec<Operation>>(ops: Vec<Operation>, mut f: F) -> Vec<Operation> {
+    ops.into_iter().map(|op| match op {
+        While(body) => While(f(body)),
+        Separate(args, body) => Separate(args, f(body)),
+        x => x,
+    }).collect()
+}
+//Drops the operations at the end which only change the array, for functions run on a separate array
+fn trim(mut ops: Vec<Operation>, cells: CellType, bounded: bool) -> Vec<Operation> {
+    while ops.last().is_some_and(|op| unobserved(op, cells, bounded)) {
+        ops.pop();
+    }
+    ops
+}
+//Checks if `op` cannot have any effect when it is the last change to a discarded array
+//
+//Operations which can fail are kept, so the function still fails the same way.
+fn unobserved(op: &Operation, cells: CellType, bounded: bool) -> bool {
+    match *op {
+        Move(_) => !bounded,
+        Add(p, _) => cells.wraps() && (p == 0 || !bounded),
+        Set(p, i) => cells.wrap(i).is_ok() && (p == 0 || !bounded),
+        _ => false,
+    }
+}
+//Merges neighbouring operations, in the loops first
+fn fold(ops: Vec<Operation>, cells: CellType, bounded: bool) -> Vec<Operation> {
+    let mut folded: Vec<Operation> = Vec::with_capacity(ops.len());
+    for op in map_bodies(ops, |body| fold(body, cells, bounded)) {
+        reduce_top(&mut folded, op, cells, bounded);
+    }
+    folded
+}
+//Replaces the loops matching a pattern, the inner ones first
+fn loops(ops: Vec<Operation>, cells: CellType, bounded: bool) -> Vec<Operation> {
+    ops.into_iter().map(|op| match op {
+        While(body) => loop_pattern(loops(body, cells, bounded), cells, bounded),
+        Separate(args, body) => Separate(args, loops(body, cells, bounded)),
+        x => x,
+    }).collect()
+}
+//Replaces the moves between operations on cells with offsets, moving the pointer
+//only before operations which need it in place and at the end
+//
+//On a bounded tape the pointer is also moved where it turns back, unless an operation
+//reaches the cell it turns on.
+fn offset_moves(ops: Vec<Operation>, bounded: bool) -> Vec<Operation> {
+    let ops = map_bodies(ops, |body| offset_moves(body, bounded));
+    let mut moved: Vec<Operation> = Vec::with_capacity(ops.len());
+    let mut offset = 0;
+    //The last move, and whether an operation reached the cell it ended on
+    let (mut last, mut reached) = (0, true);
+    for op in ops.into_iter() {
+        if op.offset() == Some(0) {
+            reached = true;
+        }
+        match op {
+            Move(i) => {
+                if bounded && !reached && offset != 0 && (i < 0) != (last < 0) {
+                    moved.push(Move(offset));
+                    offset = 0;
+                }
+                offset += i;
+                last = i;
+                reached = false;
+            }
+            Add(o, i) => moved.push(Add(o + offset, i)),
+            Set(o, i) => moved.push(Set(o + offset, i)),
+            Read(o) => moved.push(Read(o + offset)),
+            Write(o) => moved.push(Write(o + offset)),
+            PeekStack(o) => moved.push(PeekStack(o + offset)),
+            PopStack(o, i) => moved.push(PopStack(o + offset, i)),
+            PushStack(o) => moved.push(PushStack(o + offset)),
+            StackLen(o) => moved.push(StackLen(o + offset)),
+            x => {
+                if offset != 0 {
+                    moved.push(Move(offset));
+                    offset = 0;
+                }
+                moved.push(x);
+            }
+        }
+    }
+    if offset != 0 {
+        moved.push(Move(offset));
+    }
+    moved
+}
+//Calls functions directly when their number is known
+fn direct_calls(ops: Vec<Operation>, cells: CellType) -> Vec<Operation> {
+    let ops = map_bodies(ops, |body| direct_calls(body, cells));
+    let mut result: Vec<Operation> = Vec::with_capacity(ops.len());
+    for op in ops.into_iter() {
+        let known = match result.last() {
+            Some(&Move(i)) => known_value(&result[..result.len() - 1], i, cells),
+            _ => known_value(&result, 0, cells),
+        };
+        result.push(match (op, known) {
+            (CallFun, Some(n)) if n >= 0 => Call(n as usize),
+            (CallFSep(args), Some(n)) if n >= 0 => CallSep(n as usize, args),
+            (x, _) => x,
+        });
     }
-    optimised.into_iter().collect()
+    result
+}
+//Evaluates the loops whose counter is known when compiling, keeping only their changes
+fn constant_loops(ops: Vec<Operation>, cells: CellType) -> Vec<Operation> {
+    let ops = map_bodies(ops, |body| constant_loops(body, cells));
+    let mut result: Vec<Operation> = Vec::with_capacity(ops.len());
+    for op in ops.into_iter() {
+        if let While(_) | MoveTo(_) | SkipMove(_) = op {
+            let (end, base) = match result.last() {
+                Some(&Move(i)) => (result.len() - 1, i),
+                _ => (result.len(), 0),
+            };
+            if let Some(changes) = evaluate_loop(&op, &result[..end], base, cells) {
+                result.truncate(end);
+                result.extend(changes);
+                continue;
+            }
+        }
+        result.push(op);
+    }
+    result
+}
+//Returns the value the operations at the end of `ops` leave at `offset`, if it is a constant
+fn known_value(ops: &[Operation], offset: i32, cells: CellType) -> Option<i64> {
+    let mut added = Vec::new();
+    for op in ops.iter().rev() {
+        match *op {
+            Set(o, i) if o == offset => {
+                return added.iter().rev().try_fold(i, |val, &j| cells.add(val, j).ok());
+            }
+            Add(o, i) if o == offset => added.push(i),
+            //Loops end on a 0
+            While(_) | MoveTo(_) | SkipMove(_) if offset == 0 => {
+                return added.iter().rev().try_fold(0, |val, &j| cells.add(val, j).ok());
+            }
+            Read(o) | PeekStack(o) | PopStack(o, _) | StackLen(o) if o == offset => return None,
+            Add(_, _) | Set(_, _) | Read(_) | Write(_) | PeekStack(_) | PopStack(_, _) | PushStack(_) | StackLen(_) => {}
+            _ => return None,
+        }
+    }
+    None
 }
 //Reduces the next operation with the last one added
-fn reduce_top(ops: &mut Vec<Operation>, op: Operation) {
+//
+//On a bounded tape, additions cancelling out on another cell and moves in opposite
+//directions are kept, since they can still move off the tape.
+fn reduce_top(ops: &mut Vec<Operation>, op: Operation, cells: CellType, bounded: bool) {
     if let EmptyOp = op {
         return;
     }
-    if ops.len() == 0 {
+    if ops.is_empty() {
         ops.push(op);
         return;
     }
     match op {
-        //Merge with Add or Set
-        Add(i) => {
-            if let Add(j) = ops[ops.len() - 1] {
-                ops.pop();
-                if i + j != 0 {
-                    ops.push(Add(i+j));
+        //Merge with Add or Set on the same cell
+        Add(o, i) => {
+            if let Add(p, j) = ops[ops.len() - 1] {
+                if p == o {
+                    ops.pop();
+                    if cells.wrap(i.wrapping_add(j)) != Ok(0) || (bounded && o != 0) {
+                        ops.push(Add(o, i.wrapping_add(j)));
+                    }
+                    return;
+                }
+            } else if let Set(p, j) = ops[ops.len() - 1] {
+                // Out of range values are left to fail at run time
+                if let (true, Ok(k)) = (p == o, cells.add(i, j)) {
+                    ops.pop();
+                    ops.push(Set(o, k));
+                    return;
                 }
-            } else if let Set(j) = ops[ops.len() - 1] {
-                ops.pop();
-                ops.push(Set(i + j));
-            } else { ops.push(Add(i)); }
+            }
+            ops.push(Add(o, i));
         }
         //Merge with Move
         Move(i) => {
-            if let Move(j) = ops[ops.len() - 1] {
-                ops.pop();
-                if i + j != 0 {
-                    ops.push(Move(i+j));
+            match ops[ops.len() - 1] {
+                Move(j) if !bounded || (i < 0) == (j < 0) => {
+                    ops.pop();
+                    if i + j != 0 {
+                        ops.push(Move(i+j));
+                    }
                 }
-            } else { ops.push(Move(i)); }
-        }
-        //Overwrite Add, Set or Set-like operations
-        Set(i) => {
-            if let Add(_) = ops[ops.len() - 1] {
-                ops.pop();
-            } else if let Set(_) = ops[ops.len() - 1] {
-                ops.pop();
-            } else if let PeekStack = ops[ops.len() - 1] {
-                ops.pop();
-            } else if let StackLen = ops[ops.len() - 1] {
-                ops.pop();
+                _ => ops.push(Move(i)),
             }
-            ops.push(Set(i));
         }
-        //Overwrite Add, Set or Set-like operations
-        Read => {
-            if let Add(_) = ops[ops.len() - 1] {
-                ops.pop();
-            } else if let Set(_) = ops[ops.len() - 1] {
-                ops.pop();
-            } else if let PeekStack = ops[ops.len() - 1] {
-                ops.pop();
-            } else if let StackLen = ops[ops.len() - 1] {
-                ops.pop();
-            }
-            ops.push(Read);
+        //Overwrite Add, Set or Set-like operations on the same cell
+        Set(o, i) => {
+            drop_overwritten(ops, o, cells, bounded);
+            ops.push(Set(o, i));
         }
         //Remove While when start point is a known 0
         While(w_ops) => {
             if let While(_) = ops[ops.len() - 1] {
 
-            } else if let Set(0) = ops[ops.len() - 1] {
+            } else if let Set(0, 0) = ops[ops.len() - 1] {
 
             } else if let MoveTo(_) = ops[ops.len() - 1] {
 
@@ -86,93 +306,143 @@ fn reduce_top(ops: &mut Vec<Operation>, op: Operation) {
                 ops.push(While(w_ops));
             }
         }
-        // Merge PopStack operations
-        PopStack(i) => {
-            if let PopStack(j) = ops[ops.len() - 1] {
-                ops.pop();
-                ops.push(PopStack(i + j));
-            } else {
-                ops.push(PopStack(i));
+        // Merge PopStack operations on the same cell
+        PopStack(o, i) => {
+            if let PopStack(p, j) = ops[ops.len() - 1] {
+                if p == o {
+                    ops.pop();
+                    ops.push(PopStack(o, i + j));
+                    return;
+                }
             }
+            ops.push(PopStack(o, i));
         }
-        //Overwrite Add, Set or Set-like operations
-        StackLen => {
-            if let Add(_) = ops[ops.len() - 1] {
-                ops.pop();
-            } else if let Set(_) = ops[ops.len() - 1] {
-                ops.pop();
-            } else if let PeekStack = ops[ops.len() - 1] {
-                ops.pop();
-            } else if let StackLen = ops[ops.len() - 1] {
-                ops.pop();
-            }
-            ops.push(StackLen);
+        //Overwrite Add, Set or Set-like operations on the same cell
+        StackLen(o) => {
+            drop_overwritten(ops, o, cells, bounded);
+            ops.push(StackLen(o));
         }
         x => ops.push(x),
     }
 }
+//Removes the operations at the end of `ops` made useless by setting the cell at offset `o`,
+//looking back until the cell is used, the pointer moves or an operation could fail
+//
+//A failure stops the script before the cell is set, so the operations before it still count.
+//On a bounded tape that includes reaching any cell away from the pointer.
+fn drop_overwritten(ops: &mut Vec<Operation>, o: i32, cells: CellType, bounded: bool) {
+    let other = |p: i32| p != o && (p == 0 || !bounded);
+    for k in (0..ops.len()).rev() {
+        match ops[k] {
+            ref op if overwritten(op, o, cells) => {
+                ops.remove(k);
+            }
+            Add(p, _) if other(p) && cells.wraps() => {}
+            Set(p, i) if other(p) && cells.wrap(i).is_ok() => {}
+            PushStack(p) | StackLen(p) if other(p) => {}
+            _ => return,
+        }
+    }
+}
+//Checks if `op` only changes the cell at offset `o`, so setting that cell makes it useless
+//
+//Operations which can fail are kept, so the script still fails the same way.
+fn overwritten(op: &Operation, o: i32, cells: CellType) -> bool {
+    match *op {
+        Add(p, _) => p == o && cells.wraps(),
+        Set(p, i) => p == o && cells.wrap(i).is_ok(),
+        StackLen(p) => p == o,
+        _ => false,
+    }
+}
 
 /// Takes an unoptimised sequence of operations meant to be the body of a loop and optimises it as
 /// explained in README.md at [GitHub](https://github.com/VladMoldoveanu/Brainfuck_stack-and-functions).
 ///
-pub fn loop_optimiser(ops: Vec<Operation>) -> Operation {
-    let mut optimised: Vec<Operation> = Vec::with_capacity(ops.len());
-    for i in ops.into_iter() {
-        reduce_top(&mut optimised, i);
-    }
+/// # Examples
+/// ```
+/// use Brainfuck::optimiser::loop_optimiser;
+/// use Brainfuck::dispatcher::Operation::*;
+/// use Brainfuck::dispatcher::{CellType, LinearLoop};
+///
+/// // [->+>>.<<<]
+/// let body = vec![Add(0, -1), Move(1), Add(0, 1), Move(2), Write(0), Move(-3)];
+/// assert_eq!(loop_optimiser(body, CellType::Trap), While(vec![Add(0, -1), Add(1, 1), Write(3)]));
+/// // [->+<]
+/// let body = vec![Add(0, -1), Move(1), Add(0, 1), Move(-1)];
+/// assert_eq!(loop_optimiser(body, CellType::Trap),
+///            MoveTo(LinearLoop { step: -1, sets: vec![], adds: vec![(1, 1)] }));
+/// // [>[-]+>+++<<--]
+/// let body = vec![Move(1), Set(0, 0), Add(0, 1), Move(1), Add(0, 3), Move(-2), Add(0, -2)];
+/// assert_eq!(loop_optimiser(body, CellType::U8),
+///            MoveTo(LinearLoop { step: -2, sets: vec![(1, 1)], adds: vec![(2, 3)] }));
+/// ```
+pub fn loop_optimiser(ops: Vec<Operation>, cells: CellType) -> Operation {
+    loop_pattern(optimise_code(ops, cells), cells, false)
+}
+//Replaces a loop with an already optimised body by a single operation, if it matches a pattern
+//
+//On a bounded tape the loops moving the pointer are kept, so they still fail on the iteration
+//which moves it off the tape.
+fn loop_pattern(optimised: Vec<Operation>, cells: CellType, bounded: bool) -> Operation {
     //Ignore empty loops
-    if optimised.len() == 0 {
+    if optimised.is_empty() {
         return EmptyOp;
     }
     //Patterns for loops of length 1
     if optimised.len() == 1 {
-        if let Add(i) = optimised[0] {
-            if i == 1 || i == -1 {
-                return Set(0);
-            }
-        } else if let Set(i) = optimised[0] {
-            if i == 0 {
-                return Set(0);
+        if let Add(0, i) = optimised[0] {
+            // With wrapping cells adding any odd number eventually reaches 0
+            if i == 1 || i == -1 || (cells.wraps() && i % 2 != 0) {
+                return Set(0, 0);
             }
+        } else if let Set(0, 0) = optimised[0] {
+            return Set(0, 0);
         } else if let Move(i) = optimised[0] {
             return SkipMove(i);
         }
     }
     //Create a MoveTo operation
-    if move_and_add(&optimised) {
-        let mut points: HashMap<i32, i32> = HashMap::new();
-        let mut curr_pos = 0;
-        for op in optimised {
-            match op {
-                Add(i) => {
-                    if curr_pos != 0 {
-                        let counter = points.entry(curr_pos).or_insert(0);
-                        *counter += i;
-                    }
-                }
-                Move(i) => curr_pos += i,
-                x => panic!("Found {:?} in add_move!", x),
-            }
-        }
-        return MoveTo(points.into_iter().collect());
+    if let Some(lin) = linear_loop(&optimised, cells).filter(|_| !bounded) {
+        return MoveTo(lin);
     }
     While(optimised)
 }
 
-//Checks if a loop fits the MoveTo pattern
-fn move_and_add(ops: &Vec<Operation>) -> bool {
-    let mut total_move = 0;
-    let mut point_diff = 0;
-    for i in 0..ops.len() {
-        if let Add(j) = ops[i] {
-            if total_move == 0 {
-                point_diff += j;
+//Checks if a loop fits the MoveTo pattern: it ends where it started, only adds a constant
+//to the current cell, and adds constants to or sets other cells
+fn linear_loop(ops: &[Operation], cells: CellType) -> Option<LinearLoop> {
+    let mut step: i64 = 0;
+    let mut sets: BTreeMap<i32, i64> = BTreeMap::new();
+    let mut adds: BTreeMap<i32, i64> = BTreeMap::new();
+    let mut pos = 0;
+    for op in ops {
+        match *op {
+            Move(i) => pos += i,
+            Add(o, i) if pos + o == 0 => step = step.wrapping_add(i),
+            Add(o, i) => {
+                let o = pos + o;
+                // Adding after setting only changes the value set
+                if let Some(val) = sets.get_mut(&o) {
+                    *val = cells.add(*val, i).ok()?;
+                    continue;
+                }
+                let counter = adds.entry(o).or_insert(0);
+                *counter = counter.wrapping_add(i);
             }
-        } else if let Move(j) = ops[i]{
-            total_move += j;
-        } else {
-            return false;
+            Set(o, i) if pos + o != 0 => {
+                adds.remove(&(pos + o));
+                sets.insert(pos + o, i);
+            }
+            _ => return None,
         }
     }
-    return total_move == 0 && point_diff == -1;
+    if pos != 0 || cells.wrap(step).unwrap_or(0) == 0 {
+        return None;
+    }
+    Some(LinearLoop {
+        step,
+        sets: sets.into_iter().collect(),
+        adds: adds.into_iter().filter(|&(_, mult)| cells.wrap(mult) != Ok(0)).collect(),
+    })
 }
\ No newline at end of file
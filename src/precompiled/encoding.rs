@@ -0,0 +1,268 @@
+//! Turns operations into bytes and back
+
+use dispatcher::operation::*;
+use precompiled::LoadError;
+use std::convert::TryFrom;
+
+// How deep loops and bodies on separate arrays can be nested, so damaged files cannot make the
+// decoder recurse until the stack runs out
+const NESTING: usize = 1000;
+
+/// Appends numbers and operations to a buffer
+#[derive(Default)]
+pub struct Encoder {
+    out: Vec<u8>,
+}
+
+impl Encoder {
+    /// Creates an empty buffer
+    pub fn new() -> Encoder {
+        Encoder::default()
+    }
+    /// Returns the bytes written
+    pub fn finish(self) -> Vec<u8> {
+        self.out
+    }
+    /// Appends `bytes` as they are
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.out.extend_from_slice(bytes);
+    }
+    /// Appends a byte
+    pub fn u8(&mut self, n: u8) {
+        self.out.push(n);
+    }
+    /// Appends an unsigned number in 4 bytes, least significant first
+    pub fn u32(&mut self, n: u32) {
+        self.bytes(&n.to_le_bytes());
+    }
+    /// Appends a signed number in 4 bytes, least significant first
+    pub fn i32(&mut self, n: i32) {
+        self.bytes(&n.to_le_bytes());
+    }
+    /// Appends a signed number in 8 bytes, least significant first
+    pub fn i64(&mut self, n: i64) {
+        self.bytes(&n.to_le_bytes());
+    }
+    /// Appends a number or a length, in 8 bytes
+    pub fn usize(&mut self, n: usize) {
+        self.bytes(&(n as u64).to_le_bytes());
+    }
+    fn pairs(&mut self, pairs: &[(i32, i64)]) {
+        self.usize(pairs.len());
+        for &(off, val) in pairs {
+            self.i32(off);
+            self.i64(val);
+        }
+    }
+    /// Appends the number of operations in `ops`, then each of them
+    pub fn ops(&mut self, ops: &[Operation]) {
+        self.usize(ops.len());
+        for op in ops {
+            self.op(op);
+        }
+    }
+    // Appends the number of the kind of `op`, then its values
+    fn op(&mut self, op: &Operation) {
+        match *op {
+            Add(off, i) => {
+                self.u8(0);
+                self.i32(off);
+                self.i64(i);
+            }
+            Move(i) => {
+                self.u8(1);
+                self.i32(i);
+            }
+            MoveTo(ref lin) => {
+                self.u8(2);
+                self.i64(lin.step);
+                self.pairs(&lin.sets);
+                self.pairs(&lin.adds);
+            }
+            SkipMove(i) => {
+                self.u8(3);
+                self.i32(i);
+            }
+            Set(off, i) => {
+                self.u8(4);
+                self.i32(off);
+                self.i64(i);
+            }
+            Read(off) => {
+                self.u8(5);
+                self.i32(off);
+            }
+            Write(off) => {
+                self.u8(6);
+                self.i32(off);
+            }
+            While(ref body) => {
+                self.u8(7);
+                self.ops(body);
+            }
+            InsFuns => self.u8(8),
+            CallFun => self.u8(9),
+            CallFSep(args) => {
+                self.u8(10);
+                self.usize(args);
+            }
+            Call(n) => {
+                self.u8(11);
+                self.usize(n);
+            }
+            CallSep(n, args) => {
+                self.u8(12);
+                self.usize(n);
+                self.usize(args);
+            }
+            Separate(args, ref body) => {
+                self.u8(13);
+                self.usize(args);
+                self.ops(body);
+            }
+            Debug => self.u8(14),
+            PeekStack(off) => {
+                self.u8(15);
+                self.i32(off);
+            }
+            PopStack(off, n) => {
+                self.u8(16);
+                self.i32(off);
+                self.usize(n);
+            }
+            PushStack(off) => {
+                self.u8(17);
+                self.i32(off);
+            }
+            StackLen(off) => {
+                self.u8(18);
+                self.i32(off);
+            }
+            Restore(ref snap) => {
+                self.u8(19);
+                self.pairs(&snap.cells);
+                self.usize(snap.stack.len());
+                for &val in &snap.stack {
+                    self.i64(val);
+                }
+                self.usize(snap.output.len());
+                self.bytes(&snap.output);
+                self.i32(snap.pointer);
+            }
+            EmptyOp => self.u8(20),
+        }
+    }
+}
+
+/// Reads numbers and operations from bytes, failing with `LoadError::Corrupt` if they end early
+/// or nest the operations too deep
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+    // The number of bodies being read
+    depth: usize,
+}
+
+impl<'a> Decoder<'a> {
+    /// Creates a decoder reading `bytes` from the start
+    pub fn new(bytes: &'a [u8]) -> Decoder<'a> {
+        Decoder { bytes, depth: 0 }
+    }
+    /// Checks that every byte was read
+    pub fn finish(self) -> Result<(), LoadError> {
+        if self.bytes.is_empty() { Ok(()) } else { Err(LoadError::Corrupt) }
+    }
+    fn take(&mut self, n: usize) -> Result<&'a [u8], LoadError> {
+        if self.bytes.len() < n {
+            return Err(LoadError::Corrupt);
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], LoadError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+    /// Reads a byte written by `Encoder::u8`
+    pub fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+    /// Reads a number written by `Encoder::u32`
+    pub fn u32(&mut self) -> Result<u32, LoadError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+    /// Reads a number written by `Encoder::i32`
+    pub fn i32(&mut self) -> Result<i32, LoadError> {
+        Ok(i32::from_le_bytes(self.array()?))
+    }
+    /// Reads a number written by `Encoder::i64`
+    pub fn i64(&mut self) -> Result<i64, LoadError> {
+        Ok(i64::from_le_bytes(self.array()?))
+    }
+    /// Reads a number written by `Encoder::usize`, failing if it does not fit in a `usize`
+    pub fn usize(&mut self) -> Result<usize, LoadError> {
+        usize::try_from(u64::from_le_bytes(self.array()?)).map_err(|_| LoadError::Corrupt)
+    }
+    /// Reads the number of elements of a sequence, which are at least a byte each, so
+    /// there cannot be more of them than bytes left
+    pub fn count(&mut self) -> Result<usize, LoadError> {
+        let len = self.usize()?;
+        if len > self.bytes.len() { Err(LoadError::Corrupt) } else { Ok(len) }
+    }
+    // Reads the number of cells a call on a separate array copies, which the array reaches
+    // with an `i32` displacement
+    fn args(&mut self) -> Result<usize, LoadError> {
+        let args = self.usize()?;
+        if args > i32::MAX as usize { Err(LoadError::Corrupt) } else { Ok(args) }
+    }
+    fn pairs(&mut self) -> Result<Vec<(i32, i64)>, LoadError> {
+        (0..self.count()?).map(|_| Ok((self.i32()?, self.i64()?))).collect()
+    }
+    /// Reads operations written by `Encoder::ops`
+    pub fn ops(&mut self) -> Result<Vec<Operation>, LoadError> {
+        (0..self.count()?).map(|_| self.op()).collect()
+    }
+    // Reads the operations of a body nested in the ones being read
+    fn body(&mut self) -> Result<Vec<Operation>, LoadError> {
+        if self.depth >= NESTING {
+            return Err(LoadError::Corrupt);
+        }
+        self.depth += 1;
+        let ops = self.ops();
+        self.depth -= 1;
+        ops
+    }
+    fn op(&mut self) -> Result<Operation, LoadError> {
+        Ok(match self.u8()? {
+            0 => Add(self.i32()?, self.i64()?),
+            1 => Move(self.i32()?),
+            2 => MoveTo(LinearLoop { step: self.i64()?, sets: self.pairs()?, adds: self.pairs()? }),
+            3 => SkipMove(self.i32()?),
+            4 => Set(self.i32()?, self.i64()?),
+            5 => Read(self.i32()?),
+            6 => Write(self.i32()?),
+            7 => While(self.body()?),
+            8 => InsFuns,
+            9 => CallFun,
+            10 => CallFSep(self.args()?),
+            11 => Call(self.usize()?),
+            12 => CallSep(self.usize()?, self.args()?),
+            13 => Separate(self.args()?, self.body()?),
+            14 => Debug,
+            15 => PeekStack(self.i32()?),
+            16 => PopStack(self.i32()?, self.usize()?),
+            17 => PushStack(self.i32()?),
+            18 => StackLen(self.i32()?),
+            19 => {
+                let cells = self.pairs()?;
+                let stack = (0..self.count()?).map(|_| self.i64()).collect::<Result<_, _>>()?;
+                let len = self.count()?;
+                let output = self.take(len)?.to_vec();
+                Restore(Snapshot { cells, stack, output, pointer: self.i32()? })
+            }
+            20 => EmptyOp,
+            _ => return Err(LoadError::Corrupt),
+        })
+    }
+}
@@ -0,0 +1,268 @@
+//! Stores compiled programs in files, so they can be loaded again without parsing and
+//! optimising them.
+//!
+//! A file starts with `MAGIC` and the `VERSION` of the format, then the settings the program
+//! was optimised for, the number of functions loaded before it, its functions and its
+//! operations. Numbers are little endian, with the numbers and lengths of the operations in
+//! 8 bytes.
+//!
+//! The numbers of the functions called by the operations only make sense after the same
+//! functions, so a program can only be loaded after as many functions as it was compiled
+//! after, see `Compiler::try_load_program`.
+
+mod encoding;
+
+use self::encoding::{Decoder, Encoder};
+use dispatcher::operation::*;
+use std::fmt;
+
+/// The bytes every precompiled program starts with
+pub const MAGIC: [u8; 4] = *b"BFPC";
+
+/// The version of the format written, the only one read
+pub const VERSION: u32 = 1;
+
+/// A compiled program: the functions it declared and the operations queued to run
+#[derive(Clone)]
+pub struct Program {
+    /// The type of the cells it was optimised for
+    pub cells: CellType,
+    /// The size of the array it was optimised for
+    pub tape: Option<usize>,
+    /// Whether the operations have to run first on an empty array and stack, their start
+    /// having been evaluated when compiling with an array of `tape` cells
+    pub starts_empty: bool,
+    /// The number of functions loaded before the program, the first one it declares
+    /// getting this number
+    pub base: usize,
+    /// The functions it declared, in order
+    pub functions: Vec<Function>,
+    /// The operations it runs
+    pub ops: Vec<Operation>,
+}
+
+/// The reasons a precompiled program cannot be loaded
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    /// The file does not start with `MAGIC`
+    NotPrecompiled,
+    /// The file was written in another version of the format
+    Version(u32),
+    /// The file ends early or holds something no program is made of
+    Corrupt,
+    /// The program was optimised for cells of the given type
+    Cells(CellType),
+    /// The program was compiled after the given number of functions, not as many as are loaded
+    Base(usize, usize),
+    /// The program has to run first, on an empty array and stack
+    NotEmpty,
+    /// The program was compiled with an array of the given size
+    Tape(Option<usize>),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::NotPrecompiled => write!(f, "not a precompiled program"),
+            LoadError::Version(v) => write!(f, "precompiled with format version {}, expected version {}", v, VERSION),
+            LoadError::Corrupt => write!(f, "the precompiled program is cut short or damaged"),
+            LoadError::Cells(cells) => write!(f, "precompiled for {} cells", cells),
+            LoadError::Base(base, loaded) => {
+                write!(f, "precompiled after {} functions, but {} are loaded", base, loaded)
+            }
+            LoadError::NotEmpty => write!(f, "the precompiled program has to run on an empty array and stack"),
+            LoadError::Tape(Some(size)) => write!(f, "precompiled for a tape of {} cells", size),
+            LoadError::Tape(None) => write!(f, "precompiled for an unbounded tape"),
+        }
+    }
+}
+
+impl Program {
+    /// Writes the program in the current version of the format
+    ///
+    /// # Examples
+    /// ```
+    /// use Brainfuck::precompiled::{Program, MAGIC};
+    /// use Brainfuck::dispatcher::CellType;
+    /// use Brainfuck::dispatcher::Operation::*;
+    ///
+    /// let program = Program {
+    ///     cells: CellType::U8,
+    ///     tape: None,
+    ///     starts_empty: false,
+    ///     base: 0,
+    ///     functions: Vec::new(),
+    ///     ops: vec![Add(0, 65), Write(0)],
+    /// };
+    /// let bytes = program.to_bytes();
+    /// assert!(bytes.starts_with(&MAGIC));
+    /// assert_eq!(Program::from_bytes(&bytes).unwrap().ops, program.ops);
+    /// ```
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut en = Encoder::new();
+        en.bytes(&MAGIC);
+        en.u32(VERSION);
+        en.u8(match self.cells {
+            CellType::U8 => 0,
+            CellType::U16 => 1,
+            CellType::U32 => 2,
+            CellType::I64 => 3,
+            CellType::Trap => 4,
+        });
+        match self.tape {
+            Some(size) => {
+                en.u8(1);
+                en.usize(size);
+            }
+            None => en.u8(0),
+        }
+        en.u8(self.starts_empty as u8);
+        en.usize(self.base);
+        en.usize(self.functions.len());
+        for fun in &self.functions {
+            en.ops(fun.ops());
+            en.ops(fun.separate_ops());
+        }
+        en.ops(&self.ops);
+        en.finish()
+    }
+    /// Reads a program written by `to_bytes`
+    ///
+    /// # Errors
+    /// Returns `NotPrecompiled` if `bytes` do not start with `MAGIC`, `Version` if they were
+    /// written in another version of the format, and `Corrupt` if they end early, hold
+    /// anything after the program, nest loops more than a thousand deep, copy more cells to
+    /// a separate array than an `i32` counts or call a function which is neither loaded before
+    /// it nor declared by it.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, LoadError> {
+        if !bytes.starts_with(&MAGIC) {
+            return Err(LoadError::NotPrecompiled);
+        }
+        let mut de = Decoder::new(&bytes[MAGIC.len()..]);
+        let version = de.u32()?;
+        if version != VERSION {
+            return Err(LoadError::Version(version));
+        }
+        let cells = match de.u8()? {
+            0 => CellType::U8,
+            1 => CellType::U16,
+            2 => CellType::U32,
+            3 => CellType::I64,
+            4 => CellType::Trap,
+            _ => return Err(LoadError::Corrupt),
+        };
+        let tape = match de.u8()? {
+            0 => None,
+            1 => Some(de.usize()?),
+            _ => return Err(LoadError::Corrupt),
+        };
+        let starts_empty = match de.u8()? {
+            0 => false,
+            1 => true,
+            _ => return Err(LoadError::Corrupt),
+        };
+        let base = de.usize()?;
+        let mut functions = Vec::new();
+        for _ in 0..de.count()? {
+            let ops = de.ops()?;
+            functions.push(Function::with_separate(ops, de.ops()?));
+        }
+        let ops = de.ops()?;
+        de.finish()?;
+        let known = base.checked_add(functions.len()).ok_or(LoadError::Corrupt)?;
+        let calls_known = |ops: &[Operation]| calls(ops).iter().all(|&n| n < known);
+        if !functions.iter().all(|fun| calls_known(fun.ops()) && calls_known(fun.separate_ops())) || !calls_known(&ops) {
+            return Err(LoadError::Corrupt);
+        }
+        Ok(Program { cells, tape, starts_empty, base, functions, ops })
+    }
+}
+
+// Returns the numbers of the functions `ops` call directly
+fn calls(ops: &[Operation]) -> Vec<usize> {
+    let mut found = Vec::new();
+    for op in ops {
+        match *op {
+            Call(n) | CallSep(n, _) => found.push(n),
+            While(ref body) | Separate(_, ref body) => found.extend(calls(body)),
+            _ => {}
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LoadError, Program, MAGIC};
+    use dispatcher::{CellType, Function, LinearLoop, Snapshot};
+    use dispatcher::Operation::*;
+    fn program() -> Program {
+        let lin = LinearLoop { step: -1, sets: vec![(2, 0)], adds: vec![(1, i64::MIN)] };
+        let snap = Snapshot { cells: vec![(0, 3), (-1, 7)], stack: vec![1, -2], output: b"hi\n".to_vec(), pointer: -4 };
+        Program {
+            cells: CellType::I64,
+            tape: Some(30000),
+            starts_empty: true,
+            base: 2,
+            functions: vec![Function::with_separate(vec![Call(1), PopStack(-1, 2), CallFSep(3)], vec![EmptyOp])],
+            ops: vec![Restore(snap), Add(-3, 5), Move(i32::MIN), MoveTo(lin), SkipMove(2), Set(1, -1),
+                Read(0), Write(-2), While(vec![InsFuns, CallFun, Separate(1, vec![Debug, PeekStack(0)])]),
+                CallSep(2, 4), PushStack(3), StackLen(-5)],
+        }
+    }
+    #[test]
+    fn programs_are_read_back() {
+        let program = program();
+        let read = Program::from_bytes(&program.to_bytes()).unwrap();
+        assert_eq!((read.cells, read.tape, read.starts_empty, read.base), (CellType::I64, Some(30000), true, 2));
+        assert_eq!(read.functions.len(), 1);
+        assert_eq!(read.functions[0].ops(), program.functions[0].ops());
+        assert_eq!(read.functions[0].separate_ops(), &[EmptyOp]);
+        assert_eq!(read.ops, program.ops);
+    }
+    #[test]
+    fn other_versions_and_damaged_files_are_rejected() {
+        let bytes = program().to_bytes();
+        assert_eq!(Program::from_bytes(b"~+.~").err(), Some(LoadError::NotPrecompiled));
+        let mut newer = bytes.clone();
+        newer[MAGIC.len()] = 2;
+        assert_eq!(Program::from_bytes(&newer).err(), Some(LoadError::Version(2)));
+        assert_eq!(Program::from_bytes(&bytes[..bytes.len() - 1]).err(), Some(LoadError::Corrupt));
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert_eq!(Program::from_bytes(&longer).err(), Some(LoadError::Corrupt));
+        let mut unknown = program();
+        unknown.ops.push(Call(3));
+        assert_eq!(Program::from_bytes(&unknown.to_bytes()).err(), Some(LoadError::Corrupt));
+        // A million loops nested in each other, in place of the operations of the program
+        let mut nested = Program { functions: Vec::new(), ops: Vec::new(), ..program() }.to_bytes();
+        nested.truncate(nested.len() - 8);
+        for _ in 0..1_000_000 {
+            nested.push(7);
+            nested.extend_from_slice(&1u64.to_le_bytes());
+        }
+        nested.extend_from_slice(&0u64.to_le_bytes());
+        assert_eq!(Program::from_bytes(&nested).err(), Some(LoadError::Corrupt));
+    }
+    #[test]
+    fn forged_arguments_are_rejected() {
+        let args = [i32::MAX as usize + 1, 1 << 40, usize::MAX];
+        for &args in args.iter() {
+            for op in [CallFSep(args), CallSep(0, args), Separate(args, vec![Write(0)])].iter() {
+                let forged = Program { ops: vec![op.clone()], ..program() };
+                assert_eq!(Program::from_bytes(&forged.to_bytes()).err(), Some(LoadError::Corrupt));
+            }
+        }
+        let fits = Program { ops: vec![CallFSep(i32::MAX as usize)], ..program() };
+        assert!(Program::from_bytes(&fits.to_bytes()).is_ok());
+        // Damaging any byte of a program never makes reading it panic
+        let bytes = program().to_bytes();
+        for i in 0..bytes.len() {
+            for &byte in [0, 1, 0x7f, 0x80, 0xff].iter() {
+                let mut damaged = bytes.clone();
+                damaged[i] = byte;
+                let _ = Program::from_bytes(&damaged);
+            }
+        }
+    }
+}
@@ -1,5 +1,5 @@
 //! Holds a single structure: Reader, which facilitates reading scripts from
-//! both files and Strings
+//! both files and Strings, together with the positions of the characters it keeps
 
 use std::fs::File;
 use std::io::prelude::*;
@@ -24,9 +24,18 @@ use std::io::prelude::*;
 #[derive(Debug)]
 pub struct Reader {
     ch: Vec<char>, // Characters to iterate over
+    spans: Vec<Span>, // Where each kept character was found in the source
+    lines: Vec<String>, // The lines of the source, used for printing errors
     pos: usize, // Current position of the iteration
 }
 
+/// A position in the source of a script, both line and column starting from 1
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+}
+
 impl Reader {
     /// Creates new Reader with characters from a given String
     ///
@@ -42,8 +51,20 @@ impl Reader {
     /// ```
     ///
     pub fn from_string(s: String) -> Reader {
+        let mut ch = Vec::new();
+        let mut spans = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            for (j, c) in line.chars().enumerate() {
+                if CHARS.contains(&c) {
+                    ch.push(c);
+                    spans.push(Span { line: i + 1, col: j + 1 });
+                }
+            }
+        }
         Reader{
-            ch: s.chars().filter(|ch| CHARS.contains(ch)).collect(),
+            ch,
+            spans,
+            lines: s.lines().map(String::from).collect(),
             pos: 0,
         }
     }
@@ -126,10 +147,64 @@ impl Reader {
     ///
     /// Panics if the whole String/File is consumed (i.e. has_next() returns false).
     ///
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> char {
         self.pos += 1;
         self.ch[self.pos-1]
     }
+    /// Returns the position in the source of the next character.
+    ///
+    /// If everything was consumed, returns the position right after the last kept character.
+    ///
+    /// # Examples
+    /// ```
+    /// use Brainfuck::reader::{Reader, Span};
+    ///
+    /// let mut reader = Reader::from_string(String::from("Line one\n  [-]"));
+    /// assert_eq!(reader.span(), Span { line: 2, col: 3 });
+    /// ```
+    ///
+    pub fn span(&self) -> Span {
+        match self.spans.get(self.pos) {
+            Some(&span) => span,
+            None => match self.spans.last() {
+                Some(&Span { line, col }) => Span { line, col: col + 1 },
+                None => Span { line: 1, col: 1 },
+            },
+        }
+    }
+    /// Returns the line with the given number (starting from 1) of the original source
+    pub fn line(&self, n: usize) -> Option<&str> {
+        if n == 0 {
+            return None;
+        }
+        self.lines.get(n - 1).map(|l| l.as_str())
+    }
+    /// Formats a message pointing at `span`, showing the line it is on with a caret under
+    /// the column.
+    ///
+    /// # Examples
+    /// ```
+    /// use Brainfuck::reader::{Reader, Span};
+    ///
+    /// let reader = Reader::from_string(String::from("++\n>[<"));
+    /// let msg = reader.show(Span { line: 2, col: 2 }, "loop has no end point");
+    /// assert_eq!(msg, "Error at line 2, column 2: loop has no end point\n>[<\n ^");
+    /// ```
+    ///
+    pub fn show(&self, span: Span, msg: &str) -> String {
+        let mut res = format!("Error at line {}, column {}: {}", span.line, span.col, msg);
+        if let Some(line) = self.line(span.line) {
+            res.push('\n');
+            res.push_str(line);
+            res.push('\n');
+            for c in line.chars().take(span.col - 1) {
+                res.push(if c == '\t' { '\t' } else { ' ' });
+            }
+            res.push('^');
+        }
+        res
+    }
 }
 
 lazy_static! {
@@ -138,7 +213,7 @@ lazy_static! {
 
 #[cfg(test)]
 mod tests {
-    use super::Reader;
+    use super::{Reader, Span};
     #[test]
     fn go_through() {
         let s = String::from("/-~||||\\\\////...,,,++++<><><>>>><>");
@@ -170,4 +245,16 @@ mod tests {
         }
         assert_eq!(s, ss);
     }
+    #[test]
+    fn spans() {
+        let s = String::from("a+\n\tb[\n\n  ]");
+        let mut rdr = Reader::from_string(s);
+        assert_eq!(rdr.span(), Span { line: 1, col: 2 });
+        rdr.next();
+        assert_eq!(rdr.span(), Span { line: 2, col: 3 });
+        rdr.next();
+        assert_eq!(rdr.span(), Span { line: 4, col: 3 });
+        rdr.next();
+        assert_eq!(rdr.span(), Span { line: 4, col: 4 });
+    }
 }
\ No newline at end of file
@@ -0,0 +1,484 @@
+//! Differential tests: random scripts are run by a naive interpreter working directly on
+//! the source and by the `Compiler` at every optimisation level on every backend, comparing the output,
+//! the array, the pointer and the stack they leave behind, whether they end or fail.
+//!
+//! Each script runs on a growing array and on a small bounded tape.
+//!
+//! A mismatch is shrunk to a minimal script before it is reported.
+
+#![allow(non_snake_case)]
+
+extern crate Brainfuck;
+
+use Brainfuck::Compiler;
+use Brainfuck::bytecode::Backend;
+use Brainfuck::dispatcher::{CellType, RuntimeError};
+use Brainfuck::optimiser::Optimiser;
+use std::collections::HashMap;
+use std::env;
+use std::mem;
+
+// The number of scripts generated for each cell type, unless DIFFERENTIAL_CASES says otherwise
+const CASES: usize = 400;
+// The characters the naive interpreter executes before giving up on a script
+const STEPS: u64 = 20_000;
+// The deepest the naive interpreter calls functions before giving up on a script
+const DEPTH: usize = 40;
+const INPUT: &str = "hi\n";
+// The size of the bounded tape, small enough for the scripts to move off it
+const TAPE: usize = 8;
+
+// The pieces scripts are made of, including the patterns the optimiser looks for
+const PIECES: &[&str] = &[
+    "+", "-", ">", "<", "+", "-", ">", "<", "+++", "--", ">>", "<<", ".", ",",
+    "@", "^", "^^", "&", "&", "?", "!", "!!", "|", "/", "/\\", "/\\\\",
+    "[-]", "[+]", "[->+<]", "[->>++<<]", "[-<+>]", "[>]", "[<]", "[--<+>]", "[>[-]+<-]",
+    "!!-|", "!!--/\\", "@[-]", "&[-]^",
+    "[-]+++[>++<-]", "[-]>[-]<++[>+++[>++<-]<-]", "[-]>[-]+<+++[>[>]<-]", "[-]-[-->+<]",
+];
+
+// A xorshift generator, so the scripts are the same on every run
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+// Generates a script declaring up to 2 functions before its main code
+fn script(rng: &mut Rng) -> String {
+    let mut s = String::new();
+    for _ in 0..rng.below(3) {
+        s.push('~');
+        code(rng, &mut s, 0);
+        s.push('~');
+    }
+    code(rng, &mut s, 0);
+    s
+}
+// Appends random pieces and loops to `s`
+fn code(rng: &mut Rng, s: &mut String, depth: usize) {
+    for _ in 0..rng.below(10) + 1 {
+        if depth < 3 && rng.below(6) == 0 {
+            s.push('[');
+            code(rng, s, depth + 1);
+            s.push(']');
+        } else {
+            s.push_str(PIECES[rng.below(PIECES.len())]);
+        }
+    }
+}
+
+// A script as the naive interpreter sees it: characters, with only the grouping the language requires
+#[derive(Debug)]
+enum Node {
+    Char(char),
+    Loop(Vec<Node>),
+    // `/` with the number of `\` after it
+    Separate(usize),
+    // `!`, the number of functions when it runs
+    Functions,
+    // `!!`, the number of functions when it was compiled
+    Known(i64),
+}
+
+struct Script {
+    funs: Vec<Vec<Node>>,
+    main: Vec<Node>,
+}
+
+// Parses a script, or returns None if it is not valid
+fn parse(src: &str) -> Option<Script> {
+    let chars: Vec<char> = src.chars().collect();
+    let mut script = Script { funs: Vec::new(), main: Vec::new() };
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] == '~' {
+            let body = parse_code(&chars, &mut i, 1, script.funs.len())?;
+            if chars.get(i) != Some(&'~') {
+                return None;
+            }
+            i += 1;
+            script.funs.push(body);
+        } else {
+            let nodes = parse_code(&chars, &mut i, 0, script.funs.len())?;
+            if chars.get(i) == Some(&']') {
+                return None;
+            }
+            script.main.extend(nodes);
+        }
+    }
+    Some(script)
+}
+// Parses from `chars[*i]` up to the end of the current loop, function or top level code
+fn parse_code(chars: &[char], i: &mut usize, skip: usize, funs: usize) -> Option<Vec<Node>> {
+    *i += skip;
+    let mut nodes = Vec::new();
+    while *i < chars.len() {
+        let c = chars[*i];
+        let run = chars[*i..].iter().take_while(|&&x| x == c).count();
+        match c {
+            '~' | ']' => return Some(nodes),
+            '[' => {
+                let body = parse_code(chars, i, 1, funs)?;
+                if chars.get(*i) != Some(&']') {
+                    return None;
+                }
+                *i += 1;
+                nodes.push(Node::Loop(body));
+                continue;
+            }
+            '!' if run == 1 => nodes.push(Node::Functions),
+            '!' => nodes.push(Node::Known(funs as i64)),
+            '@' | '?' => nodes.push(Node::Char(c)),
+            '/' => {
+                let args = chars[*i + 1..].iter().take_while(|&&x| x == '\\').count();
+                nodes.push(Node::Separate(args));
+                *i += 1 + args;
+                continue;
+            }
+            '\\' | '#' => return None,
+            _ => {
+                *i += 1;
+                nodes.push(Node::Char(c));
+                continue;
+            }
+        }
+        *i += run;
+    }
+    Some(nodes)
+}
+
+// Why the naive interpreter stopped early
+enum Stop {
+    Error(RuntimeError),
+    // The script ran too long or called too deep to be compared
+    GaveUp,
+}
+
+impl From<RuntimeError> for Stop {
+    fn from(e: RuntimeError) -> Stop {
+        Stop::Error(e)
+    }
+}
+
+// The naive interpreter, executing one character at a time
+struct Naive<'a> {
+    script: &'a Script,
+    cells: CellType,
+    bound: Option<usize>,
+    tape: HashMap<i64, i64>,
+    pointer: i64,
+    stack: Vec<i64>,
+    input: std::str::Bytes<'a>,
+    output: Vec<u8>,
+    steps: u64,
+    depth: usize,
+}
+
+impl<'a> Naive<'a> {
+    fn get(&self) -> i64 {
+        self.tape.get(&self.pointer).cloned().unwrap_or(0)
+    }
+    fn set(&mut self, val: i64) -> Result<(), Stop> {
+        let val = self.cells.wrap(val)?;
+        self.tape.insert(self.pointer, val);
+        Ok(())
+    }
+    fn add(&mut self, val: i64) -> Result<(), Stop> {
+        let val = self.cells.add(self.get(), val)?;
+        self.set(val)
+    }
+    // Moves the pointer by one cell, failing if it would leave a bounded tape
+    fn move_by(&mut self, i: i64) -> Result<(), Stop> {
+        if let Some(size) = self.bound {
+            let target = self.pointer + i;
+            if target < 0 || target >= size as i64 {
+                return Err(Stop::Error(RuntimeError::OutOfTape(target)));
+            }
+        }
+        self.pointer += i;
+        Ok(())
+    }
+    fn step(&mut self) -> Result<(), Stop> {
+        self.steps += 1;
+        if self.steps > STEPS {
+            return Err(Stop::GaveUp);
+        }
+        Ok(())
+    }
+    fn run(&mut self, nodes: &[Node]) -> Result<(), Stop> {
+        for node in nodes {
+            self.step()?;
+            match *node {
+                Node::Char('+') => self.add(1)?,
+                Node::Char('-') => self.add(-1)?,
+                Node::Char('>') => self.move_by(1)?,
+                Node::Char('<') => self.move_by(-1)?,
+                Node::Char('.') => self.output.push(self.get() as u8),
+                Node::Char(',') => {
+                    if let Some(ch) = self.input.next() {
+                        self.set(i64::from(ch))?;
+                    }
+                }
+                Node::Char('@') => {
+                    let top = *self.stack.last().ok_or(RuntimeError::StackUnderflow)?;
+                    self.add(top)?;
+                }
+                Node::Char('^') => {
+                    let top = self.stack.pop().ok_or(RuntimeError::StackUnderflow)?;
+                    self.add(top)?;
+                }
+                Node::Char('&') => self.stack.push(self.get()),
+                Node::Char('?') => {
+                    let len = self.stack.len() as i64;
+                    self.set(len)?;
+                }
+                Node::Char('|') => {
+                    let n = self.get();
+                    self.call(n, None)?;
+                }
+                Node::Char(c) => panic!("Unexpected character {:?}", c),
+                Node::Loop(ref body) => {
+                    while self.get() != 0 {
+                        self.step()?;
+                        self.run(body)?;
+                    }
+                }
+                Node::Separate(args) => {
+                    let n = self.get();
+                    self.call(n, Some(args))?;
+                }
+                Node::Functions => {
+                    let funs = self.script.funs.len() as i64;
+                    self.set(funs)?;
+                }
+                Node::Known(n) => self.set(n)?,
+            }
+        }
+        Ok(())
+    }
+    // Calls function `n`, on a new array with `args` numbers copied if there are any
+    fn call(&mut self, n: i64, args: Option<usize>) -> Result<(), Stop> {
+        if n < 0 {
+            return Err(Stop::Error(RuntimeError::NegativeFunction(n)));
+        }
+        let script = self.script;
+        let body = match script.funs.get(n as usize) {
+            Some(body) => body,
+            None => return Err(Stop::Error(RuntimeError::UnknownFunction(n as usize))),
+        };
+        if self.depth == DEPTH {
+            return Err(Stop::GaveUp);
+        }
+        self.depth += 1;
+        let res = match args {
+            None => self.run(body),
+            Some(args) => {
+                if let Some(size) = self.bound {
+                    // The numbers copied have to fit on both tapes
+                    if self.pointer + args as i64 >= size as i64 {
+                        self.depth -= 1;
+                        return Err(Stop::Error(RuntimeError::OutOfTape(size as i64)));
+                    }
+                }
+                let tape = (0..=args as i64).map(|i| (i, self.tape.get(&(self.pointer + i)).cloned().unwrap_or(0))).collect();
+                let tape = mem::replace(&mut self.tape, tape);
+                let pointer = mem::replace(&mut self.pointer, 0);
+                let res = self.run(body);
+                self.tape = tape;
+                self.pointer = pointer;
+                res
+            }
+        };
+        self.depth -= 1;
+        res
+    }
+}
+
+// What a run leaves behind, where it stopped if it failed
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    result: Result<(), RuntimeError>,
+    output: Vec<u8>,
+    array: Vec<(i64, i64)>,
+    pointer: i64,
+    stack: Vec<i64>,
+}
+
+// Runs a script with the naive interpreter on a tape of `bound` cells, returning what it left
+// and the steps it took, or None if the script is not valid or gave up
+fn naive(src: &str, cells: CellType, bound: Option<usize>) -> Option<(Outcome, u64)> {
+    let script = parse(src)?;
+    let mut naive = Naive {
+        script: &script,
+        cells,
+        bound,
+        tape: HashMap::new(),
+        pointer: 0,
+        stack: Vec::new(),
+        input: INPUT.bytes(),
+        output: Vec::new(),
+        steps: 0,
+        depth: 0,
+    };
+    let result = match naive.run(&script.main) {
+        Ok(()) => Ok(()),
+        Err(Stop::Error(e)) => Err(e),
+        Err(Stop::GaveUp) => return None,
+    };
+    let mut array: Vec<(i64, i64)> = naive.tape.iter().filter(|&(_, &v)| v != 0).map(|(&p, &v)| (p, v)).collect();
+    array.sort();
+    Some((Outcome { result, output: naive.output, array, pointer: naive.pointer, stack: naive.stack }, naive.steps))
+}
+
+// Runs a script with the Compiler at an optimisation level, without a limit if there is no fuel
+fn optimised(src: &str, cells: CellType, bound: Option<usize>, level: u32, backend: Backend, fuel: Option<u64>) -> Result<Outcome, String> {
+    let mut cmp = Compiler::with_io(INPUT.as_bytes(), Vec::new());
+    cmp.set_cell_type(cells);
+    cmp.set_tape(bound);
+    cmp.set_backend(backend);
+    cmp.set_optimiser(Optimiser::with_level(level).unwrap());
+    cmp.try_compile_string(src.to_string()).map_err(|e| format!("-O{} does not compile it: {}", level, e))?;
+    let result = match fuel {
+        Some(fuel) => cmp.execute_with_fuel(false, fuel),
+        None => cmp.execute(false),
+    };
+    let ah = &cmp.context().ah;
+    Ok(Outcome {
+        result,
+        output: cmp.output().clone(),
+        array: ah.values(),
+        pointer: ah.pointer(),
+        stack: cmp.context().stack.as_slice().to_vec(),
+    })
+}
+
+// Compares the runs of a script, returning whether it could be compared or how they differ
+fn check(src: &str, cells: CellType, bound: Option<usize>) -> Result<bool, String> {
+    let (expected, steps) = match naive(src, cells, bound) {
+        Some(run) => run,
+        None => return Ok(false),
+    };
+    for level in 0..4 {
+        for &backend in [Backend::Tree, Backend::Bytecode, Backend::Jit].iter() {
+            // Optimised operations never take more fuel than the characters they replace, and
+            // the machine code, which has no fuel, ends as the naive run did
+            let fuel = if backend == Backend::Jit { None } else { Some(steps + 1) };
+            let got = optimised(src, cells, bound, level, backend, fuel)?;
+            if got != expected {
+                return Err(format!("-O{} on {:?} gives {:?}\nexpected {:?}", level, backend, got, expected));
+            }
+        }
+    }
+    Ok(true)
+}
+
+// Removes parts of a script for as long as the runs still differ
+fn shrink(src: &str, cells: CellType, bound: Option<usize>) -> String {
+    let mut smallest = src.to_string();
+    'shrinking: loop {
+        for candidate in smaller(&smallest) {
+            if check(&candidate, cells, bound).is_err() {
+                smallest = candidate;
+                continue 'shrinking;
+            }
+        }
+        return smallest;
+    }
+}
+// Returns the scripts left by removing a single character, a pair of brackets or
+// everything between a pair of brackets
+fn smaller(src: &str) -> Vec<String> {
+    let chars: Vec<char> = src.chars().collect();
+    let without = |drop: &dyn Fn(usize) -> bool| -> String {
+        chars.iter().enumerate().filter(|&(i, _)| !drop(i)).map(|(_, &c)| c).collect()
+    };
+    let mut candidates = Vec::new();
+    for (i, &c) in chars.iter().enumerate() {
+        let end = match c {
+            '[' => matching(&chars, i),
+            '~' => chars[i + 1..].iter().position(|&x| x == '~').map(|j| i + 1 + j),
+            _ => None,
+        };
+        if let Some(j) = end {
+            candidates.push(without(&|k| k >= i && k <= j));
+            candidates.push(without(&|k| k == i || k == j));
+        }
+        candidates.push(without(&|k| k == i));
+    }
+    candidates
+}
+// Returns the position of the ']' closing the loop opened at `start`
+fn matching(chars: &[char], start: usize) -> Option<usize> {
+    let mut open = 0;
+    for (i, &c) in chars.iter().enumerate().skip(start) {
+        match c {
+            '[' => open += 1,
+            ']' => {
+                open -= 1;
+                if open == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+#[test]
+fn naive_interpreter_runs_scripts() {
+    let (hello, _) = naive("++++++++[>++++++++<-]>+.&~>+.~!!-|^", CellType::Trap, None).unwrap();
+    assert_eq!(hello.result, Ok(()));
+    assert_eq!(hello.output, b"A\x01");
+    assert_eq!(hello.array, vec![(2, 66)]);
+    assert_eq!(hello.stack, vec![]);
+    assert!(naive("+[]", CellType::U8, None).is_none());
+    assert!(naive("+]", CellType::U8, None).is_none());
+    let (off_tape, _) = naive("+>+>+", CellType::Trap, Some(2)).unwrap();
+    assert_eq!(off_tape.result, Err(RuntimeError::OutOfTape(2)));
+    assert_eq!((off_tape.array, off_tape.pointer), (vec![(0, 1), (1, 1)], 1));
+}
+
+#[test]
+fn shrinking_keeps_scripts_valid() {
+    let chars: Vec<char> = "+[>.<-]..~-~".chars().collect();
+    assert_eq!(matching(&chars, 1), Some(6));
+    let candidates = smaller("~+~[-]");
+    assert!(candidates.contains(&String::from("[-]")));
+    assert!(candidates.contains(&String::from("~+~-")));
+    assert!(candidates.contains(&String::from("~+~")));
+}
+
+#[test]
+fn optimised_runs_match_the_naive_interpreter() {
+    let cases = env::var("DIFFERENTIAL_CASES").ok().and_then(|n| n.parse().ok()).unwrap_or(CASES);
+    for &cells in [CellType::U8, CellType::I64, CellType::Trap].iter() {
+        for &bound in [None, Some(TAPE)].iter() {
+            let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+            let mut compared = 0;
+            for _ in 0..cases {
+                let src = script(&mut rng);
+                match check(&src, cells, bound) {
+                    Ok(true) => compared += 1,
+                    Ok(false) => {}
+                    Err(_) => {
+                        let small = shrink(&src, cells, bound);
+                        panic!("Runs of {:?} with {} cells and tape {:?} differ (shrunk from {:?}):\n{}",
+                               small, cells, bound, src, check(&small, cells, bound).unwrap_err());
+                    }
+                }
+            }
+            // Most scripts should end quickly enough to be compared
+            assert!(compared > cases / 2, "only {} scripts compared with {} cells and tape {:?}", compared, cells, bound);
+        }
+    }
+}
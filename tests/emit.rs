@@ -0,0 +1,213 @@
+//! Emitted programs are built and run, comparing their output and runtime errors with the
+//! ones of `Compiler::execute` on the same scripts.
+//!
+//! The C programs need `cc` and the Rust modules `rustc`; without them their test is skipped.
+//! The WebAssembly modules are assembled with `wat` and run with the `wasmi` interpreter.
+
+#![allow(non_snake_case)]
+
+extern crate Brainfuck;
+extern crate wasmi;
+extern crate wat;
+
+use Brainfuck::Compiler;
+use Brainfuck::dispatcher::{CellType, EofMode};
+use Brainfuck::emit::Language;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{self, Command, Stdio};
+use wasmi::{Caller, Engine, Extern, Linker, Module, Store};
+
+// A script together with the settings and the input it runs with
+struct Case {
+    name: &'static str,
+    files: &'static [&'static str],
+    script: &'static str,
+    cells: CellType,
+    tape: Option<usize>,
+    eof: EofMode,
+    input: &'static str,
+}
+
+const CASE: Case = Case {
+    name: "",
+    files: &[],
+    script: "",
+    cells: CellType::Trap,
+    tape: None,
+    eof: EofMode::Unchanged,
+    input: "",
+};
+
+const CASES: &[Case] = &[
+    Case { name: "hello_world", files: &["hello_world"], ..CASE },
+    Case { name: "functions_from_files", files: &["loadtest", "funtest"], ..CASE },
+    Case { name: "stack_test", files: &["stack_test"], ..CASE },
+    Case { name: "separate_calls", script: "~>[>+<-]>&~~>>++++[<++++++++>-]<.[-]~+++[>+/-<-]>>+++>++<<|^.<+/\\.", ..CASE },
+    Case { name: "arguments", script: "~>[-<+>]>[-<<+>>]<<.~>++>+++<</\\\\.!.", ..CASE },
+    Case { name: "calls_by_number", script: "~++.~ ~!!-|>+++.~ !!-|", ..CASE },
+    Case { name: "negative_function", script: "-|", ..CASE },
+    Case { name: "u8_loops", script: "-[->+<]>.[-]++++++[-->+<]>.-[>+<---]>.>+>+>+<<<[>]<.[<]>.", cells: CellType::U8, ..CASE },
+    Case { name: "u16_loops", script: "-[--->+<]>.>-.", cells: CellType::U16, ..CASE },
+    Case { name: "u32_loops", script: "-[--->+<]>.", cells: CellType::U32, ..CASE },
+    Case { name: "i64_loops", script: "-[->+<]>.[-]+++[>---<-]>.", cells: CellType::I64, ..CASE },
+    Case { name: "trap_overflow", script: "+++++[-->+<]", ..CASE },
+    Case { name: "stack_underflow", script: "+++&^^", ..CASE },
+    Case { name: "evaluated_start", script: "++++++++[>++++++++<-]>+.&,.@.", input: "a", ..CASE },
+    Case { name: "input", script: ",[.,]", eof: EofMode::Zero, input: "h\u{e9}llo \u{263a}\n", ..CASE },
+    Case { name: "end_of_input", script: ",+[-.,+]>,.", cells: CellType::U8, eof: EofMode::MinusOne, input: "ab", ..CASE },
+    Case { name: "tape_end", script: "+[>+]", tape: Some(10), ..CASE },
+    Case { name: "tape_start", script: "+.<", tape: Some(10), ..CASE },
+    Case { name: "tape_before_stack", script: "+.>>><><<>>>>>>>>^", tape: Some(10), ..CASE },
+];
+
+// Runs a case in the interpreter, returning the program emitted for it in `lang`, the output
+// and the error printed, if any
+fn interpret(case: &Case, lang: Language) -> (String, Vec<u8>, Option<String>) {
+    let mut cmp = Compiler::with_io(case.input.as_bytes(), Vec::new());
+    cmp.set_cell_type(case.cells);
+    cmp.set_tape(case.tape);
+    cmp.set_eof(case.eof);
+    for file in case.files {
+        cmp.try_compile_file(file).unwrap();
+    }
+    if !case.script.is_empty() {
+        cmp.try_compile_string(case.script.to_string()).unwrap();
+    }
+    let program = cmp.emit(lang);
+    let error = cmp.execute(false).err().map(|e| format!("Runtime error: {}\n", e));
+    (program, cmp.output().clone(), error)
+}
+
+// Runs `cmd` on `input`, returning its output and what it printed to stderr if it failed
+fn run(cmd: &mut Command, input: &str) -> (Vec<u8>, Option<String>) {
+    let mut child = cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped())
+        .spawn().unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let out = child.wait_with_output().unwrap();
+    let stderr = String::from_utf8_lossy(&out.stderr).into_owned();
+    // Debug information is printed to stderr too, before the error
+    let error = if out.status.success() { None } else { stderr.lines().last().map(|line| format!("{}\n", line)) };
+    (out.stdout, error)
+}
+
+// Returns a new directory for the programs of a test
+fn temp_dir(lang: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("brainfuck-emit-{}-{}", lang, process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn c_programs_match_the_interpreter() {
+    if Command::new("cc").arg("--version").output().is_err() {
+        eprintln!("cc not found, skipping the C programs");
+        return;
+    }
+    let dir = temp_dir("c");
+    for case in CASES {
+        let (program, output, error) = interpret(case, Language::C);
+        let (source, exe) = (dir.join(format!("{}.c", case.name)), dir.join(case.name));
+        fs::write(&source, program).unwrap();
+        let built = Command::new("cc").arg("-std=c99").arg("-O1").arg("-o").arg(&exe).arg(&source).status().unwrap();
+        assert!(built.success(), "{} does not build", case.name);
+        assert_eq!(run(&mut Command::new(&exe), case.input), (output, error), "{} runs differently", case.name);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn rust_modules_match_the_interpreter() {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+    if Command::new(&rustc).arg("--version").output().is_err() {
+        eprintln!("rustc not found, skipping the Rust modules");
+        return;
+    }
+    // A single program including every module, running the one named by its argument
+    let dir = temp_dir("rust");
+    let mut main = String::new();
+    let mut arms = String::new();
+    let mut expected = Vec::new();
+    for case in CASES {
+        let (program, output, error) = interpret(case, Language::Rust);
+        fs::write(dir.join(format!("{}.rs", case.name)), program).unwrap();
+        main.push_str(&format!("mod {0} {{ include!(\"{0}.rs\"); }}\n", case.name));
+        arms.push_str(&format!("        \"{0}\" => {0}::run(input.lock(), output.lock()),\n", case.name));
+        expected.push((output, error));
+    }
+    main.push_str(&format!("\nfn main() {{
+    let (input, output) = (std::io::stdin(), std::io::stdout());
+    let result = match &*std::env::args().nth(1).unwrap() {{
+{}        _ => unreachable!(),
+    }};
+    if let Err(e) = result {{
+        eprintln!(\"Runtime error: {{}}\", e);
+        std::process::exit(1);
+    }}
+}}
+", arms));
+    let (source, exe) = (dir.join("main.rs"), dir.join("main"));
+    fs::write(&source, main).unwrap();
+    let built = Command::new(&rustc).arg("-o").arg(&exe).arg(&source).status().unwrap();
+    assert!(built.success(), "the modules do not build");
+    for (case, expected) in CASES.iter().zip(expected) {
+        assert_eq!(run(Command::new(&exe).arg(case.name), case.input), expected, "{} runs differently", case.name);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// What the imported functions of a WebAssembly module work on
+#[derive(Default)]
+struct Host {
+    input: Vec<u8>,
+    next: usize,
+    output: Vec<u8>,
+    error: Option<String>,
+}
+
+// Returns the `len` bytes of the memory of the module calling at `at`
+fn memory(caller: &Caller<Host>, at: i32, len: i32) -> Vec<u8> {
+    let memory = caller.get_export("memory").and_then(Extern::into_memory).unwrap();
+    let mut bytes = vec![0; len as usize];
+    memory.read(caller, at as usize, &mut bytes).unwrap();
+    bytes
+}
+
+// Runs a WebAssembly text module on `input`, returning its output and its error, if any
+fn run_wat(text: &str, input: &str) -> (Vec<u8>, Option<String>) {
+    let engine = Engine::default();
+    let module = Module::new(&engine, &wat::parse_str(text).unwrap()[..]).unwrap();
+    let mut store = Store::new(&engine, Host { input: input.as_bytes().to_vec(), ..Host::default() });
+    let mut linker = Linker::<Host>::new(&engine);
+    linker.func_wrap("env", "read", |mut caller: Caller<Host>| {
+        let host = caller.data_mut();
+        host.next += 1;
+        host.input.get(host.next - 1).map_or(-1, |&byte| i32::from(byte))
+    }).unwrap();
+    linker.func_wrap("env", "write", |mut caller: Caller<Host>, at: i32, len: i32| {
+        let bytes = memory(&caller, at, len);
+        caller.data_mut().output.extend(bytes);
+    }).unwrap();
+    linker.func_wrap("env", "debug", |_: Caller<Host>, _: i32, _: i32| {}).unwrap();
+    linker.func_wrap("env", "error", |mut caller: Caller<Host>, at: i32, len: i32| {
+        let message = String::from_utf8(memory(&caller, at, len)).unwrap();
+        caller.data_mut().error = Some(format!("Runtime error: {}\n", message));
+    }).unwrap();
+    let instance = linker.instantiate(&mut store, &module).unwrap().start(&mut store).unwrap();
+    let run = instance.get_typed_func::<(), ()>(&store, "run").unwrap();
+    let trapped = run.call(&mut store, ()).is_err();
+    let host = store.into_data();
+    // The module only traps after giving the message of the error
+    assert_eq!(trapped, host.error.is_some());
+    (host.output, host.error)
+}
+
+#[test]
+fn wat_modules_match_the_interpreter() {
+    for case in CASES {
+        let (program, output, error) = interpret(case, Language::Wat);
+        assert_eq!(run_wat(&program, case.input), (output, error), "{} runs differently", case.name);
+    }
+}